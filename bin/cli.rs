@@ -0,0 +1,197 @@
+use anyhow::{bail, Result};
+use chrono::Utc;
+use serde_json::Value;
+use std::fs;
+use std::path::Path;
+use xlive::config;
+use xlive::dump;
+use xlive::token;
+
+pub const USAGE: &str = "usage: xlive [command]
+
+commands:
+    serve                         run the server (default)
+    check                         run built-in vectors through codecs and muxer
+    check-config                  validate conf.yaml
+    list-streams [host:port]      list live streams of a running instance
+    mint-token <path> [ttl_secs]  sign a playback path, e.g. /live.flv
+    validate-playlist <file>      check an m3u8 playlist
+    replay <file> [app] [key]     serve and publish a packet dump into app
+
+options:
+    --print-config                print the effective settings and exit";
+
+pub fn check_config() -> Result<()> {
+    let settings = config::load()?;
+    let problems = settings.check();
+    if problems.is_empty() {
+        println!("conf.yaml is valid");
+        return Ok(());
+    }
+    for problem in &problems {
+        println!("{}", problem);
+    }
+    bail!("conf.yaml has {} problem(s)", problems.len())
+}
+
+/// Runs the built-in vectors, failing if any step of the pipeline this
+/// build was compiled with does not work.
+#[cfg(feature = "hls")]
+pub fn self_check() -> Result<()> {
+    let checks = xlive::selfcheck::run();
+    let failed = checks.iter().filter(|check| check.result.is_err()).count();
+    for check in &checks {
+        match &check.result {
+            Ok(()) => println!("ok      {}", check.name),
+            Err(e) => println!("FAILED  {}: {:#}", check.name, e),
+        }
+    }
+    if failed > 0 {
+        bail!("{} of {} check(s) failed", failed, checks.len());
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "hls"))]
+pub fn self_check() -> Result<()> {
+    bail!("built without the hls feature")
+}
+
+/// Prints the settings after merging conf.yaml and the environment.
+/// Secrets are left out.
+pub fn print_config() -> Result<()> {
+    let settings = config::load()?;
+    println!("{}", serde_json::to_string_pretty(&settings)?);
+    Ok(())
+}
+
+/// Queries the `/streams` endpoint of the HTTP-FLV service, on the admin
+/// port if there is one.
+pub async fn list_streams(args: &[String]) -> Result<()> {
+    let settings = config::load()?;
+    let addr = match args.first() {
+        Some(addr) => addr.clone(),
+        None if settings.http_flv.admin_port != 0 => {
+            format!("127.0.0.1:{}", settings.http_flv.admin_port)
+        }
+        None => format!("127.0.0.1:{}", settings.http_flv.port),
+    };
+    let now = Utc::now().timestamp();
+    let keyring = token::Keyring::new(&settings.http_flv);
+    let token = keyring.mint("/streams", now + 60, now).unwrap_or_default();
+    let uri = format!("http://{}/streams?token={}", addr, token).parse()?;
+
+    let res = hyper::Client::new().get(uri).await?;
+    let status = res.status();
+    let body = hyper::body::to_bytes(res.into_body()).await?;
+    if !status.is_success() {
+        bail!("{}: {}", status, String::from_utf8_lossy(&body));
+    }
+    let streams: Vec<Value> = serde_json::from_slice(&body)?;
+    println!(
+        "{:<24} {:<10} {:<40} {:>11}  HEALTH",
+        "NAME", "CLIENT", "PUBLISHER", "SUBSCRIBERS"
+    );
+    for stream in streams {
+        let mut name = stream["name"].as_str().unwrap_or_default().to_owned();
+        if stream["resuming"].as_bool() == Some(true) {
+            name.push_str(" (resuming)");
+        }
+        if let Some(source) = stream["source"].as_str() {
+            name.push_str(&format!(" ({})", source));
+        }
+        let mut health = stream["health"]["state"]
+            .as_str()
+            .unwrap_or_default()
+            .to_owned();
+        if let Some(sinks) = stream["health"]["sinks"].as_array() {
+            let sinks: Vec<_> = sinks.iter().filter_map(Value::as_str).collect();
+            health.push_str(&format!(" ({})", sinks.join(", ")));
+        }
+        println!(
+            "{:<24} {:<10} {:<40} {:>11}  {}",
+            name,
+            stream["client_id"],
+            stream["addr"].as_str().unwrap_or_default(),
+            stream["subscribers"],
+            health
+        );
+    }
+    Ok(())
+}
+
+pub fn mint_token(args: &[String]) -> Result<()> {
+    let path = match args.first() {
+        Some(path) if path.starts_with('/') => path,
+        _ => bail!("usage: xlive mint-token <path> [ttl_secs]"),
+    };
+    let ttl: i64 = match args.get(1) {
+        Some(ttl) => ttl.parse()?,
+        None => 3600,
+    };
+    let settings = config::load()?;
+    let keyring = token::Keyring::new(&settings.http_flv);
+    let now = Utc::now().timestamp();
+    match keyring.mint(path, now + ttl, now) {
+        Some(token) => println!("{}", token),
+        None if keyring.is_empty() => {
+            bail!("http_flv.token_secret is not set, any token is accepted")
+        }
+        None => bail!("no http_flv.token_keys entry is signing yet"),
+    }
+    Ok(())
+}
+
+pub fn validate_playlist(args: &[String]) -> Result<()> {
+    let file = match args.first() {
+        Some(file) => file,
+        None => bail!("usage: xlive validate-playlist <file>"),
+    };
+    let problems = validate(&fs::read_to_string(file)?);
+    if problems.is_empty() {
+        println!("{} is valid", file);
+        return Ok(());
+    }
+    for problem in &problems {
+        println!("{}", problem);
+    }
+    bail!("{} has {} problem(s)", file, problems.len())
+}
+
+/// A packet dump to publish once the server runs.
+pub struct Replay {
+    pub app: String,
+    pub key: String,
+    pub packets: Vec<xlive::Packet>,
+}
+
+/// Loads the dump to replay. The app defaults to the one it was captured
+/// from, taken from its `{app}_{unix}.dump` name.
+pub fn replay(args: &[String]) -> Result<Replay> {
+    let file = match args.first() {
+        Some(file) => file,
+        None => bail!("usage: xlive replay <file> [app] [key]"),
+    };
+    let app = match args.get(1) {
+        Some(app) => app.clone(),
+        None => Path::new(file)
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.rsplit_once('_'))
+            .map(|(app, _)| app.to_owned())
+            .ok_or_else(|| anyhow::anyhow!("can not tell the app from {}, pass it", file))?,
+    };
+    let key = args.get(2).cloned().unwrap_or_default();
+    let packets = dump::read(file)?;
+    Ok(Replay { app, key, packets })
+}
+
+#[cfg(feature = "hls")]
+fn validate(text: &str) -> Vec<String> {
+    xlive::playlist::validate(text)
+}
+
+#[cfg(not(feature = "hls"))]
+fn validate(_text: &str) -> Vec<String> {
+    vec!["built without the hls feature".to_owned()]
+}
@@ -1,15 +1,22 @@
-#[warn(unused_mut)]
 use anyhow::Result;
 use chrono::Local;
 use std::io::Write;
+use std::time::Duration;
 use tokio::sync::mpsc;
 #[cfg(feature = "flv")]
 use xlive::flv;
+use xlive::health::{self, Listener};
 #[cfg(feature = "hls")]
 use xlive::hls;
 #[cfg(feature = "http-flv")]
 use xlive::http_flv;
+#[cfg(feature = "hls")]
+use xlive::janitor::Janitor;
 use xlive::service::Service;
+use xlive::shutdown::Coordinator;
+use xlive::testsrc;
+#[cfg(feature = "http-flv")]
+use xlive::token;
 use xlive::transport::TsMessageQueue;
 #[cfg(feature = "hls")]
 use xlive::ts;
@@ -17,12 +24,35 @@ use xlive::ts;
 use xlive::user::Redis;
 use xlive::Manager;
 
+mod cli;
+
 #[tokio::main]
 async fn main() -> Result<()> {
-    let config = xlive::config::get_setting();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "--print-config") {
+        return cli::print_config();
+    }
+    match args.first().map(String::as_str) {
+        None | Some("serve") => serve(None).await,
+        Some("replay") => serve(Some(cli::replay(&args[1..])?)).await,
+        Some("check") => cli::self_check(),
+        Some("check-config") => cli::check_config(),
+        Some("list-streams") => cli::list_streams(&args[1..]).await,
+        Some("mint-token") => cli::mint_token(&args[1..]),
+        Some("validate-playlist") => cli::validate_playlist(&args[1..]),
+        Some(_) => {
+            eprintln!("{}", cli::USAGE);
+            std::process::exit(2);
+        }
+    }
+}
+
+async fn serve(replay: Option<cli::Replay>) -> Result<()> {
+    // fail before anything starts listening on unknown or malformed settings
+    let config = xlive::config::load()?;
 
     let env =
-        env_logger::Env::default().filter_or(env_logger::DEFAULT_FILTER_ENV, config.log_level);
+        env_logger::Env::default().filter_or(env_logger::DEFAULT_FILTER_ENV, &config.log_level);
     env_logger::Builder::from_env(env)
         .format(|buf, record| {
             writeln!(
@@ -36,27 +66,72 @@ async fn main() -> Result<()> {
         })
         .init();
 
-    let mut handles = Vec::new();
+    xlive::memory::set_limit(config.memory_limit_mb * 1024 * 1024);
+
+    #[cfg(feature = "hls")]
+    if config.hls.replica {
+        return replica(config).await;
+    }
+
+    // HTTP servers, waited for once shutdown stopped them
+    let mut servers = Vec::new();
+    let coordinator = Coordinator::new();
     let redis_client: Option<Redis> = Some(Redis::new(&config.redis)?);
 
-    let manager = Manager::new(redis_client, config.full_gop, config.auth_enable);
+    let manager = Manager::new(
+        redis_client,
+        config.full_gop,
+        config.auth_enable,
+        Duration::from_secs(config.rtmp.reconnect_grace),
+        &config.apps,
+        Some(Duration::from_secs(config.rtmp.failover_after)).filter(|d| !d.is_zero()),
+    )
+    .with_max_duration(
+        Some(Duration::from_secs(config.rtmp.max_duration)).filter(|d| !d.is_zero()),
+        Duration::from_secs(config.rtmp.duration_warning),
+    )
+    .with_webhook(Some(config.webhook.clone()).filter(|url| !url.is_empty()));
     let manager_handle = manager.handle();
-    handles.push(tokio::spawn(manager.run()));
+    tokio::spawn(manager.run());
+
+    if config.clock.check_interval > 0 {
+        tokio::spawn(xlive::clock::monitor(config.clock.clone()));
+    }
 
     #[cfg(feature = "flv")]
     {
         let manager_handle_t = manager_handle.clone();
-        let data_path = config.flv.data_path;
-        handles.push(tokio::spawn(async {
-           _ = flv::Service::new(manager_handle_t, data_path).run().await;
-        }));
+        let data_path = config.flv.data_path.clone();
+        let shutdown = coordinator.token();
+        tokio::spawn(async {
+            _ = flv::Service::new(manager_handle_t, data_path, shutdown)
+                .run()
+                .await;
+        });
     }
     #[cfg(feature = "http-flv")]
     {
         let port = config.http_flv.port;
+        let admin_port = config.http_flv.admin_port;
         let manager_handle_t = manager_handle.clone();
-        handles.push(tokio::spawn(async move {
-            http_flv::Service::new(manager_handle_t).run(port).await;
+        let vod_path = config.flv.data_path.clone();
+        let keyring = token::Keyring::new(&config.http_flv);
+        let expose_streams = config.http_flv.expose_streams;
+        let endpoints = http_flv::Endpoints::new(&config);
+        let socket = config.socket.clone();
+        let http = config.http.clone();
+        let shutdown = coordinator.token();
+        health::expect(Listener::HttpFlv);
+        servers.push(tokio::spawn(async move {
+            http_flv::Service::new(
+                manager_handle_t,
+                vod_path,
+                keyring,
+                expose_streams,
+                endpoints,
+            )
+            .run(port, admin_port, &socket, &http, &shutdown)
+            .await;
         }));
     }
 
@@ -64,24 +139,141 @@ async fn main() -> Result<()> {
     {
         let (mq_handle, mq_receiver) = mpsc::unbounded_channel::<TsMessageQueue>();
         let manager_handle_t = manager_handle.clone();
-        let data_path = config.hls.data_path;
-        let ts_duration = config.hls.ts_duration;
-        let port = config.hls.port;
-        handles.push(tokio::spawn(async move {
-            _ = ts::Service::new(manager_handle_t, data_path, mq_handle, ts_duration)
+        let ts_config = config.hls.clone();
+        let hls_config = config.hls.clone();
+        let apps = config.apps.clone();
+        let socket = config.socket.clone();
+        let http = config.http.clone();
+        let shutdown = coordinator.token();
+        let sinks = coordinator.token();
+        tokio::spawn(async move {
+            _ = ts::Service::new(manager_handle_t, ts_config, mq_handle, sinks)
                 .run()
                 .await;
-        }));
+        });
 
-        handles.push(tokio::spawn(async move {
-            _ = hls::run(mq_receiver, port as u32).await;
+        health::expect(Listener::Hls);
+        servers.push(tokio::spawn(async move {
+            let result =
+                hls::run(mq_receiver, hls_config, apps, socket, http, None, shutdown).await;
+            if let Err(e) = result {
+                log::error!("Hls service stopped: {}", e);
+            }
         }));
+
+        if config.hls.cleanup_after > 0 || config.hls.max_disk_mb > 0 {
+            let janitor = Janitor::new(
+                manager_handle.clone(),
+                config.hls.data_path.clone(),
+                vec![
+                    config.flv.data_path.clone(),
+                    "data/keyframe".to_owned(),
+                    xlive::dump::DUMP_PATH.to_owned(),
+                ],
+                Some(Duration::from_secs(config.hls.cleanup_after)).filter(|d| !d.is_zero()),
+                Some(config.hls.max_disk_mb * 1024 * 1024).filter(|&q| q > 0),
+            );
+            tokio::spawn(janitor.run());
+        }
     }
     let port = config.rtmp.port;
-    handles.push(tokio::spawn(Service::new(manager_handle).run(port)));
+    health::expect(Listener::Rtmp);
+    let first_packet_timeout =
+        Some(Duration::from_secs(config.rtmp.first_packet_timeout)).filter(|d| !d.is_zero());
+    let stats_interval =
+        Some(Duration::from_secs(config.rtmp.stats_interval)).filter(|d| !d.is_zero());
+    tokio::spawn(
+        Service::new(
+            manager_handle.clone(),
+            config.socket.clone(),
+            first_packet_timeout,
+            stats_interval,
+            coordinator.token(),
+        )
+        .run(port),
+    );
 
-    for handle in handles {
-        handle.await?;
+    if config.test_source.enable {
+        let manager_handle_t = manager_handle.clone();
+        let test_source = config.test_source.clone();
+        tokio::spawn(async move {
+            if let Err(e) = testsrc::run(manager_handle_t, test_source).await {
+                log::error!("Test source stopped: {}", e);
+            }
+        });
+    }
+
+    #[cfg(feature = "mosaic")]
+    for (name, mosaic) in config.mosaics.clone() {
+        let manager_handle_t = manager_handle.clone();
+        tokio::spawn(xlive::mosaic::run(manager_handle_t, name, mosaic, port));
+    }
+
+    if let Some(replay) = replay {
+        let manager_handle_t = manager_handle.clone();
+        tokio::spawn(async move {
+            let result =
+                xlive::dump::replay(manager_handle_t, replay.app, replay.key, replay.packets).await;
+            if let Err(e) = result {
+                log::error!("Replay stopped: {}", e);
+            }
+        });
+    }
+
+    coordinator
+        .run(
+            Some(manager_handle),
+            Duration::from_secs(config.shutdown_grace),
+        )
+        .await;
+    // open connections may keep a server from finishing, don't wait forever
+    let stopped = futures::future::join_all(servers);
+    if tokio::time::timeout(Duration::from_secs(5), stopped)
+        .await
+        .is_err()
+    {
+        log::warn!("HTTP services did not stop in time");
+    }
+    log::info!("Shut down");
+    Ok(())
+}
+
+/// Serves HLS from the data path another instance writes, nothing else.
+#[cfg(feature = "hls")]
+async fn replica(config: xlive::config::Settings) -> Result<()> {
+    log::info!(
+        "Serving HLS from {} as a read-only replica",
+        config.hls.data_path
+    );
+    let coordinator = Coordinator::new();
+    let grace = Duration::from_secs(config.shutdown_grace);
+    health::without_manager();
+    health::expect(Listener::Hls);
+    // the playlists come from the state sidecars, not from a muxer
+    let (_, mq_receiver) = mpsc::unbounded_channel::<TsMessageQueue>();
+    let shutdown = coordinator.token();
+    let server = tokio::spawn(async move {
+        let result = hls::run(
+            mq_receiver,
+            config.hls,
+            config.apps,
+            config.socket,
+            config.http,
+            None,
+            shutdown,
+        )
+        .await;
+        if let Err(e) = result {
+            log::error!("Hls service stopped: {}", e);
+        }
+    });
+    coordinator.run(None, grace).await;
+    if tokio::time::timeout(Duration::from_secs(5), server)
+        .await
+        .is_err()
+    {
+        log::warn!("HTTP services did not stop in time");
     }
+    log::info!("Shut down");
     Ok(())
 }
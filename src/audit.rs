@@ -0,0 +1,39 @@
+use chrono::Utc;
+use hyper::StatusCode;
+use std::net::SocketAddr;
+use std::path::Path;
+use tokio::fs::{self, OpenOptions};
+use tokio::io::AsyncWriteExt;
+
+/// Append-only log of the admin actions, one JSON object per line.
+pub const AUDIT_PATH: &str = "data/audit.log";
+
+/// Appends an admin action on `target` to the audit log, with the address
+/// it came from and the status it was answered with. Failing to write is
+/// logged, the action itself has happened by then.
+pub async fn record(peer: Option<SocketAddr>, action: &str, target: &str, status: StatusCode) {
+    let entry = serde_json::json!({
+        "time": Utc::now().to_rfc3339(),
+        "peer": peer.map(|peer| peer.to_string()),
+        "action": action,
+        "target": target,
+        "status": status.as_u16(),
+    });
+    // a single write per line, so concurrent entries don't interleave
+    let line = format!("{}\n", entry);
+    if let Err(e) = append(line.as_bytes()).await {
+        log::error!("Failed to write the audit log {}: {}", AUDIT_PATH, e);
+    }
+}
+
+async fn append(line: &[u8]) -> std::io::Result<()> {
+    if let Some(dir) = Path::new(AUDIT_PATH).parent() {
+        fs::create_dir_all(dir).await?;
+    }
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(AUDIT_PATH)
+        .await?;
+    file.write_all(line).await
+}
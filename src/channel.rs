@@ -1,17 +1,23 @@
-use crate::codec::flv::{audio::AudioFormat::Aac, AudioData, VideoData};
+use crate::clock;
+use crate::codec::flv::{AudioData, VideoData};
+#[cfg(any(feature = "hls", feature = "keyframe_image"))]
+use crate::elementary::Converter;
+use crate::memory;
+use crate::metrics::{self, DropReason};
 use crate::packet::{Packet, PacketType};
+use crate::plugin::{Inspector, Verdict};
+use crate::sampling;
+use crate::timestamp::{Normalizer, WallClock};
 use crate::transport::{IncomingBroadcast, Message, OutgoingBroadcast};
 use anyhow::Result;
-#[cfg(feature = "keyframe_image")]
 use chrono::prelude::*;
+use log::Level;
 use std::convert::TryFrom;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Arc;
 
 #[cfg(feature = "keyframe_image")]
-use {
-    crate::codec::avc::{self, AvcCoder},
-    crate::codec::FormatReader,
-    crate::codec::FormatWriter,
-};
+use crate::thumbnails::ThumbnailTrack;
 #[cfg(feature = "keyframe_image")]
 use {pic::video_decode, std::fs};
 
@@ -19,14 +25,22 @@ pub struct Channel {
     name: String,
     incoming: IncomingBroadcast,
     outgoing: OutgoingBroadcast,
-    metadata: Option<Packet>,
-    video_seq_header: Option<Packet>,
-    audio_seq_header: Option<Packet>,
-    gop: Option<Vec<Packet>>,
+    metadata: Option<Arc<Packet>>,
+    video_seq_header: Option<Arc<Packet>>,
+    audio_seq_header: Option<Arc<Packet>>,
+    gop: Option<Vec<Arc<Packet>>>,
+    gop_bytes: usize,
+    timestamps: Normalizer,
+    wallclock: WallClock,
     closing: bool,
     full_gop: bool,
+    // unix time of the last packet, read by the manager for stream health
+    activity: Arc<AtomicI64>,
+    inspectors: Vec<Box<dyn Inspector>>,
+    #[cfg(any(feature = "hls", feature = "keyframe_image"))]
+    converter: Converter,
     #[cfg(feature = "keyframe_image")]
-    coder: AvcCoder,
+    thumbnails: ThumbnailTrack,
 }
 
 impl Channel {
@@ -35,8 +49,13 @@ impl Channel {
         incoming: IncomingBroadcast,
         outgoing: OutgoingBroadcast,
         full_gop: bool,
+        activity: Arc<AtomicI64>,
+        inspectors: Vec<Box<dyn Inspector>>,
     ) -> Self {
+        memory::register();
         Self {
+            #[cfg(feature = "keyframe_image")]
+            thumbnails: ThumbnailTrack::new(name.clone()),
             name,
             incoming,
             outgoing,
@@ -44,10 +63,15 @@ impl Channel {
             video_seq_header: None,
             audio_seq_header: None,
             gop: None,
+            gop_bytes: 0,
+            timestamps: Normalizer::new(),
+            wallclock: WallClock::new(),
             closing: false,
             full_gop,
-            #[cfg(feature = "keyframe_image")]
-            coder: AvcCoder::new(),
+            activity,
+            inspectors,
+            #[cfg(any(feature = "hls", feature = "keyframe_image"))]
+            converter: Converter::new(),
         }
     }
 
@@ -61,9 +85,29 @@ impl Channel {
 
     async fn handle_message(&mut self, message: Message) {
         match message {
-            Message::Packet(packet) => {
+            Message::Packet(mut packet) => {
+                self.activity
+                    .store(Utc::now().timestamp(), Ordering::Relaxed);
+                self.rewrite_timestamp(&mut packet);
+                #[cfg(any(feature = "hls", feature = "keyframe_image"))]
+                if let Err(e) = self.converter.convert(&mut packet) {
+                    sampling::log(
+                        Level::Error,
+                        format!("Failed to convert video of {} {}", self.name, e),
+                    );
+                }
+                if !self.inspect(&mut packet) {
+                    metrics::PLUGIN_DROPS.inc();
+                    metrics::dropped(&self.name, DropReason::Policy, 1);
+                    return;
+                }
+                // shared by the cache and every watcher instead of cloned for each
+                let packet = Arc::new(packet);
                 if let Err(e) = self.set_cache(&packet) {
-                    log::error!("Failed to set channel cache {}", e);
+                    sampling::log(
+                        Level::Error,
+                        format!("Failed to set channel cache of {} {}", self.name, e),
+                    );
                 }
                 self.broadcast_packet(packet);
             }
@@ -78,68 +122,143 @@ impl Channel {
                     log::error!("Failed to send init data");
                 }
             }
+            Message::Resume(timestamp) => {
+                // splice the resumed publisher onto the timeline already sent to watchers
+                self.timestamps.resume(timestamp);
+                // the gap while it was away is not on the timeline
+                self.wallclock = WallClock::new();
+            }
             Message::Disconnect => {
                 self.closing = true;
             }
         }
     }
 
-    fn broadcast_packet(&self, packet: Packet) {
+    fn rewrite_timestamp(&mut self, packet: &mut Packet) {
+        if let Some(timestamp) = packet.timestamp {
+            let timestamp = self.timestamps.normalize(packet.kind, timestamp.into());
+            packet.timestamp = Some(timestamp.into());
+            let now = clock::now().timestamp_millis();
+            packet.wallclock = Some(self.wallclock.map(timestamp, now));
+        }
+    }
+
+    /// Runs the plugins on a packet, false if one of them dropped it.
+    fn inspect(&mut self, packet: &mut Packet) -> bool {
+        self.inspectors
+            .iter_mut()
+            .all(|inspector| inspector.inspect(packet) == Verdict::Pass)
+    }
+
+    fn broadcast_packet(&self, packet: Arc<Packet>) {
         if self.outgoing.receiver_count() != 0 && self.outgoing.send(packet).is_err() {
-            log::error!("Failed to broadcast packet");
+            sampling::log(
+                Level::Error,
+                format!("Failed to broadcast packet of {}", self.name),
+            );
+        }
+    }
+
+    fn parameters_changed(&self, previous: &Option<Arc<Packet>>, header: &Packet) -> bool {
+        match previous {
+            Some(previous) if previous.payload != header.payload => {
+                log::info!("Stream parameters of {} changed", self.name);
+                metrics::STREAM_PARAMETER_CHANGES.inc();
+                true
+            }
+            _ => false,
         }
     }
 
-    fn set_cache(&mut self, packet: &Packet) -> Result<()> {
+    fn set_cache(&mut self, packet: &Arc<Packet>) -> Result<()> {
         match packet.kind {
             PacketType::Meta => {
                 self.metadata = Some(packet.clone());
             }
             PacketType::Video => {
-                let flv_packet = VideoData::try_from(packet.as_ref())?;
+                let flv_packet = VideoData::try_from(&packet.payload[..])?;
                 if flv_packet.is_sequence_header() && flv_packet.is_keyframe() {
+                    if self.parameters_changed(&self.video_seq_header, packet) {
+                        // the cached GOP can't be decoded with the new sequence header
+                        self.drop_gop();
+                    }
                     self.video_seq_header = Some(packet.clone());
-
-                    #[cfg(feature = "keyframe_image")]
-                    self.coder.set_dcr(flv_packet.body.as_ref())?;
                 } else if !flv_packet.is_sequence_header() && flv_packet.is_keyframe() {
                     #[cfg(feature = "keyframe_image")]
                     {
                         //提取关键帧AnnexB,保持成文件，需要ffmpeg 转码成jpg（参考readme 命令）
-                        let video = match self.coder.read_format(avc::Avcc, &flv_packet.body)? {
-                            Some(avc) => self.coder.write_format(avc::AnnexB, avc)?,
+                        let video = match &packet.annexb {
+                            Some(video) => video.to_vec(),
                             None => return Ok(()),
                         };
-                        let file_name =
-                            format!("data/keyframe/{}_{}.jpg", self.name, Utc::now().timestamp());
+                        let timestamp: u64 = packet.timestamp.map(|t| t.into()).unwrap_or(0);
+                        // the wall clock time of the frame, as its cue is in stream time
+                        let taken = packet
+                            .wallclock
+                            .unwrap_or_else(|| clock::now().timestamp_millis());
+                        let (file_name, path) = self.thumbnails.image_path(taken / 1000);
 
-                        if !pic::keyframe_to_jpg(video, file_name.clone()) {
-                            log::info!("keyframe_to_jpg err {}", file_name);
+                        if !pic::keyframe_to_jpg(video, path.clone()) {
+                            log::info!("keyframe_to_jpg err {}", path);
+                        } else if self.thumbnails.due(timestamp) {
+                            self.thumbnails.add(timestamp, file_name)?;
                         }
                     }
 
-                    let mut pck = vec![];
-                    pck.push(packet.clone());
-                    self.gop = Some(pck);
+                    self.drop_gop();
+                    self.gop = Some(vec![]);
+                    self.cache_gop(packet);
                 } else if self.full_gop {
-                    if let Some(ref mut v) = self.gop {
-                        v.push(packet.clone());
-                    }
+                    self.cache_gop(packet);
                 }
             }
             PacketType::Audio => {
-                let audio_packet = AudioData::try_from(packet.as_ref())?;
-                if audio_packet.is_sequence_header() && audio_packet.format == Aac {
+                let audio_packet = AudioData::try_from(&packet.payload[..])?;
+                if audio_packet.is_sequence_header() {
+                    self.parameters_changed(&self.audio_seq_header, packet);
                     self.audio_seq_header = Some(packet.clone());
                 }
             }
         }
         Ok(())
     }
+
+    fn cache_gop(&mut self, packet: &Arc<Packet>) {
+        let gop = match self.gop {
+            Some(ref mut gop) => gop,
+            None => return,
+        };
+        let size = packet.payload.len() + packet.annexb.as_ref().map_or(0, |v| v.len());
+        if memory::over_share(self.gop_bytes, size) {
+            // new viewers of this stream wait for the next keyframe instead
+            log::info!("Memory limit reached, dropping GOP cache of {}", self.name);
+            metrics::GOP_CACHE_EVICTIONS.inc();
+            self.drop_gop();
+            return;
+        }
+        gop.push(packet.clone());
+        self.gop_bytes += size;
+        memory::charge(size);
+    }
+
+    fn drop_gop(&mut self) {
+        self.gop = None;
+        memory::release(self.gop_bytes);
+        self.gop_bytes = 0;
+    }
 }
 
 impl Drop for Channel {
     fn drop(&mut self) {
+        self.drop_gop();
+        for inspector in &mut self.inspectors {
+            inspector.detach();
+        }
+        memory::unregister();
+        #[cfg(feature = "keyframe_image")]
+        if let Err(e) = self.thumbnails.finish() {
+            log::error!("Failed to finish thumbnail track {}", e);
+        }
         log::info!("channel {} closed", self.name);
     }
 }
@@ -0,0 +1,100 @@
+use crate::config;
+use crate::metrics;
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::process::Command;
+
+// offset of the system clock ahead of NTP time, valid once MEASURED
+static DRIFT_MS: AtomicI64 = AtomicI64::new(0);
+static MEASURED: AtomicBool = AtomicBool::new(false);
+static MAX_DRIFT_MS: AtomicU64 = AtomicU64::new(0);
+static REFUSE_RECORDINGS: AtomicBool = AtomicBool::new(false);
+
+/// The wall clock segment and recording names and PROGRAM-DATE-TIME are
+/// taken from.
+pub fn now() -> DateTime<Utc> {
+    Utc::now()
+}
+
+/// Milliseconds the system clock runs ahead of NTP time, negative when it
+/// is behind. `None` until it was measured.
+pub fn drift_ms() -> Option<i64> {
+    Some(DRIFT_MS.load(Ordering::Relaxed)).filter(|_| MEASURED.load(Ordering::Relaxed))
+}
+
+/// The last measured drift exceeds `clock.max_drift_ms`.
+pub fn drifting() -> bool {
+    let max = MAX_DRIFT_MS.load(Ordering::Relaxed);
+    max > 0 && drift_ms().map_or(false, |drift| drift.unsigned_abs() > max)
+}
+
+/// Whether FLV recordings may start, they are named after the wall clock.
+pub fn recordings_allowed() -> bool {
+    !(REFUSE_RECORDINGS.load(Ordering::Relaxed) && drifting())
+}
+
+/// Measures the drift every `check_interval` seconds, asking chronyd and
+/// falling back to `sntp` against `ntp_server`.
+pub async fn monitor(config: config::Clock) {
+    MAX_DRIFT_MS.store(config.max_drift_ms, Ordering::Relaxed);
+    REFUSE_RECORDINGS.store(config.refuse_recordings, Ordering::Relaxed);
+    let mut interval = tokio::time::interval(Duration::from_secs(config.check_interval));
+    loop {
+        interval.tick().await;
+        let drift = match query(&config.ntp_server).await {
+            Ok(drift) => drift,
+            Err(e) => {
+                log::debug!("Failed to measure clock drift: {}", e);
+                continue;
+            }
+        };
+        let was_drifting = drifting();
+        DRIFT_MS.store(drift, Ordering::Relaxed);
+        MEASURED.store(true, Ordering::Relaxed);
+        metrics::CLOCK_DRIFT_MS.set(drift.unsigned_abs());
+        match (was_drifting, drifting()) {
+            (false, true) => log::warn!("System clock is {} ms off NTP time", drift),
+            (true, false) => log::info!("System clock is back within {} ms", drift),
+            _ => {}
+        }
+    }
+}
+
+async fn query(ntp_server: &str) -> Result<i64> {
+    // chronyd tracks the offset anyway, no packets are sent for it
+    if let Ok(output) = Command::new("chronyc")
+        .args(["-c", "tracking"])
+        .output()
+        .await
+    {
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        if let Some(drift) = output.status.success().then(|| chrony(&stdout)).flatten() {
+            return Ok(drift);
+        }
+    }
+    let output = Command::new("sntp")
+        .args(["-t", "2", ntp_server])
+        .output()
+        .await?;
+    sntp(&String::from_utf8_lossy(&output.stdout)).ok_or_else(|| anyhow!("unexpected sntp output"))
+}
+
+// the fifth field of `chronyc -c tracking` is the correction in seconds,
+// positive when the system clock is slow
+fn chrony(output: &str) -> Option<i64> {
+    let correction: f64 = output.trim().split(',').nth(4)?.parse().ok()?;
+    Some((-correction * 1000.0).round() as i64)
+}
+
+// `sntp` prints `<date> <time> (<zone>) <offset> +/- <error> <server> ...`,
+// the offset being what the system clock lacks
+fn sntp(output: &str) -> Option<i64> {
+    output.lines().find_map(|line| {
+        let fields: Vec<_> = line.split_whitespace().collect();
+        let error = fields.iter().position(|field| *field == "+/-")?;
+        let offset: f64 = fields.get(error.checked_sub(1)?)?.parse().ok()?;
+        Some((-offset * 1000.0).round() as i64)
+    })
+}
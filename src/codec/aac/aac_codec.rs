@@ -1,6 +1,7 @@
 use super::common::AudioObjectType;
 
 #[derive(Debug, Clone)]
+#[allow(clippy::enum_variant_names)]
 pub enum AacProfile {
     AacProfileReserved = 3,
 
@@ -28,17 +29,18 @@ impl From<u8> for AacProfile {
     }
 }
 
-impl Into<AudioObjectType> for AacProfile {
-    fn into(self) -> AudioObjectType {
-        match self {
-            Self::AacProfileMain => AudioObjectType::AacMain,
-            Self::AacProfileLC => AudioObjectType::AacLowComplexity,
-            Self::AacProfileSSR => AudioObjectType::AacScalableSampleRate,
+impl From<AacProfile> for AudioObjectType {
+    fn from(val: AacProfile) -> Self {
+        match val {
+            AacProfile::AacProfileMain => AudioObjectType::AacMain,
+            AacProfile::AacProfileLC => AudioObjectType::AacLowComplexity,
+            AacProfile::AacProfileSSR => AudioObjectType::AacScalableSampleRate,
             _ => AudioObjectType::Reserved,
         }
     }
 }
 
+#[derive(Default)]
 pub struct RawAacStreamCodec {
     // Codec level informations.
     pub protection_absent: u8,
@@ -54,20 +56,3 @@ pub struct RawAacStreamCodec {
     // 0 for sh; 1 for raw data.
     pub aac_packet_type: u8,
 }
-
-impl Default for RawAacStreamCodec {
-    fn default() -> Self {
-        Self {
-            protection_absent: Default::default(),
-            aac_object: Default::default(),
-            sampling_frequency_index: Default::default(),
-            channel_configuration: Default::default(),
-            frame_length: Default::default(),
-            sound_format: Default::default(),
-            sound_rate: Default::default(),
-            sound_size: Default::default(),
-            sound_type: Default::default(),
-            aac_packet_type: Default::default(),
-        }
-    }
-}
@@ -1,4 +1,3 @@
-use std::cmp::{max, min};
 use std::io::Cursor;
 
 use super::aac_codec::{AacProfile, RawAacStreamCodec};
@@ -74,7 +73,7 @@ impl WriteFormat<Aac> for AudioDataTransportStream {
         tmp.put_u8(channel_configuration2 | frame_length1);
 
         // Frame length cont. (11 bits) and buffer fullness (5 bits)
-        let frame_length2 = ((frame_length & 0x7FF) << 5) as u16;
+        let frame_length2 = (frame_length & 0x7FF) << 5;
         tmp.put_u16(frame_length2 | 0b0000_0000_0001_1111);
 
         // Buffer fullness cont. (6 bits) and number of AAC frames minus one (2 bits = 0)
@@ -147,11 +146,11 @@ impl ReadFormat<Vec<Aac>> for AudioDataTransportStream {
             let sound_format = 10;
             let sound_rate = match sampling_frequency_index {
                 0x0a | 0x0b => 0u8,
-                0x07 | 0x08 | 0x09 => 1u8,
-                0x04 | 0x05 | 0x06 => 2u8,
+                0x07..=0x09 => 1u8,
+                0x04..=0x06 => 2u8,
                 _ => 3u8,
             };
-            let sound_type = max(0, min(1, channel_configuration - 1)) as u8;
+            let sound_type = (channel_configuration - 1).clamp(0, 1);
             let sound_size = 1u8;
 
             let aac_packet_type = 0u8;
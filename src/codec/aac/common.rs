@@ -74,14 +74,14 @@ impl TryFrom<u8> for AudioObjectType {
     }
 }
 
-impl Into<u8> for AudioObjectType {
-    fn into(self) -> u8 {
-        match self {
-            Self::AacMain => 1,
-            Self::AacLowComplexity => 2,
-            Self::AacScalableSampleRate => 3,
-            Self::AacLongTermPrediction => 4,
-            Self::Reserved => 0,
+impl From<AudioObjectType> for u8 {
+    fn from(val: AudioObjectType) -> Self {
+        match val {
+            AudioObjectType::AacMain => 1,
+            AudioObjectType::AacLowComplexity => 2,
+            AudioObjectType::AacScalableSampleRate => 3,
+            AudioObjectType::AacLongTermPrediction => 4,
+            AudioObjectType::Reserved => 0,
         }
     }
 }
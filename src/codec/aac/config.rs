@@ -16,8 +16,8 @@ use {
 // 1    | Frame length flag
 // 1    | Depends on core coder
 // 1    | Extension flag
-///
 #[derive(Debug, Clone)]
+#[allow(dead_code)]
 pub struct AudioSpecificConfiguration {
     pub object_type: AudioObjectType,
     pub sampling_frequency_index: SamplingFrequencyIndex,
@@ -87,8 +87,8 @@ impl FormatReader<Avcc> for AvcCoder {
                 None
             }
             State::Ready => {
-                let mut dcr = self.dcr.as_mut().unwrap();
-                Some(format.read_format(input, &mut dcr)?)
+                let dcr = self.dcr.as_mut().unwrap();
+                Some(format.read_format(input, dcr)?)
             }
         })
     }
@@ -121,8 +121,8 @@ impl FormatReader<AnnexB> for AvcCoder {
         Ok(match &self.state {
             State::Initializing => {
                 self.dcr = Some(DecoderConfigurationRecord::default());
-                let mut dcr = self.dcr.as_mut().unwrap();
-                let nals = format.read_format(input, &mut dcr)?;
+                let dcr = self.dcr.as_mut().unwrap();
+                let nals = format.read_format(input, dcr)?;
                 self.state = State::Ready;
                 if dcr.ready() {
                     Some(nals)
@@ -131,8 +131,8 @@ impl FormatReader<AnnexB> for AvcCoder {
                 }
             }
             State::Ready => {
-                let mut dcr = self.dcr.as_mut().unwrap();
-                Some(format.read_format(input, &mut dcr)?)
+                let dcr = self.dcr.as_mut().unwrap();
+                Some(format.read_format(input, dcr)?)
             }
         })
     }
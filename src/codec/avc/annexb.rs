@@ -141,7 +141,7 @@ impl ReadFormat<Avc> for AnnexB {
 }
 
 fn iterate_nalu_startcode(nalu: &[u8], start: usize) -> Result<(usize, usize), AvcError> {
-    if nalu.len() == 0 || start >= nalu.len() {
+    if nalu.is_empty() || start >= nalu.len() {
         return Err(AvcError::NotEnoughData("NALU data"));
     }
     let mut count = 0;
@@ -20,6 +20,7 @@ use {
 // 16   | PPS Length
 // var  | PPS
 #[derive(Debug, Clone)]
+#[allow(dead_code)]
 pub struct DecoderConfigurationRecord {
     pub version: u8,
     pub profile_indication: u8,
@@ -45,6 +46,7 @@ impl Default for DecoderConfigurationRecord {
 }
 
 impl DecoderConfigurationRecord {
+    #[allow(dead_code)]
     pub fn to_bytes(&self) -> Vec<u8> {
         let mut buf = vec![];
 
@@ -68,7 +70,7 @@ impl DecoderConfigurationRecord {
     }
 
     pub fn parse(&mut self) -> Result<(), AvcError> {
-        let sps_t = Sps::new(&self.sps.first().unwrap().payload());
+        let sps_t = Sps::new(self.sps.first().unwrap().payload());
         self.profile_indication = sps_t.profile_idc; //sps
         self.level_indication = sps_t.level_idc; //sps
         Ok(())
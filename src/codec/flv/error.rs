@@ -20,6 +20,9 @@ pub enum FlvError {
     #[error("Audio format with id {0} is not supported")]
     UnsupportedAudioFormat(u8),
 
+    #[error("Audio codec {0} is not supported")]
+    UnsupportedAudioFourCc(String),
+
     #[error("Not enough data: {0}")]
     NotEnoughData(&'static str),
 
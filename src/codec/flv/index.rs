@@ -0,0 +1,62 @@
+use std::path::{Path, PathBuf};
+use tokio::fs::{self, File};
+use tokio::io::AsyncWriteExt;
+
+/// Path of the keyframe index written next to a recording.
+pub fn path_for<P: AsRef<Path>>(recording: P) -> PathBuf {
+    recording.as_ref().with_extension("idx")
+}
+
+/// Keyframe index of a recording, mapping a keyframe timestamp (ms) to the
+/// byte offset of its tag.
+pub struct KeyframeIndex {
+    entries: Vec<(u64, u64)>,
+}
+
+impl KeyframeIndex {
+    pub async fn load<P: AsRef<Path>>(recording: P) -> std::io::Result<Self> {
+        let content = fs::read_to_string(path_for(recording)).await?;
+        let entries = content
+            .lines()
+            .filter_map(|line| {
+                let (timestamp, offset) = line.split_once(' ')?;
+                Some((timestamp.parse().ok()?, offset.parse().ok()?))
+            })
+            .collect();
+        Ok(Self { entries })
+    }
+
+    pub fn first(&self) -> Option<(u64, u64)> {
+        self.entries.first().copied()
+    }
+
+    /// The last keyframe at or before `timestamp`, falling back to the first.
+    pub fn seek(&self, timestamp: u64) -> Option<(u64, u64)> {
+        let pos = self.entries.partition_point(|&(ts, _)| ts <= timestamp);
+        self.entries.get(pos.saturating_sub(1)).copied()
+    }
+}
+
+/// Appends one `timestamp offset` line per keyframe, mapping its timestamp
+/// (ms) to the byte offset of its tag in the recording.
+pub struct IndexWriter {
+    file: File,
+}
+
+impl IndexWriter {
+    pub async fn create<P: AsRef<Path>>(recording: P) -> std::io::Result<Self> {
+        let file = File::create(path_for(recording)).await?;
+        Ok(Self { file })
+    }
+
+    pub async fn append(&mut self, timestamp: u64, offset: u64) -> std::io::Result<()> {
+        let line = format!("{} {}\n", timestamp, offset);
+        self.file.write_all(line.as_bytes()).await
+    }
+}
+
+// Frame type 1 (keyframe) with AVC/HEVC packet type 1 (NALU), read from the
+// tag header without parsing the whole body.
+pub(crate) fn is_keyframe_tag(payload: &[u8]) -> bool {
+    payload.len() > 1 && payload[0] >> 4 == 1 && payload[1] == 1
+}
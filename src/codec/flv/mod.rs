@@ -1,8 +1,6 @@
 pub mod error;
+pub mod index;
 pub mod tag;
 pub mod writer;
 
-pub use {
-    tag::audio, tag::audio::AudioData, tag::video::AvcPacketType, tag::video::Codec,
-    tag::video::VideoData,
-};
+pub use {tag::audio, tag::audio::AudioData, tag::video::Codec, tag::video::VideoData};
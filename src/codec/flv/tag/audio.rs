@@ -16,13 +16,17 @@ pub struct Frequency(u32);
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum AudioFormat {
     Aac,
+    /// Dolby Digital, passed through as is.
+    Ac3,
+    /// Dolby Digital Plus, passed through as is.
+    Eac3,
 }
 
 impl TryFrom<u8> for AudioFormat {
     type Error = FlvError;
 
     fn try_from(val: u8) -> Result<Self, Self::Error> {
-        // 目前只支持aac
+        // 目前只支持aac, 其余编码需用Enhanced RTMP的FourCC
         if val == 10 {
             Ok(Self::Aac)
         } else {
@@ -31,6 +35,21 @@ impl TryFrom<u8> for AudioFormat {
     }
 }
 
+/// `SoundFormat` of Enhanced RTMP tags, a FourCC follows the header byte.
+const EX_HEADER: u8 = 9;
+
+impl AudioFormat {
+    fn from_fourcc(fourcc: &[u8; 4]) -> Result<Self, FlvError> {
+        match fourcc {
+            b"ac-3" => Ok(Self::Ac3),
+            b"ec-3" => Ok(Self::Eac3),
+            _ => Err(FlvError::UnsupportedAudioFourCc(
+                String::from_utf8_lossy(fourcc).into_owned(),
+            )),
+        }
+    }
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum AacPacketType {
     SequenceHeader,
@@ -58,6 +77,16 @@ impl TryFrom<u8> for AacPacketType {
 // Stereo Flag          | u1
 // AAC Packet Type      | u8
 // Body                 | [u8]
+//
+// Enhanced RTMP tags (Audio Format 9) instead carry the packet type in the
+// low nibble followed by the FourCC of the codec:
+//
+// Field                | Type
+// -------------------- | ---
+// Audio Format         | u4
+// Audio Packet Type    | u4
+// FourCC               | [u8; 4]
+// Body                 | [u8]
 #[derive(Clone)]
 pub struct AudioData {
     pub format: AudioFormat,
@@ -85,6 +114,9 @@ impl TryFrom<&[u8]> for AudioData {
         let mut buf = Cursor::new(bytes);
 
         let header = buf.get_u8();
+        if header >> 4 == EX_HEADER {
+            return Self::try_from_ex_header(header, buf);
+        }
         let format = AudioFormat::try_from(header >> 4)?;
         let sampling_rate = try_convert_sampling_rate((header >> 2) & 0x02)?;
         let sample_size = try_convert_sample_size((header >> 1) & 0x01)?;
@@ -110,6 +142,35 @@ impl TryFrom<&[u8]> for AudioData {
     }
 }
 
+impl AudioData {
+    fn try_from_ex_header(header: u8, mut buf: Cursor<&[u8]>) -> Result<Self, FlvError> {
+        if buf.remaining() < 4 {
+            return Err(FlvError::NotEnoughData("FLV Audio Tag FourCC"));
+        }
+        let mut fourcc = [0; 4];
+        buf.copy_to_slice(&mut fourcc);
+        let format = AudioFormat::from_fourcc(&fourcc)?;
+        let aac_packet_type = match header & 0x0f {
+            0 => AacPacketType::SequenceHeader,
+            1 => AacPacketType::Raw,
+            x => return Err(FlvError::UnknownPackageType(x)),
+        };
+
+        let mut body = Vec::new();
+        buf.read_to_end(&mut body)?;
+
+        // the frames describe themselves, the legacy fields don't apply
+        Ok(Self {
+            format,
+            sampling_rate: Frequency(44000),
+            sample_size: 16,
+            stereo: true,
+            aac_packet_type,
+            body: body.into(),
+        })
+    }
+}
+
 impl Debug for AudioData {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("AudioData")
@@ -54,6 +54,7 @@ impl TryInto<u8> for FrameType {
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Copy)]
+#[allow(dead_code)]
 pub enum AvcPacketType {
     SequenceHeader,
     NalUnit,
@@ -111,6 +112,7 @@ impl VideoData {
         self.frame_type == FrameType::KeyFrame
     }
 
+    #[allow(dead_code)]
     pub fn as_bytes(&self) -> Vec<u8> {
         let mut a = vec![];
         let ft: u8 = self.frame_type.try_into().unwrap();
@@ -1,53 +1,111 @@
+use super::index::{self, IndexWriter};
 use crate::packet::{Packet, PacketType};
 use crate::{put_i24_be, put_i32_be, FLV_HEADER};
+use bytes::{BufMut, BytesMut};
 use std::path::Path;
 use tokio::fs::File;
 use tokio::io::AsyncWriteExt;
 
 pub struct Writer {
     file: File,
+    index: IndexWriter,
+    offset: u64,
 }
 
 impl Writer {
     pub async fn new<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
-        let mut file = File::create(path).await?;
-        file.write(&FLV_HEADER).await?;
-        Ok(Self { file })
+        let mut file = File::create(&path).await?;
+        file.write_all(&FLV_HEADER).await?;
+        let index = IndexWriter::create(&path).await?;
+        Ok(Self {
+            file,
+            index,
+            offset: FLV_HEADER.len() as u64,
+        })
     }
 
     pub async fn write(&mut self, packet: &Packet) -> std::io::Result<()> {
-        let type_id = match packet.kind {
-            PacketType::Audio => 8,
-            PacketType::Meta => {
-                //@todo
-                18
-            }
-            PacketType::Video => 9,
-        };
-
-        let data_len = packet.payload.len();
-        let timestamp: u64 = match packet.timestamp {
-            Some(u) => u.into(),
-            None => 0,
-        };
-
-        let pre_data_len = data_len + 11;
-        let timestamp_base = timestamp & 0xffffff;
-        let timestamp_ext = timestamp >> 24 & 0xff;
-        let mut h = [0u8; 11];
-
-        h[0] = type_id;
-        put_i24_be(&mut h[1..4], data_len as i32);
-        put_i24_be(&mut h[4..7], timestamp_base as i32);
-        h[7] = timestamp_ext as u8;
-
+        let tag = tag(packet);
+        if matches!(packet.kind, PacketType::Video) && index::is_keyframe_tag(&packet.payload) {
+            self.index
+                .append(timestamp(packet).into(), self.offset)
+                .await?;
+        }
         //这边需要使用write_all write可能数据没写完整
-        self.file.write_all(&h).await?;
-        self.file.write_all(&packet.payload).await?;
+        self.file.write_all(&tag).await?;
+        self.offset += tag.len() as u64;
+        Ok(())
+    }
+}
 
-        put_i32_be(&mut h[0..4], pre_data_len as i32);
-        self.file.write_all(&h[0..4]).await?;
+/// The FLV timestamp of a packet. FLV has 32 bits for it, 24 in the tag
+/// header and 8 in its extension, so later timestamps wrap around like
+/// RTMP ones do.
+pub fn timestamp(packet: &Packet) -> u32 {
+    packet.timestamp.map_or(0, |t| u64::from(t) as u32)
+}
 
-        Ok(())
+/// A packet as an FLV tag followed by its previous tag size, as written to
+/// recordings and HTTP-FLV players.
+pub fn tag(packet: &Packet) -> BytesMut {
+    let type_id = match packet.kind {
+        PacketType::Audio => 8,
+        PacketType::Meta => 18,
+        PacketType::Video => 9,
+    };
+    // RTMP message lengths have 24 bits as well
+    let data_len = packet.payload.len();
+    debug_assert!(data_len <= 0xffffff);
+    let timestamp = timestamp(packet);
+
+    let mut h = [0u8; 11];
+    h[0] = type_id;
+    put_i24_be(&mut h[1..4], data_len as i32);
+    put_i24_be(&mut h[4..7], (timestamp & 0xffffff) as i32);
+    h[7] = (timestamp >> 24) as u8;
+
+    let mut tag = BytesMut::with_capacity(h.len() + data_len + 4);
+    tag.put_slice(&h);
+    tag.put_slice(&packet.payload);
+    put_i32_be(&mut h[0..4], (data_len + 11) as i32);
+    tag.put_slice(&h[0..4]);
+    tag
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The 24 timestamp bits of a tag header and its extension byte.
+    fn header_timestamp(timestamp: u64) -> [u8; 4] {
+        let tag = tag(&Packet::new_audio(timestamp, vec![0xaf, 1]));
+        [tag[4], tag[5], tag[6], tag[7]]
+    }
+
+    #[test]
+    fn splits_timestamps_into_header_and_extension() {
+        assert_eq!(header_timestamp(0xffffff), [0xff, 0xff, 0xff, 0x00]);
+        assert_eq!(header_timestamp(0x1000000), [0x00, 0x00, 0x00, 0x01]);
+        assert_eq!(header_timestamp(0xffffffff), [0xff, 0xff, 0xff, 0xff]);
+    }
+
+    #[test]
+    fn wraps_timestamps_beyond_32_bits() {
+        assert_eq!(header_timestamp(0x1_0000_0005), [0x00, 0x00, 0x05, 0x00]);
+        assert_eq!(timestamp(&Packet::new_audio(0x1_0000_0005u64, vec![])), 5);
+    }
+
+    #[test]
+    fn ends_tags_with_their_size() {
+        let payload = vec![0x17, 1, 0, 0, 0, 0xaa, 0xbb];
+        let tag = tag(&Packet::new_video(0x1234567u64, payload.clone()));
+        assert_eq!(tag.len(), 11 + payload.len() + 4);
+        assert_eq!(tag[0], 9);
+        assert_eq!(&tag[1..4], &[0, 0, payload.len() as u8]);
+        // the stream id is always 0
+        assert_eq!(&tag[8..11], &[0, 0, 0]);
+        assert_eq!(&tag[11..11 + payload.len()], &payload[..]);
+        let size = u32::from_be_bytes([tag[18], tag[19], tag[20], tag[21]]);
+        assert_eq!(size as usize, 11 + payload.len());
     }
 }
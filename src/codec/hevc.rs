@@ -13,7 +13,7 @@ use {
     },
 };
 
-pub use self::{annexb::AnnexB, error::HevcError, hvcc::Hvcc, nal::NaluType};
+pub use self::{annexb::AnnexB, error::HevcError, hvcc::Hvcc};
 
 pub struct Hevc(Vec<nal::Unit>);
 
@@ -87,8 +87,8 @@ impl FormatReader<Hvcc> for HevcCoder {
                 None
             }
             State::Ready => {
-                let mut dcr = self.dcr.as_mut().unwrap();
-                Some(format.read_format(input, &mut dcr)?)
+                let dcr = self.dcr.as_mut().unwrap();
+                Some(format.read_format(input, dcr)?)
             }
         })
     }
@@ -106,14 +106,14 @@ impl FormatReader<AnnexB> for HevcCoder {
         Ok(match &self.state {
             State::Initializing => {
                 self.dcr = Some(HEVCDecoderConfigurationRecord::default());
-                let mut dcr = self.dcr.as_mut().unwrap();
-                let nals = format.read_format(input, &mut dcr)?;
+                let dcr = self.dcr.as_mut().unwrap();
+                let nals = format.read_format(input, dcr)?;
                 self.state = State::Ready;
                 Some(nals)
             }
             State::Ready => {
-                let mut dcr = self.dcr.as_mut().unwrap();
-                Some(format.read_format(input, &mut dcr)?)
+                let dcr = self.dcr.as_mut().unwrap();
+                Some(format.read_format(input, dcr)?)
             }
         })
     }
@@ -2,7 +2,6 @@ use std::convert::TryFrom;
 
 use super::{ReadFormat, WriteFormat};
 use crate::codec::hevc::{config::HEVCDecoderConfigurationRecord, error::HevcError, nal, Hevc};
-use log::info;
 pub struct AnnexB;
 
 impl AnnexB {
@@ -87,7 +86,7 @@ impl ReadFormat<Hevc> for AnnexB {
 
         let (mut pre_pos, mut pre_length) = match iterate_nalu_startcode(nals, 0) {
             Ok(e) => e,
-            Err(e) => {
+            Err(_) => {
                 let nal_unit = nal::Unit::try_from(&nals[0..])?;
                 match nal_unit.kind {
                     nal::NaluType::NaluTypeVps => {
@@ -113,7 +112,7 @@ impl ReadFormat<Hevc> for AnnexB {
             let start = pre_pos + pre_length;
             let (pos, length) = match iterate_nalu_startcode(nals, start) {
                 Ok(e) => e,
-                Err(e) => {
+                Err(_) => {
                     if start < nals.len() {
                         let nal_unit = nal::Unit::try_from(&nals[start..])?;
                         match nal_unit.kind {
@@ -168,7 +167,7 @@ impl ReadFormat<Hevc> for AnnexB {
 }
 
 fn iterate_nalu_startcode(nalu: &[u8], start: usize) -> Result<(usize, usize), HevcError> {
-    if nalu.len() == 0 || start >= nalu.len() {
+    if nalu.is_empty() || start >= nalu.len() {
         return Err(HevcError::NotEnoughData("NALU data"));
     }
     let mut count = 0;
@@ -142,14 +142,13 @@ impl TryFrom<&[u8]> for HEVCDecoderConfigurationRecord {
             pps.push(nal::Unit::try_from(&*tmp)?);
         }
 
-        let mut c = Self::default();
-
-        c.configuration_version = configuration_version;
-        c.vps = vps;
-        c.sps = sps;
-        c.pps = pps;
-
-        Ok(c)
+        Ok(Self {
+            configuration_version,
+            vps,
+            sps,
+            pps,
+            ..Default::default()
+        })
     }
 }
 
@@ -221,7 +220,7 @@ impl HEVCDecoderConfigurationRecord {
 
         let general_profile_compatibility_flags = buf.get_u32();
         let temp = buf.get_u64();
-        let general_constraint_indicator_flags = temp >> 16;
+        let _general_constraint_indicator_flags = temp >> 16;
         let general_level_idc = ((temp | 0x00_00_00_00_00_00_FF_00) >> 8) as u8;
 
         self.general_profile_space = general_profile_space;
@@ -271,8 +270,7 @@ impl HEVCDecoderConfigurationRecord {
         buf.put_u16(0);
 
         buf.put_u8(
-            0 << 6
-                | self.num_temporal_layers << 3
+            (self.num_temporal_layers << 3)
                 | self.temporal_id_nested << 2
                 | self.length_size_minus_one,
         );
@@ -19,7 +19,7 @@ impl ReadFormat<Hevc> for Hvcc {
             let nalu_data = buf
                 .chunk()
                 .get(..nalu_length)
-                .ok_or_else(|| HevcError::NotEnoughData("NALU data"))?
+                .ok_or(HevcError::NotEnoughData("NALU data"))?
                 .to_owned();
             buf.advance(nalu_length);
             let nal_unit = nal::Unit::try_from(&*nalu_data)?;
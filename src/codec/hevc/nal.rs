@@ -5,6 +5,7 @@ use {
 };
 
 #[derive(Debug, Copy, Clone, PartialEq, PartialOrd, Eq, Ord)]
+#[allow(clippy::enum_variant_names)]
 pub enum NaluType {
     NaluTypeSliceTrailN = 0, // 0x0
     NaluTypeSliceTrailR = 1, // 0x01
@@ -59,7 +60,7 @@ pub enum NaluType {
 }
 
 impl NaluType {
-    pub fn to_string(&self) -> &'static str {
+    pub fn to_string(self) -> &'static str {
         match self {
             NaluType::NaluTypeSliceTrailN => "TrailN",
             NaluType::NaluTypeSliceTrailR => "TrailR",
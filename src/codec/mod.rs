@@ -37,5 +37,3 @@ where
 
     fn write_format(&mut self, format: F, input: Self::Input) -> Result<Vec<u8>, Self::Error>;
 }
-
-pub use flv::error::FlvError;
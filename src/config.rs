@@ -1,20 +1,17 @@
 use lazy_static::lazy_static;
 
 use config::Config;
+use config::ConfigError;
+use config::Environment;
 use config::File;
-use serde::Deserialize;
+use crate::naming;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::sync::RwLock;
+use std::time::Duration;
 
 lazy_static! {
-    static ref SETTINGS: RwLock<Settings> = {
-        let conf = Config::builder()
-            .add_source(File::with_name("conf.yaml"))
-            .build()
-            .unwrap();
-
-        let s: Settings = conf.try_deserialize().unwrap();
-        RwLock::new(s)
-    };
+    static ref SETTINGS: RwLock<Settings> = RwLock::new(load().unwrap());
 }
 
 pub fn get_setting() -> Settings {
@@ -22,40 +19,585 @@ pub fn get_setting() -> Settings {
     lock.to_owned()
 }
 
-#[derive(Debug, Deserialize, Clone)]
+/// Reads the optional `conf.yaml`, then `XLIVE__` environment variables on
+/// top of it, with `__` between nesting levels (`XLIVE__HLS__PORT`). Every
+/// setting has a default and unknown keys are rejected, but only variables
+/// naming a known section are read, others may belong to something else.
+pub fn load() -> Result<Settings, ConfigError> {
+    Config::builder()
+        .add_source(File::with_name("conf.yaml").required(false))
+        .add_source(
+            Environment::with_prefix("XLIVE")
+                .separator("__")
+                .source(Some(environment())),
+        )
+        .build()?
+        .try_deserialize()
+}
+
+/// The `XLIVE__` variables whose first level is a section of the settings.
+fn environment() -> config::Map<String, String> {
+    let sections = match serde_json::to_value(Settings::default()) {
+        Ok(serde_json::Value::Object(sections)) => sections,
+        _ => serde_json::Map::new(),
+    };
+    std::env::vars()
+        .filter(|(key, _)| {
+            let key = key.to_lowercase();
+            key.strip_prefix("xlive__")
+                .and_then(|path| path.split("__").next())
+                .map_or(false, |section| sections.contains_key(section))
+        })
+        .collect()
+}
+
+impl Settings {
+    /// Problems in otherwise well-formed settings.
+    pub fn check(&self) -> Vec<String> {
+        let mut problems = vec![];
+        if self.hls.ts_duration == 0 {
+            problems.push("hls.ts_duration must be greater than 0".to_owned());
+        }
+        if self.hls.playlist_length == 0 {
+            problems.push("hls.playlist_length must be greater than 0".to_owned());
+        }
+        if self.hls.pts_offset >= 1 << 33 {
+            problems.push("hls.pts_offset must be below 2^33".to_owned());
+        }
+        if self.http.max_header_bytes < 8192 {
+            problems.push("http.max_header_bytes must be at least 8192".to_owned());
+        }
+        if self.http.header_timeout == 0 {
+            problems.push("http.header_timeout must be greater than 0".to_owned());
+        }
+        let ports = [
+            ("rtmp", self.rtmp.port),
+            ("hls", self.hls.port),
+            ("http_flv", self.http_flv.port),
+        ];
+        for (i, (name, port)) in ports.iter().enumerate() {
+            if !(1..=65535).contains(port) {
+                problems.push(format!("{}.port {} is not a valid port", name, port));
+            }
+            for (other, other_port) in &ports[..i] {
+                if port == other_port {
+                    problems.push(format!("{}.port and {}.port are both {}", other, name, port));
+                }
+            }
+        }
+        if !self.webhook.is_empty() {
+            match url::Url::parse(&self.webhook) {
+                Ok(url) if url.scheme() == "http" => {}
+                Ok(_) => problems.push("webhook must be a plain http URL".to_owned()),
+                Err(e) => problems.push(format!("webhook: {}", e)),
+            }
+        }
+        let admin_port = self.http_flv.admin_port;
+        if !(0..=65535).contains(&admin_port) {
+            problems.push(format!("http_flv.admin_port {} is not a valid port", admin_port));
+        } else if admin_port != 0 && ports.iter().any(|(_, port)| *port == admin_port) {
+            problems.push(format!("http_flv.admin_port {} is already in use", admin_port));
+        }
+        for (i, key) in self.http_flv.token_keys.iter().enumerate() {
+            if key.id.is_empty() || key.id.contains('.') {
+                problems.push(format!(
+                    "http_flv.token_keys[{}].id must be non-empty without '.'",
+                    i
+                ));
+            }
+            if key.secret.is_empty() {
+                problems.push(format!("http_flv.token_keys[{}].secret is empty", i));
+            }
+            let earlier = &self.http_flv.token_keys[..i];
+            if earlier.iter().any(|other| other.id == key.id) {
+                problems.push(format!("http_flv.token_keys id {} is not unique", key.id));
+            }
+        }
+        if self.test_source.enable {
+            if let Err(e) = naming::validate_app_name(&self.test_source.app) {
+                problems.push(format!("test_source.app: {}", e));
+            }
+            if let Err(e) = naming::validate_stream_key(&self.test_source.key) {
+                problems.push(format!("test_source.key: {}", e));
+            }
+        }
+        for (name, app) in &self.apps {
+            if let Err(e) = naming::validate_app_name(name) {
+                problems.push(format!("apps.{}: {}", name, e));
+            }
+            if let Some(Err(e)) = app.sync_group.as_deref().map(naming::validate_app_name) {
+                problems.push(format!("apps.{}.sync_group: {}", name, e));
+            }
+            #[cfg(feature = "hls")]
+            for (header, value) in &app.headers {
+                if hyper::header::HeaderName::from_bytes(header.as_bytes()).is_err() {
+                    problems.push(format!("apps.{}.headers: invalid name {}", name, header));
+                } else if hyper::header::HeaderValue::from_str(value).is_err() {
+                    problems.push(format!("apps.{}.headers.{}: invalid value", name, header));
+                }
+            }
+            for origin in &app.backup_origins {
+                match url::Url::parse(origin) {
+                    Ok(url) if matches!(url.scheme(), "http" | "https") => {}
+                    Ok(_) => problems.push(format!(
+                        "apps.{}.backup_origins {}: not an http URL",
+                        name, origin
+                    )),
+                    Err(e) => {
+                        problems.push(format!("apps.{}.backup_origins {}: {}", name, origin, e))
+                    }
+                }
+            }
+            for mirror in &app.mirrors {
+                if let Err(e) = naming::validate_app_name(mirror) {
+                    problems.push(format!("apps.{}.mirrors {}: {}", name, mirror, e));
+                } else if mirror == name {
+                    problems.push(format!("apps.{} mirrors itself", name));
+                } else if self.apps.get(mirror).map_or(false, |m| !m.mirrors.is_empty()) {
+                    problems.push(format!("apps.{}.mirrors {} has mirrors of its own", name, mirror));
+                }
+            }
+        }
+        let mut backed_up = HashMap::new();
+        for (name, app) in &self.apps {
+            let backup = match &app.backup {
+                Some(backup) => backup,
+                None => continue,
+            };
+            if let Err(e) = naming::validate_app_name(backup) {
+                problems.push(format!("apps.{}.backup {}: {}", name, backup, e));
+            } else if backup == name {
+                problems.push(format!("apps.{} is its own backup", name));
+            } else if self.apps.get(backup).map_or(false, |b| b.backup.is_some()) {
+                problems.push(format!("apps.{}.backup {} has a backup of its own", name, backup));
+            }
+            if let Some(other) = backed_up.insert(backup, name) {
+                problems.push(format!("apps.{} is the backup of both {} and {}", backup, other, name));
+            }
+        }
+        for (name, mosaic) in &self.mosaics {
+            if let Err(e) = naming::validate_app_name(name) {
+                problems.push(format!("mosaics.{}: {}", name, e));
+            }
+            if let Err(e) = naming::validate_stream_key(&mosaic.key) {
+                problems.push(format!("mosaics.{}.key: {}", name, e));
+            }
+            if mosaic.sources.len() < 2 {
+                problems.push(format!("mosaics.{} needs at least 2 sources", name));
+            }
+            for source in &mosaic.sources {
+                if let Err(e) = naming::validate_app_name(source) {
+                    problems.push(format!("mosaics.{}.sources {}: {}", name, source, e));
+                } else if self.mosaics.contains_key(source) {
+                    problems.push(format!("mosaics.{}.sources {} is a mosaic", name, source));
+                }
+            }
+            if mosaic.tile_width == 0 || mosaic.tile_height == 0 {
+                problems.push(format!("mosaics.{} tiles must not be empty", name));
+            }
+        }
+        let mut mirrored = HashMap::new();
+        for (name, app) in &self.apps {
+            for mirror in &app.mirrors {
+                if let Some(other) = mirrored.insert(mirror, name) {
+                    problems.push(format!("apps.{} is mirrored by both {} and {}", mirror, other, name));
+                }
+            }
+        }
+        problems
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default, deny_unknown_fields)]
 pub struct Settings {
     pub rtmp: Rtmp,
     pub hls: Hls,
     pub http_flv: HTTPFLV,
     pub redis: String,
+    /// Host name encoders and players reach the server by, used in the
+    /// URLs `/urls` hands out. Empty uses the host of the request.
+    pub public_host: String,
     pub auth_enable: bool,
+    /// Plain HTTP URL stream events are posted to as JSON. Empty posts
+    /// none.
+    pub webhook: String,
     pub log_level: String,
     pub full_gop: bool,
-    pub flv:Flv,
+    pub memory_limit_mb: u64,
+    /// Seconds sinks get to flush after the publishers were closed on
+    /// shutdown, before the HTTP servers stop.
+    pub shutdown_grace: u64,
+    pub flv: Flv,
+    pub test_source: TestSource,
+    pub socket: Socket,
+    pub http: Http,
+    pub clock: Clock,
+    /// Overrides for single apps, keyed by app name.
+    pub apps: HashMap<String, App>,
+    /// Channels composed of other channels, keyed by their app name.
+    pub mosaics: HashMap<String, Mosaic>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            rtmp: Rtmp::default(),
+            hls: Hls::default(),
+            http_flv: HTTPFLV::default(),
+            redis: "redis://127.0.0.1/".to_owned(),
+            public_host: String::new(),
+            auth_enable: false,
+            webhook: String::new(),
+            log_level: "info".to_owned(),
+            full_gop: true,
+            memory_limit_mb: 0,
+            shutdown_grace: 10,
+            flv: Flv::default(),
+            test_source: TestSource::default(),
+            socket: Socket::default(),
+            http: Http::default(),
+            clock: Clock::default(),
+            apps: HashMap::new(),
+            mosaics: HashMap::new(),
+        }
+    }
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default, deny_unknown_fields)]
 pub struct Rtmp {
     pub port: i32,
+    /// Seconds a channel is kept alive after its publisher drops, so a
+    /// reconnecting publisher can resume it. 0 disables resumption.
+    pub reconnect_grace: u64,
+    /// Seconds a publisher may take to send its first audio or video
+    /// packet before it is disconnected. 0 waits forever.
+    pub first_packet_timeout: u64,
+    /// Seconds a connected primary publisher may send nothing before its
+    /// backup takes over. 0 only switches when the primary disconnects.
+    pub failover_after: u64,
+    /// Seconds between `NetStream.Publish.Stats` reports of the received
+    /// bitrate and frames sent to publishers. 0 sends none.
+    pub stats_interval: u64,
+    /// Seconds a channel may be published continuously before it is
+    /// stopped, against forgotten encoders. 0 never stops it.
+    pub max_duration: u64,
+    /// Seconds before the stop the webhook is warned.
+    pub duration_warning: u64,
+}
+
+impl Default for Rtmp {
+    fn default() -> Self {
+        Self {
+            port: 1935,
+            reconnect_grace: 0,
+            first_packet_timeout: 0,
+            failover_after: 0,
+            stats_interval: 0,
+            max_duration: 0,
+            duration_warning: 300,
+        }
+    }
+}
+
+/// TCP options for the RTMP, HLS and HTTP-FLV listeners.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Socket {
+    pub nodelay: bool,
+    /// Send and receive buffer sizes in bytes, 0 for the OS default.
+    pub send_buffer: u32,
+    pub recv_buffer: u32,
+    /// Seconds a connection is idle before keepalive probes are sent, 0
+    /// disables keepalive.
+    pub keepalive: u64,
+}
+
+impl Socket {
+    pub fn keepalive(&self) -> Option<Duration> {
+        Some(Duration::from_secs(self.keepalive)).filter(|d| !d.is_zero())
+    }
+}
+
+impl Default for Socket {
+    fn default() -> Self {
+        Self {
+            nodelay: true,
+            send_buffer: 0,
+            recv_buffer: 0,
+            keepalive: 0,
+        }
+    }
+}
+
+/// Request limits of the HLS and HTTP-FLV servers.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Http {
+    pub max_uri_bytes: usize,
+    /// Request line and headers, at least 8192.
+    pub max_header_bytes: usize,
+    /// Largest request body, checked against `Content-Length` up front and
+    /// while reading bodies sent without one.
+    pub max_body_bytes: u64,
+    /// Seconds a client has to send a request after connecting or after
+    /// the previous response, idle keep-alive connections are closed too.
+    pub header_timeout: u64,
+}
+
+impl Default for Http {
+    fn default() -> Self {
+        Self {
+            max_uri_bytes: 8192,
+            max_header_bytes: 16384,
+            max_body_bytes: 65536,
+            header_timeout: 30,
+        }
+    }
+}
+
+/// Watching the system clock against NTP time, segments and recordings
+/// are named after it.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Clock {
+    /// Seconds between measurements, 0 disables them.
+    pub check_interval: u64,
+    /// Drift in milliseconds above which the clock is reported off, 0
+    /// never reports it.
+    pub max_drift_ms: u64,
+    /// Don't start FLV recordings while the clock is off.
+    pub refuse_recordings: bool,
+    /// Server `sntp` asks when chronyd is not running.
+    pub ntp_server: String,
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Self {
+            check_interval: 0,
+            max_drift_ms: 1000,
+            refuse_recordings: false,
+            ntp_server: "pool.ntp.org".to_owned(),
+        }
+    }
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default, deny_unknown_fields)]
 pub struct Flv {
     pub enable: bool,
     pub data_path: String,
 }
 
+impl Default for Flv {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            data_path: "data/flv".to_owned(),
+        }
+    }
+}
+
+/// A built-in publisher of color bars, for trying the server without an
+/// encoder.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct TestSource {
+    pub enable: bool,
+    pub app: String,
+    /// Stream key it publishes with, checked like any other when auth is
+    /// enabled.
+    pub key: String,
+}
+
+impl Default for TestSource {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            app: "testbars".to_owned(),
+            key: "test".to_owned(),
+        }
+    }
+}
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default, deny_unknown_fields)]
 pub struct Hls {
     pub enable: bool,
     pub port: i32,
     pub ts_duration: u64,
     pub data_path: String,
+    /// Segments listed in the live playlist.
+    pub playlist_length: usize,
+    /// Segments kept on disk, never fewer than `playlist_length`.
+    pub retention: usize,
+    /// Seconds after which the directory of a stream that is no longer
+    /// published is removed. 0 keeps them.
+    pub cleanup_after: u64,
+    /// Disk quota in MB for all stream directories, 0 for none. Directories
+    /// of ended streams are removed oldest first to stay under it.
+    pub max_disk_mb: u64,
+    pub playlist_type: PlaylistType,
+    /// Tag every segment with #EXT-X-PROGRAM-DATE-TIME, the wall clock time
+    /// its first frame was received at.
+    pub program_date_time: bool,
+    /// 90 kHz ticks added to every PCR, PTS and DTS, below 2^33.
+    pub pts_offset: u64,
+    /// Only serve the playlists and segments another instance writes to a
+    /// shared `data_path`, without ingest or any other service.
+    pub replica: bool,
+}
+
+impl Default for Hls {
+    fn default() -> Self {
+        Self {
+            enable: true,
+            port: 3000,
+            ts_duration: 5,
+            data_path: "data".to_owned(),
+            playlist_length: 6,
+            retention: 0,
+            cleanup_after: 0,
+            max_disk_mb: 0,
+            playlist_type: PlaylistType::Live,
+            program_date_time: false,
+            pts_offset: 0,
+            replica: false,
+        }
+    }
+}
+
+/// What the HLS playlist of a stream keeps listing.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PlaylistType {
+    /// A sliding window of the newest `playlist_length` segments.
+    Live,
+    /// Every segment since the stream started, nothing is deleted while it
+    /// is live. Becomes a VOD playlist once the stream ends.
+    Event,
+}
+
+/// A grid of other channels, published into its own channel by an ffmpeg
+/// subprocess (feature `mosaic`).
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Mosaic {
+    /// Apps shown, row by row. The mosaic runs while all are live.
+    pub sources: Vec<String>,
+    /// Tiles per row, 0 for a square-ish grid.
+    pub columns: usize,
+    pub tile_width: u32,
+    pub tile_height: u32,
+    /// Stream key the mosaic is published with, checked like any other
+    /// when auth is enabled.
+    #[serde(skip_serializing)]
+    pub key: String,
+    pub ffmpeg: String,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+impl Mosaic {
+    pub fn columns(&self) -> usize {
+        match self.columns {
+            0 => (self.sources.len() as f64).sqrt().ceil().max(1.0) as usize,
+            columns => columns,
+        }
+    }
+}
+
+impl Default for Mosaic {
+    fn default() -> Self {
+        Self {
+            sources: vec![],
+            columns: 0,
+            tile_width: 480,
+            tile_height: 270,
+            key: "mosaic".to_owned(),
+            ffmpeg: "ffmpeg".to_owned(),
+        }
+    }
+}
+
+/// Settings of a single app. Unset fields fall back to the global ones.
+#[derive(Debug, Default, Deserialize, Serialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct App {
+    pub playlist_type: Option<PlaylistType>,
+    pub retention: Option<usize>,
+    /// Overrides `rtmp.max_duration`, 0 never stops the app.
+    pub max_duration: Option<u64>,
+    /// Apps opened along with this one whenever it is published, carrying
+    /// the same packets, so one ingest can be played back under several
+    /// names with their own tokens and settings.
+    pub mirrors: Vec<String>,
+    /// App a backup publisher streams to. While this app's own publisher
+    /// is away the backup feeds its channel, the primary takes over again
+    /// once it is back.
+    pub backup: Option<String>,
+    /// Streams sharing a sync group are dated by the ingest wall clock and
+    /// listed together at `/groups/{group}.json`.
+    pub sync_group: Option<String>,
+    /// Extra headers on the app's HLS playlist and segment responses, e.g.
+    /// `Cross-Origin-Resource-Policy` for cross-origin isolated pages.
+    pub headers: HashMap<String, String>,
+    /// Base URLs of origins serving the same playlists, e.g.
+    /// `https://backup.example.com`, listed as redundant variants in the
+    /// app's master playlist for players to fail over to.
+    pub backup_origins: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default, deny_unknown_fields)]
 pub struct HTTPFLV {
     pub enable: bool,
     pub port: i32,
+    /// Secret for signed playback tokens (`xlive mint-token`). Empty
+    /// accepts any token.
+    #[serde(skip_serializing)]
+    pub token_secret: String,
+    /// Rotating signing keys, checked before `token_secret`.
+    pub token_keys: Vec<TokenKey>,
+    /// Serve the `/streams` listing. Stream names are often stream keys,
+    /// so without a token secret it is readable by anyone.
+    pub expose_streams: bool,
+    /// Port of a listener on 127.0.0.1 serving the same routes without
+    /// tokens, for local tooling and health scripts. 0 disables it.
+    pub admin_port: i32,
+}
+
+impl Default for HTTPFLV {
+    fn default() -> Self {
+        Self {
+            enable: true,
+            port: 3006,
+            token_secret: String::new(),
+            token_keys: vec![],
+            expose_streams: true,
+            admin_port: 0,
+        }
+    }
+}
+
+/// A playback token signing key. The newest key whose `not_before` has
+/// passed signs new tokens, tokens signed with older keys are accepted
+/// until their `not_after`, which should leave a grace period as long as
+/// the longest token lifetime.
+#[derive(Debug, Default, Deserialize, Serialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct TokenKey {
+    pub id: String,
+    #[serde(skip_serializing)]
+    pub secret: String,
+    /// Unix time the key starts signing at, 0 for right away.
+    pub not_before: i64,
+    /// Unix time the key stops being accepted at, 0 for never.
+    pub not_after: i64,
+}
+
+impl TokenKey {
+    pub fn retired(&self, now: i64) -> bool {
+        self.not_after > 0 && self.not_after <= now
+    }
 }
@@ -1,14 +1,19 @@
+use crate::metrics::{self, DropReason};
 use crate::packet::{Packet, PacketType};
-use crate::rtmp::{Event, Protocol};
+use crate::rtmp::{Event, IngestStats, Protocol};
+use crate::sampling;
+use crate::transport::Publisher;
 use crate::{error::Error as PError, ChannelMessage, Handle, ManagerHandle, Message, Watcher};
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use futures::SinkExt;
-use log;
+use log::{self, Level};
+use std::net::SocketAddr;
+use std::sync::Arc;
 use std::time::Duration;
 use tokio::{
     io::{AsyncRead, AsyncWrite},
     sync::{mpsc, oneshot},
-    time::timeout,
+    time::{timeout, Instant},
 };
 use tokio_stream::StreamExt;
 use tokio_util::codec::{BytesCodec, Framed};
@@ -27,31 +32,72 @@ where
     S: AsyncRead + AsyncWrite + Unpin,
 {
     id: u64,
+    addr: SocketAddr,
     bytes_stream: Framed<S, BytesCodec>,
     manager_handle: ManagerHandle,
-    return_queue: ReturnQueue<Packet>,
+    return_queue: ReturnQueue<Arc<Packet>>,
     proto: Protocol,
     app_name: Option<String>,
     state: State,
+    first_packet_timeout: Option<Duration>,
+    // set while publishing until the first audio or video packet arrives
+    media_deadline: Option<Instant>,
+    stats_interval: Option<Duration>,
+    // received since the last report to the publisher
+    stats: IngestStats,
+    stats_since: Instant,
+    // dropped with the connection, the manager watches it
+    presence: Arc<()>,
 }
 
 impl<S> Connection<S>
 where
     S: AsyncRead + AsyncWrite + Unpin,
 {
-    pub fn new(id: u64, stream: S, manager_handle: ManagerHandle) -> Self {
+    pub fn new(
+        id: u64,
+        addr: SocketAddr,
+        stream: S,
+        manager_handle: ManagerHandle,
+        first_packet_timeout: Option<Duration>,
+        stats_interval: Option<Duration>,
+    ) -> Self {
         Self {
             id,
+            addr,
             bytes_stream: Framed::new(stream, BytesCodec::new()),
             manager_handle,
             return_queue: mpsc::unbounded_channel(),
             proto: Protocol::new(),
             app_name: None,
             state: State::Initializing,
+            first_packet_timeout,
+            media_deadline: None,
+            stats_interval,
+            stats: IngestStats::default(),
+            stats_since: Instant::now(),
+            presence: Arc::new(()),
         }
     }
 
     pub async fn run(mut self) -> Result<()> {
+        let res = self.process().await;
+        if res.is_err() {
+            // don't leave the channel of a failed publisher open
+            _ = self.disconnect();
+        }
+        res.map_err(|e| anyhow!("Client {}: {}", self.label(), e))
+    }
+
+    /// `#id addr`, followed by the app name once the client negotiated one.
+    fn label(&self) -> String {
+        match &self.app_name {
+            Some(app_name) => format!("#{} {} {}", self.id, self.addr, app_name),
+            None => format!("#{} {}", self.id, self.addr),
+        }
+    }
+
+    async fn process(&mut self) -> Result<()> {
         loop {
             while let Ok(packet) = self.return_queue.1.try_recv() {
                 if self.handle_return_packet(packet).await.is_err() {
@@ -61,9 +107,24 @@ where
 
             match &mut self.state {
                 State::Initializing | State::Publishing(_) => {
+                    if let (Some(deadline), Some(limit)) =
+                        (self.media_deadline, self.first_packet_timeout)
+                    {
+                        if Instant::now() >= deadline {
+                            return Err(PError::NoMediaReceived(limit.as_secs()).into());
+                        }
+                    }
+                    if let Some(interval) = self.stats_interval {
+                        if matches!(self.state, State::Publishing(_))
+                            && self.stats_since.elapsed() >= interval
+                        {
+                            self.report_stats().await?;
+                        }
+                    }
                     let val = self.bytes_stream.try_next();
                     match timeout(TIME_OUT, val).await? {
                         Ok(Some(data)) => {
+                            self.stats.bytes += data.len() as u64;
                             for event in self.proto.handle_bytes(&data)? {
                                 self.handle_event(event).await?;
                             }
@@ -80,27 +141,45 @@ where
                             PacketType::Audio => self.send_back(packet)?,
                         },
                         Err(RecvError::Closed) => self.disconnect()?,
-                        Err(_) => (),
+                        Err(RecvError::Lagged(skipped)) => {
+                            let app_name = self.app_name.as_deref().unwrap_or_default();
+                            metrics::dropped(app_name, DropReason::Lag, skipped);
+                        }
                     }
                 }
                 State::Disconnecting => {
-                    log::debug!("Disconnecting...");
+                    log::debug!("Disconnecting client {}", self.label());
                     return Ok(());
                 }
             }
         }
     }
 
-    async fn handle_return_packet(&mut self, packet: Packet) -> Result<()> {
+    async fn handle_return_packet(&mut self, packet: Arc<Packet>) -> Result<()> {
         let bytes = match packet.kind {
-            PacketType::Meta => self.proto.pack_metadata(packet)?,
-            PacketType::Video => self.proto.pack_video(packet)?,
-            PacketType::Audio => self.proto.pack_audio(packet)?,
+            PacketType::Meta => self.proto.pack_metadata(Packet::clone(&packet))?,
+            PacketType::Video => self.proto.pack_video(&packet)?,
+            PacketType::Audio => self.proto.pack_audio(&packet)?,
         };
         let res = timeout(TIME_OUT, self.bytes_stream.send(bytes.into())).await?;
         Ok(res?)
     }
 
+    /// Tells the publisher what was received since the previous report.
+    async fn report_stats(&mut self) -> Result<()> {
+        let elapsed = self.stats_since.elapsed().as_millis().max(1) as u64;
+        let mut stats = std::mem::take(&mut self.stats);
+        self.stats_since = Instant::now();
+        // bits per millisecond are kbit/s
+        stats.bitrate_kbps = stats.bytes * 8 / elapsed;
+        if let Some(app_name) = &self.app_name {
+            stats.dropped = metrics::drops(app_name);
+        }
+        let bytes = self.proto.pack_publish_stats(&stats)?;
+        timeout(TIME_OUT, self.bytes_stream.send(bytes.into())).await??;
+        Ok(())
+    }
+
     async fn handle_event(&mut self, event: Event) -> Result<()> {
         match event {
             Event::ReturnData(data) => {
@@ -110,6 +189,14 @@ where
                     .expect("Failed to return data");
             }
             Event::SendPacket(packet) => {
+                match packet.kind {
+                    PacketType::Video => self.stats.video_frames += 1,
+                    PacketType::Audio => self.stats.audio_frames += 1,
+                    PacketType::Meta => {}
+                }
+                if !matches!(packet.kind, PacketType::Meta) {
+                    self.media_deadline = None;
+                }
                 if let State::Publishing(session) = &mut self.state {
                     session
                         .send(Message::Packet(packet))
@@ -119,16 +206,31 @@ where
             Event::AcquireChannel {
                 app_name,
                 stream_key,
+                resume,
             } => {
                 self.app_name = Some(app_name.clone());
+                log::info!("Client {} publishing", self.label());
+                let publisher = Publisher {
+                    client_id: self.id,
+                    addr: self.addr,
+                    resume,
+                    presence: Arc::downgrade(&self.presence),
+                };
                 let (request, response) = oneshot::channel();
                 self.manager_handle
-                    .send(ChannelMessage::Create((app_name, stream_key, request)))
+                    .send(ChannelMessage::Create((
+                        app_name, stream_key, publisher, request,
+                    )))
                     .map_err(|_| PError::ChannelCreationFailed)?;
                 let session_sender = response.await.map_err(|_| PError::ChannelCreationFailed)?;
                 self.state = State::Publishing(session_sender);
+                self.media_deadline = self.first_packet_timeout.map(|t| Instant::now() + t);
+                self.stats = IngestStats::default();
+                self.stats_since = Instant::now();
             }
             Event::JoinChannel { app_name, .. } => {
+                self.app_name = Some(app_name.clone());
+                log::info!("Client {} playing", self.label());
                 let (request, response) = oneshot::channel();
                 self.manager_handle
                     .send(ChannelMessage::Join((app_name, request)))
@@ -152,17 +254,16 @@ where
                         meta.map(|m| self.send_back(m));
                         video.map(|v| self.send_back(v));
                         audio.map(|a| self.send_back(a));
-                        gop.map(|gop| {
-                            for g in gop {
-                                match self.send_back(g) {
-                                    Ok(_) => {}
-                                    Err(e) => {
-                                        log::error!("{}", e);
-                                        _ = self.disconnect();
-                                    }
+                        for g in gop.into_iter().flatten() {
+                            match self.send_back(g) {
+                                Ok(_) => {}
+                                Err(e) => {
+                                    let message = format!("Client {}: {}", self.label(), e);
+                                    sampling::log(Level::Error, message);
+                                    _ = self.disconnect();
                                 }
                             }
-                        });
+                        }
                     }
                 }
             }
@@ -171,7 +272,7 @@ where
         Ok(())
     }
 
-    fn send_back(&mut self, packet: Packet) -> Result<(), PError> {
+    fn send_back(&mut self, packet: Arc<Packet>) -> Result<(), PError> {
         self.return_queue
             .0
             .send(packet)
@@ -179,14 +280,11 @@ where
     }
 
     fn disconnect(&mut self) -> Result<(), PError> {
-        if let State::Publishing(session) = &mut self.state {
+        if let State::Publishing(_) = &self.state {
+            // the manager closes the channel, possibly after a reconnect grace period
             let app_name = self.app_name.clone().unwrap();
-            session
-                .send(Message::Disconnect)
-                .map_err(|_| PError::ChannelSendFailed)?;
-
             self.manager_handle
-                .send(ChannelMessage::Release(app_name))
+                .send(ChannelMessage::Release((app_name, self.id)))
                 .map_err(|_| PError::ChannelReleaseFailed)?;
         }
         self.state = State::Disconnecting;
@@ -199,6 +297,6 @@ where
     S: AsyncRead + AsyncWrite + Unpin,
 {
     fn drop(&mut self) {
-        log::info!("Client {} disconnected", self.id);
+        log::info!("Client {} disconnected", self.label());
     }
 }
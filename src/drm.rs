@@ -0,0 +1,109 @@
+use std::fmt::Write;
+
+/// Extension passed to [`hls::run`](crate::hls::run) that tells players
+/// where to get the key of a stream, e.g. a FairPlay or Widevine license
+/// server. Segments are written as they are, encrypting them is up to
+/// whatever sits between this server and the players.
+pub trait KeyProvider: Send + Sync {
+    /// The key of stream `app`, or `None` to leave its playlist in the clear.
+    /// Asked on every playlist request, a cached playlist is rendered again
+    /// once the key changes, so keys may rotate.
+    fn key(&self, app: &str) -> Option<Key>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Method {
+    Aes128,
+    SampleAes,
+    SampleAesCtr,
+}
+
+impl Method {
+    fn as_str(self) -> &'static str {
+        match self {
+            Method::Aes128 => "AES-128",
+            Method::SampleAes => "SAMPLE-AES",
+            Method::SampleAesCtr => "SAMPLE-AES-CTR",
+        }
+    }
+}
+
+/// The attributes of an `#EXT-X-KEY` or `#EXT-X-SESSION-KEY` tag.
+#[derive(Clone, Debug)]
+pub struct Key {
+    pub method: Method,
+    /// Where players fetch the key or license, e.g. `skd://` for FairPlay.
+    pub uri: String,
+    pub iv: Option<[u8; 16]>,
+    /// The key system, players skip keys of systems they don't support.
+    pub keyformat: Option<String>,
+    pub keyformat_versions: Option<String>,
+}
+
+impl Key {
+    /// `#EXT-X-KEY` line for a media playlist.
+    pub fn tag(&self) -> String {
+        format!("#EXT-X-KEY:{}\n", self.attributes())
+    }
+
+    /// `#EXT-X-SESSION-KEY` line for a master playlist, letting players
+    /// fetch the license before the first media playlist.
+    pub fn session_tag(&self) -> String {
+        format!("#EXT-X-SESSION-KEY:{}\n", self.attributes())
+    }
+
+    /// The lowest `#EXT-X-VERSION` a playlist carrying this key needs.
+    pub fn version(&self) -> u8 {
+        match (&self.keyformat, &self.iv) {
+            (Some(_), _) => 5,
+            (None, Some(_)) => 2,
+            (None, None) => 1,
+        }
+    }
+
+    fn attributes(&self) -> String {
+        let mut attributes = format!("METHOD={},URI=\"{}\"", self.method.as_str(), self.uri);
+        if let Some(iv) = self.iv {
+            attributes += ",IV=0x";
+            for byte in iv {
+                _ = write!(attributes, "{:02X}", byte);
+            }
+        }
+        if let Some(keyformat) = &self.keyformat {
+            _ = write!(attributes, ",KEYFORMAT=\"{}\"", keyformat);
+        }
+        if let Some(versions) = &self.keyformat_versions {
+            _ = write!(attributes, ",KEYFORMATVERSIONS=\"{}\"", versions);
+        }
+        attributes
+    }
+}
+
+/// Skeleton FairPlay Streaming provider. Players hand the `skd://` URI to
+/// the application, which does the key exchange with the key server; the
+/// asset id is the app name.
+pub struct FairPlay {
+    key_server: String,
+}
+
+impl FairPlay {
+    /// `key_server` is the host (and optional path) of the `skd://` URIs.
+    pub fn new(key_server: impl Into<String>) -> Self {
+        Self {
+            key_server: key_server.into().trim_end_matches('/').to_owned(),
+        }
+    }
+}
+
+impl KeyProvider for FairPlay {
+    fn key(&self, app: &str) -> Option<Key> {
+        Some(Key {
+            method: Method::SampleAes,
+            uri: format!("skd://{}/{}", self.key_server, app),
+            // FairPlay derives the IV from the license
+            iv: None,
+            keyformat: Some("com.apple.streamingkeydelivery".to_owned()),
+            keyformat_versions: Some("1".to_owned()),
+        })
+    }
+}
@@ -0,0 +1,204 @@
+use crate::error::Error;
+use crate::packet::Packet;
+use crate::transport::{ChannelMessage, ManagerHandle, Message, Publisher, Watcher};
+use anyhow::{bail, Result};
+use chrono::Utc;
+use std::convert::TryInto;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::fs::{self, File};
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::{broadcast::error::RecvError, oneshot};
+use tokio::time::Instant;
+
+pub const DUMP_PATH: &str = "data/dump";
+/// Captures running at once, each holds a watcher and writes to disk.
+pub const MAX_DUMPS: usize = 4;
+
+static RUNNING: AtomicUsize = AtomicUsize::new(0);
+
+const REPLAY_CLIENT_ID: u64 = u64::MAX - 1;
+// the sinks register their triggers once they run, publish after them
+const REPLAY_DELAY: Duration = Duration::from_secs(1);
+// longer gaps between timestamps are jumps in the timeline, not pauses
+const MAX_GAP_MS: u64 = 5000;
+
+/// Starts capturing the packets of channel `app` for `duration` into a new
+/// file under `data/dump`, returning its name once the capture runs.
+///
+/// The dump starts with the channel's metadata, sequence headers and the
+/// current GOP, so it decodes on its own. Each packet is written as its
+/// bincode serialization behind a big endian u32 length. Fails with
+/// [`Error::TooManyDumps`] while `MAX_DUMPS` captures run.
+pub async fn start(manager: &ManagerHandle, app: &str, duration: Duration) -> Result<String> {
+    let slot = Slot::take().ok_or(Error::TooManyDumps(MAX_DUMPS))?;
+    let (request, response) = oneshot::channel();
+    manager
+        .send(ChannelMessage::Join((app.to_owned(), request)))
+        .map_err(|_| Error::ChannelJoinFailed)?;
+    let (handle, watcher) = response
+        .await
+        .map_err(|_| Error::NoSuchStream(app.to_owned()))?;
+    // the channel may close in between
+    let (request, response) = oneshot::channel();
+    handle
+        .send(Message::InitData(request))
+        .map_err(|_| Error::NoSuchStream(app.to_owned()))?;
+    let (meta, video, audio, gop) = response
+        .await
+        .map_err(|_| Error::NoSuchStream(app.to_owned()))?;
+    let init = vec![meta, video, audio]
+        .into_iter()
+        .flatten()
+        .chain(gop.into_iter().flatten())
+        .collect();
+
+    fs::create_dir_all(DUMP_PATH).await?;
+    let file_name = format!("{}_{}.dump", app, Utc::now().timestamp());
+    let file = File::create(Path::new(DUMP_PATH).join(&file_name)).await?;
+    log::info!("Dumping {} for {:?} to {}", app, duration, file_name);
+    let name = file_name.clone();
+    tokio::spawn(async move {
+        match capture(file, init, watcher, duration).await {
+            Ok(count) => log::info!("Dumped {} packets to {}", count, name),
+            Err(e) => log::error!("Dump {} failed: {}", name, e),
+        }
+        drop(slot);
+    });
+    Ok(file_name)
+}
+
+/// One of the `MAX_DUMPS` captures, given back when dropped.
+struct Slot;
+
+impl Slot {
+    fn take() -> Option<Slot> {
+        RUNNING
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                (n < MAX_DUMPS).then(|| n + 1)
+            })
+            .ok()
+            .map(|_| Slot)
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        RUNNING.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+async fn capture(
+    file: File,
+    init: Vec<Arc<Packet>>,
+    mut watcher: Watcher,
+    duration: Duration,
+) -> Result<usize> {
+    let mut file = BufWriter::new(file);
+    let mut count = 0;
+    for packet in &init {
+        write_packet(&mut file, packet).await?;
+        count += 1;
+    }
+    let deadline = tokio::time::sleep(duration);
+    tokio::pin!(deadline);
+    loop {
+        let packet = tokio::select! {
+            _ = &mut deadline => break,
+            packet = watcher.recv() => packet,
+        };
+        match packet {
+            Ok(packet) => {
+                write_packet(&mut file, &packet).await?;
+                count += 1;
+            }
+            Err(RecvError::Lagged(skipped)) => {
+                log::warn!("Dump fell behind, {} packets are missing", skipped);
+            }
+            Err(RecvError::Closed) => break,
+        }
+    }
+    file.flush().await?;
+    Ok(count)
+}
+
+async fn write_packet(file: &mut BufWriter<File>, packet: &Packet) -> Result<()> {
+    let packed = packet.pack()?;
+    file.write_u32(packed.len() as u32).await?;
+    file.write_all(&packed).await?;
+    Ok(())
+}
+
+/// Reads the packets of a dump written by [`start`].
+pub fn read<P: AsRef<Path>>(path: P) -> Result<Vec<Packet>> {
+    let data = std::fs::read(path)?;
+    let mut rest = &data[..];
+    let mut packets = Vec::new();
+    while !rest.is_empty() {
+        if rest.len() < 4 {
+            bail!("Dump ends inside a packet length");
+        }
+        let (len, tail) = rest.split_at(4);
+        let len = u32::from_be_bytes(len.try_into()?) as usize;
+        if tail.len() < len {
+            bail!("Dump ends inside packet {}", packets.len());
+        }
+        packets.push(Packet::unpack(&tail[..len])?);
+        rest = &tail[len..];
+    }
+    Ok(packets)
+}
+
+/// Publishes `packets` into channel `app` as if an encoder was connected,
+/// spacing them by their timestamps, and releases the channel at the end
+/// so the sinks finish their output. Returns the number of packets sent.
+pub async fn replay(
+    manager: ManagerHandle,
+    app: String,
+    key: String,
+    packets: Vec<Packet>,
+) -> Result<usize> {
+    tokio::time::sleep(REPLAY_DELAY).await;
+    let presence = Arc::new(());
+    let publisher = Publisher {
+        client_id: REPLAY_CLIENT_ID,
+        addr: ([127, 0, 0, 1], 0).into(),
+        resume: None,
+        presence: Arc::downgrade(&presence),
+    };
+    let (request, response) = oneshot::channel();
+    let create = ChannelMessage::Create((app.clone(), key, publisher, request));
+    if manager.send(create).is_err() {
+        bail!("Manager stopped");
+    }
+    let handle = match response.await {
+        Ok(handle) => handle,
+        Err(_) => bail!("Refused to publish {}", app),
+    };
+    log::info!("Replaying {} packets into {}", packets.len(), app);
+
+    let mut due = Instant::now();
+    let mut latest: Option<u64> = None;
+    let mut count = 0;
+    for packet in packets {
+        if let Some(timestamp) = packet.timestamp.map(u64::from) {
+            if let Some(latest) = latest {
+                let gap = timestamp.saturating_sub(latest);
+                if gap <= MAX_GAP_MS {
+                    due += Duration::from_millis(gap);
+                }
+            }
+            latest = Some(latest.map_or(timestamp, |l| l.max(timestamp)));
+            tokio::time::sleep_until(due).await;
+        }
+        if handle.send(Message::Packet(packet)).is_err() {
+            break;
+        }
+        count += 1;
+    }
+    _ = manager.send(ChannelMessage::Release((app.clone(), REPLAY_CLIENT_ID)));
+    log::info!("Replay into {} finished after {} packets", app, count);
+    Ok(count)
+}
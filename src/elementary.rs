@@ -0,0 +1,62 @@
+use crate::codec::avc::{self, AvcCoder};
+use crate::codec::flv::{Codec, VideoData};
+use crate::codec::hevc::{self, HevcCoder};
+use crate::codec::{FormatReader, FormatWriter};
+use crate::packet::{Packet, PacketType};
+use anyhow::Result;
+use std::convert::TryFrom;
+
+/// Converts the video of a channel from AVCC/HVCC to AnnexB once, so the
+/// sinks that need elementary streams (TS segments, thumbnails, snapshots)
+/// share the result instead of each keeping their own decoder state.
+pub(crate) struct Converter {
+    avc_coder: AvcCoder,
+    hevc_coder: HevcCoder,
+}
+
+impl Converter {
+    pub fn new() -> Self {
+        Self {
+            avc_coder: AvcCoder::new(),
+            hevc_coder: HevcCoder::new(),
+        }
+    }
+
+    /// Sets `packet.annexb` for video frames. Sequence headers reconfigure
+    /// the converter and are left as they are.
+    pub fn convert(&mut self, packet: &mut Packet) -> Result<()> {
+        // mirrored packets arrive converted by the channel they come from
+        if !matches!(packet.kind, PacketType::Video) || packet.annexb.is_some() {
+            return Ok(());
+        }
+        let flv_packet = VideoData::try_from(packet.as_ref())?;
+        let payload = &flv_packet.body;
+
+        if flv_packet.is_sequence_header() {
+            match flv_packet.codec {
+                Codec::H264 => {
+                    self.avc_coder = AvcCoder::new();
+                    self.avc_coder.set_dcr(payload.as_ref())?;
+                }
+                Codec::H265 => {
+                    self.hevc_coder = HevcCoder::new();
+                    self.hevc_coder.set_dcr(payload.as_ref())?;
+                }
+            }
+            return Ok(());
+        }
+
+        let video = match flv_packet.codec {
+            Codec::H264 => match self.avc_coder.read_format(avc::Avcc, payload)? {
+                Some(avc) => self.avc_coder.write_format(avc::AnnexB, avc)?,
+                None => return Ok(()),
+            },
+            Codec::H265 => match self.hevc_coder.read_format(hevc::Hvcc, payload)? {
+                Some(hevc) => self.hevc_coder.write_format(hevc::AnnexB, hevc)?,
+                None => return Ok(()),
+            },
+        };
+        packet.annexb = Some(video.into());
+        Ok(())
+    }
+}
@@ -31,6 +31,9 @@ pub enum Error {
     #[error("Connection timeout")]
     ConnectionTimeout(#[from] Elapsed),
 
+    #[error("No audio or video received within {0}s of publishing")]
+    NoMediaReceived(u64),
+
     #[error("RTMP handshake failed")]
     HandshakeFailed,
 
@@ -52,9 +55,19 @@ pub enum Error {
     #[error("Application name cannot be empty")]
     EmptyAppName,
 
+    #[error("Invalid application name: {0}")]
+    InvalidAppName(String),
+
+    #[error("Invalid stream key: {0}")]
+    InvalidStreamKey(String),
+
     #[error("Http-flv app name error")]
+    #[allow(dead_code)]
     HttpFlvAppNameErr,
 
     #[error("send ts message to redis failed")]
     SendTsToMqErr,
+
+    #[error("At most {0} dumps may run at once")]
+    TooManyDumps(usize),
 }
@@ -0,0 +1,216 @@
+use crate::codec::flv::index::is_keyframe_tag;
+use crate::codec::flv::{AudioData, VideoData};
+use crate::packet::{Packet, PacketType};
+use crate::transport::{Handle, IncomingBroadcast, Message, Publisher, Role};
+use chrono::Utc;
+use std::convert::TryFrom;
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+struct Source {
+    publisher: Publisher,
+    active: Arc<AtomicBool>,
+    // unix milliseconds of the last packet, set by the relay
+    last_packet: Arc<AtomicI64>,
+}
+
+impl Source {
+    fn stalled(&self, now: i64, after: Option<i64>) -> bool {
+        after.map_or(false, |after| {
+            now - self.last_packet.load(Ordering::Relaxed) > after
+        })
+    }
+}
+
+impl Drop for Source {
+    fn drop(&mut self) {
+        self.active.store(false, Ordering::Relaxed);
+    }
+}
+
+/// The publishers of a primary/backup pair. Both publish at the same time,
+/// only the active one reaches the channel: the primary while it is there
+/// and sending, the backup otherwise.
+pub(crate) struct Pair {
+    primary: Option<Source>,
+    backup: Option<Source>,
+    active: Option<Role>,
+    // milliseconds without packets after which a publisher is passed over
+    stall_after: Option<i64>,
+}
+
+impl Pair {
+    /// A pair that also switches away from a connected publisher once it
+    /// sent nothing for `stall_after`.
+    pub fn new(stall_after: Option<Duration>) -> Self {
+        Self {
+            primary: None,
+            backup: None,
+            active: None,
+            stall_after: stall_after.map(|after| after.as_millis() as i64),
+        }
+    }
+
+    /// Adds a publisher, replacing an earlier one in the same role, and
+    /// returns the handle it publishes into.
+    pub fn join(&mut self, role: Role, publisher: Publisher, channel: Handle) -> Handle {
+        let (handle, incoming) = mpsc::unbounded_channel();
+        let active = Arc::new(AtomicBool::new(false));
+        // a new publisher gets as long as a stalled one to send something
+        let last_packet = Arc::new(AtomicI64::new(Utc::now().timestamp_millis()));
+        tokio::spawn(relay(
+            incoming,
+            channel,
+            active.clone(),
+            last_packet.clone(),
+        ));
+        *self.slot(role) = Some(Source {
+            publisher,
+            active,
+            last_packet,
+        });
+        handle
+    }
+
+    /// Removes the publisher with `client_id`, false if it was already
+    /// replaced.
+    pub fn leave(&mut self, role: Role, client_id: u64) -> bool {
+        let slot = self.slot(role);
+        if slot.as_ref().map(|s| s.publisher.client_id) != Some(client_id) {
+            return false;
+        }
+        *slot = None;
+        true
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.primary.is_none() && self.backup.is_none()
+    }
+
+    pub fn active(&self) -> Option<(Role, &Publisher)> {
+        let role = self.active?;
+        let source = match role {
+            Role::Primary => self.primary.as_ref(),
+            Role::Backup => self.backup.as_ref(),
+        };
+        source.map(|s| (role, &s.publisher))
+    }
+
+    pub fn role(&self) -> Option<Role> {
+        self.active
+    }
+
+    /// Activates the primary if it publishes, the backup if the primary is
+    /// away or stalled and the backup is not. Returns the role switched to,
+    /// if it changed.
+    pub fn switch(&mut self) -> Option<Role> {
+        let now = Utc::now().timestamp_millis();
+        let stalled = |source: &Option<Source>| {
+            source
+                .as_ref()
+                .map(|source| source.stalled(now, self.stall_after))
+        };
+        let wanted = match (stalled(&self.primary), stalled(&self.backup)) {
+            (Some(true), Some(false)) => Some(Role::Backup),
+            (Some(_), _) => Some(Role::Primary),
+            (None, Some(_)) => Some(Role::Backup),
+            (None, None) => None,
+        };
+        for (role, source) in [(Role::Primary, &self.primary), (Role::Backup, &self.backup)] {
+            if let Some(source) = source {
+                source.active.store(wanted == Some(role), Ordering::Relaxed);
+            }
+        }
+        if wanted == self.active {
+            return None;
+        }
+        self.active = wanted;
+        wanted
+    }
+
+    fn slot(&mut self, role: Role) -> &mut Option<Source> {
+        match role {
+            Role::Primary => &mut self.primary,
+            Role::Backup => &mut self.backup,
+        }
+    }
+}
+
+/// Sequence headers and metadata of a publisher, sent ahead of its first
+/// keyframe whenever it becomes active.
+#[derive(Default)]
+struct Headers {
+    metadata: Option<Packet>,
+    video: Option<Packet>,
+    audio: Option<Packet>,
+}
+
+impl Headers {
+    fn remember(&mut self, packet: &Packet) {
+        let slot = match packet.kind {
+            PacketType::Meta => &mut self.metadata,
+            PacketType::Video => match VideoData::try_from(packet.as_ref()) {
+                Ok(video) if video.is_sequence_header() => &mut self.video,
+                _ => return,
+            },
+            PacketType::Audio => match AudioData::try_from(packet.as_ref()) {
+                Ok(audio) if audio.is_sequence_header() => &mut self.audio,
+                _ => return,
+            },
+        };
+        *slot = Some(packet.clone());
+    }
+}
+
+/// Feeds one publisher of a pair into the channel while it is active. Taking
+/// over waits for a keyframe, then splices the publisher onto the channel's
+/// timeline behind its own sequence headers, so players only see a
+/// discontinuity where the parameters differ.
+async fn relay(
+    mut incoming: IncomingBroadcast,
+    channel: Handle,
+    active: Arc<AtomicBool>,
+    last_packet: Arc<AtomicI64>,
+) {
+    let mut headers = Headers::default();
+    let mut forwarding = false;
+    while let Some(message) = incoming.recv().await {
+        let packet = match message {
+            Message::Packet(packet) => packet,
+            message => {
+                _ = channel.send(message);
+                continue;
+            }
+        };
+        last_packet.store(Utc::now().timestamp_millis(), Ordering::Relaxed);
+        headers.remember(&packet);
+        if !active.load(Ordering::Relaxed) {
+            forwarding = false;
+            continue;
+        }
+        if !forwarding {
+            let starts = match packet.kind {
+                PacketType::Video => is_keyframe_tag(&packet.payload),
+                // without video any packet will do
+                _ => headers.video.is_none(),
+            };
+            let timestamp = match packet.timestamp {
+                Some(timestamp) if starts => timestamp,
+                _ => continue,
+            };
+            _ = channel.send(Message::Resume(timestamp.into()));
+            let cached = [&headers.metadata, &headers.video, &headers.audio];
+            for header in cached.iter().copied().flatten() {
+                let mut header = header.clone();
+                header.timestamp = Some(timestamp);
+                _ = channel.send(Message::Packet(header));
+            }
+            forwarding = true;
+        }
+        if channel.send(Message::Packet(packet)).is_err() {
+            break;
+        }
+    }
+}
@@ -1,22 +1,36 @@
 use std::path::PathBuf;
 
+use crate::clock;
 use crate::codec::flv::writer::Writer;
-use crate::transport::{trigger_channel, ChannelMessage, ManagerHandle, Watcher};
-use chrono::prelude::*;
+use crate::metrics::{self, DropReason};
+use crate::shutdown::{Shutdown, SinkGuard};
+use crate::transport::{trigger_channel, ChannelMessage, ManagerHandle, Sink, Watcher};
 use anyhow::Result;
 
 struct FlvWriter {
     writer: Writer,
     watcher: Watcher,
+    _sink: SinkGuard,
 }
 
 impl FlvWriter {
-    fn new(writer: Writer, watcher: Watcher) -> Self {
-        Self { writer, watcher }
+    fn new(writer: Writer, watcher: Watcher, sink: SinkGuard) -> Self {
+        Self {
+            writer,
+            watcher,
+            _sink: sink,
+        }
     }
-    async fn run(&mut self) -> std::io::Result<()> {
-        while let Ok(packet) = self.watcher.recv().await {
-            self.writer.write(&packet).await?
+    async fn run(&mut self, app_name: &str) -> std::io::Result<()> {
+        use tokio::sync::broadcast::error::RecvError;
+        loop {
+            match self.watcher.recv().await {
+                Ok(packet) => self.writer.write(&packet).await?,
+                Err(RecvError::Lagged(skipped)) => {
+                    metrics::dropped(app_name, DropReason::Lag, skipped);
+                }
+                Err(RecvError::Closed) => break,
+            }
         }
         Ok(())
     }
@@ -25,13 +39,15 @@ impl FlvWriter {
 pub struct Service {
     manager_handle: ManagerHandle,
     flv_data_path: String,
+    shutdown: Shutdown,
 }
 
 impl Service {
-    pub fn new(manager_handle: ManagerHandle, flv_data_path: String) -> Self {
+    pub fn new(manager_handle: ManagerHandle, flv_data_path: String, shutdown: Shutdown) -> Self {
         Self {
             manager_handle,
             flv_data_path,
+            shutdown,
         }
     }
 
@@ -41,30 +57,56 @@ impl Service {
         super::prepare_stream_directory(&stream_path)?;
 
         let (trigger, mut trigger_handle) = trigger_channel();
-        if let Err(_) = self
+        if self
             .manager_handle
-            .send(ChannelMessage::RegisterTrigger("create_session", trigger))
+            .send(ChannelMessage::RegisterTrigger(Sink::Flv.event(), trigger))
+            .is_err()
         {
             log::error!("Failed to register session trigger");
             return Ok(());
         }
 
         while let Some((app_name, watcher)) = trigger_handle.recv().await {
-            let local: DateTime<Local> = Local::now();
+            if !clock::recordings_allowed() {
+                log::warn!("Not recording {}, the system clock is off NTP time", app_name);
+                _ = self.manager_handle.send(ChannelMessage::SinkFailing(
+                    app_name,
+                    Sink::Flv,
+                    true,
+                ));
+                continue;
+            }
             let flv_path = format!(
                 "{}/{}_{}.flv",
                 self.flv_data_path,
                 app_name,
-                local.timestamp()
+                clock::now().timestamp()
             );
             match Writer::new(flv_path).await {
                 Ok(writer) => {
-                    let mut flv_writer = FlvWriter::new(writer, watcher);
-                    tokio::spawn(async move { flv_writer.run().await.unwrap() });
+                    let mut flv_writer = FlvWriter::new(writer, watcher, self.shutdown.sink());
+                    let manager_handle = self.manager_handle.clone();
+                    tokio::spawn(async move {
+                        if let Err(why) = flv_writer.run(&app_name).await {
+                            log::error!("Failed to record {}: {}", app_name, why);
+                            _ = manager_handle.send(ChannelMessage::SinkFailing(
+                                app_name,
+                                Sink::Flv,
+                                true,
+                            ));
+                        }
+                    });
+                }
+                Err(why) => {
+                    log::error!("Failed to create writer: {:?}", why);
+                    _ = self.manager_handle.send(ChannelMessage::SinkFailing(
+                        app_name,
+                        Sink::Flv,
+                        true,
+                    ));
                 }
-                Err(why) => log::error!("Failed to create writer: {:?}", why),
             }
         }
-        return Ok(())
+        Ok(())
     }
 }
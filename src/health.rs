@@ -0,0 +1,92 @@
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+/// Listeners that have to be bound before the instance is ready.
+#[derive(Clone, Copy)]
+pub enum Listener {
+    Rtmp = 1,
+    Hls = 2,
+    HttpFlv = 4,
+}
+
+const LISTENERS: [(Listener, &str); 3] = [
+    (Listener::Rtmp, "rtmp"),
+    (Listener::Hls, "hls"),
+    (Listener::HttpFlv, "http-flv"),
+];
+
+static EXPECTED: AtomicU8 = AtomicU8::new(0);
+static BOUND: AtomicU8 = AtomicU8::new(0);
+static MANAGER_RUNNING: AtomicBool = AtomicBool::new(false);
+static MANAGER_EXPECTED: AtomicBool = AtomicBool::new(true);
+
+/// Registers a listener the instance is not ready without.
+pub fn expect(listener: Listener) {
+    EXPECTED.fetch_or(listener as u8, Ordering::SeqCst);
+}
+
+/// For instances that run no stream manager, such as HLS replicas.
+pub fn without_manager() {
+    MANAGER_EXPECTED.store(false, Ordering::SeqCst);
+}
+
+pub fn bound(listener: Listener) {
+    BOUND.fetch_or(listener as u8, Ordering::SeqCst);
+}
+
+pub fn unbound(listener: Listener) {
+    BOUND.fetch_and(!(listener as u8), Ordering::SeqCst);
+}
+
+/// Marks the manager task as running until the guard is dropped, which
+/// also happens when the task panics.
+pub(crate) fn manager_running() -> ManagerGuard {
+    MANAGER_RUNNING.store(true, Ordering::SeqCst);
+    ManagerGuard
+}
+
+pub(crate) struct ManagerGuard;
+
+impl Drop for ManagerGuard {
+    fn drop(&mut self) {
+        MANAGER_RUNNING.store(false, Ordering::SeqCst);
+    }
+}
+
+/// What keeps the instance from being ready, empty once it is.
+pub fn not_ready() -> Vec<&'static str> {
+    let missing = EXPECTED.load(Ordering::SeqCst) & !BOUND.load(Ordering::SeqCst);
+    let mut reasons: Vec<_> = LISTENERS
+        .iter()
+        .filter(|(listener, _)| missing & *listener as u8 != 0)
+        .map(|(_, name)| *name)
+        .collect();
+    if MANAGER_EXPECTED.load(Ordering::SeqCst) && !MANAGER_RUNNING.load(Ordering::SeqCst) {
+        reasons.push("manager");
+    }
+    reasons
+}
+
+#[cfg(any(feature = "http-flv", feature = "hls"))]
+pub(crate) async fn readyz<S>(
+    _state: std::sync::Arc<S>,
+    _req: hyper::Request<hyper::Body>,
+    _params: crate::router::Params,
+) -> hyper::Response<hyper::Body> {
+    let reasons = not_ready();
+    if reasons.is_empty() {
+        // a wrong clock misnames segments but players are still served
+        return match crate::clock::drifting() {
+            true => hyper::Response::new(
+                format!(
+                    "ready, clock {} ms off NTP time\n",
+                    crate::clock::drift_ms().unwrap_or(0)
+                )
+                .into(),
+            ),
+            false => hyper::Response::new("ready\n".into()),
+        };
+    }
+    crate::problem::Problem::UNAVAILABLE
+        .detail(format!("not ready: {}", reasons.join(", ")))
+        .into_response()
+}
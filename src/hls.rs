@@ -1,139 +1,890 @@
-use crate::transport::{TsMessageQueue, TsMessageReceiver};
+use crate::config::{self, PlaylistType};
+use crate::drm::{Key, KeyProvider};
+use crate::health::{self, Listener};
+use crate::metrics;
+use crate::transport::{Segment, TsMessageQueue, TsMessageReceiver};
+
+use crate::problem::Problem;
+use crate::router::{self, Params, Router};
+use crate::shutdown::Shutdown;
 
 use {
-    hyper::{
-        service::{make_service_fn, service_fn},
-        Body, Request, Response, Server, StatusCode,
-    },
+    hyper::{Body, HeaderMap, Request, Response},
     tokio::fs::File,
     tokio_util::codec::{BytesCodec, FramedRead},
 };
 
+use bytes::Bytes;
+use chrono::{SecondsFormat, TimeZone, Utc};
 use lazy_static::*;
-use std::{
-    collections::{HashMap, VecDeque},
-    vec,
-};
-use std::{fs, path::PathBuf, sync::Arc};
-use tokio::sync::RwLock;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use std::{fs, sync::Arc, sync::Mutex};
+use tokio::sync::{watch, RwLock};
 
 type GenericError = Box<dyn std::error::Error + Send + Sync>;
 type Result<T> = std::result::Result<T, GenericError>;
 
-static NOTFOUND: &[u8] = b"Not Found";
+/// Sidecar in each stream directory holding the number of segments ever
+/// added, so media sequence numbers survive a restart.
+pub(crate) const SEQUENCE_FILE: &str = "sequence";
+
+/// Sidecar in each stream directory holding its playlist state, read by
+/// replicas serving the directory from shared storage.
+pub(crate) const STATE_FILE: &str = "playlist.json";
+
+/// How often replicas pick up the playlist state written by the ingest.
+const REPLICA_SYNC: Duration = Duration::from_millis(500);
+
+/// BANDWIDTH announced in master playlists before a segment was written.
+const DEFAULT_BANDWIDTH: u64 = 2_000_000;
+
+/// What a replica needs to list the segments of a stream like the ingest
+/// instance that wrote them.
+#[derive(Serialize, Deserialize)]
+struct State {
+    added: u32,
+    discontinuities: u32,
+    finished: bool,
+    segments: Vec<Segment>,
+}
 
 lazy_static! {
-    static ref DATA: Arc<RwLock<HashMap<String, (VecDeque<(i64, u8)>, u32)>>> =
-        Arc::new(RwLock::new(HashMap::new()));
+    static ref DATA: Arc<RwLock<HashMap<String, Segments>>> = Arc::new(RwLock::new(HashMap::new()));
+    // segment requests per stream and minute the segment started in
+    static ref WATCHED: Mutex<HashMap<String, BTreeMap<i64, u64>>> = Mutex::new(HashMap::new());
+}
+
+/// Segments of a stream still on disk, oldest first. Live playlists list
+/// only the newest `playlist_length` of them, event playlists all.
+struct Segments {
+    retained: VecDeque<Segment>,
+    added: u32,
+    discontinuities: u32,
+    playlist_type: PlaylistType,
+    // segments kept on disk, never fewer than the playlist lists
+    retention: usize,
+    // streams of a sync group are always dated and listed in its manifest
+    sync_group: Option<String>,
+    // added to successful playlist and segment responses
+    headers: HeaderMap,
+    // listed after this origin in the master playlist
+    backup_origins: Vec<String>,
+    // the writer stopped, the playlist gets an #EXT-X-ENDLIST
+    finished: bool,
+    // the rendered playlist and the #EXT-X-KEY line it has, shared by all
+    // requests until the next segment or the key rotating
+    rendered: Option<(Option<String>, Bytes)>,
+    // signalled on every new segment, for blocking playlist reloads
+    updated: watch::Sender<()>,
+    // asked for an #EXT-X-KEY on every render
+    keys: Option<Arc<dyn KeyProvider>>,
 }
 
-async fn handle_connection(req: Request<Body>) -> Result<Response<Body>> {
-    let path = req.uri().path();
+impl Segments {
+    /// Segments of an app, with its overrides of the global settings.
+    fn new(config: &config::Hls, app: &config::App, keys: Option<Arc<dyn KeyProvider>>) -> Self {
+        Self {
+            retained: VecDeque::new(),
+            added: 0,
+            discontinuities: 0,
+            playlist_type: app.playlist_type.unwrap_or(config.playlist_type),
+            retention: app
+                .retention
+                .unwrap_or(config.retention)
+                .max(config.playlist_length),
+            sync_group: app.sync_group.clone(),
+            // checked with the rest of the settings
+            headers: app
+                .headers
+                .iter()
+                .filter_map(|(name, value)| Some((name.parse().ok()?, value.parse().ok()?)))
+                .collect(),
+            backup_origins: app
+                .backup_origins
+                .iter()
+                .map(|origin| origin.trim_end_matches('/').to_owned())
+                .collect(),
+            finished: false,
+            rendered: None,
+            updated: watch::channel(()).0,
+            keys,
+        }
+    }
 
-    let mut file_path: String = String::from("");
+    /// Returns the segments that fell out of retention and can be deleted,
+    /// never any of an event playlist.
+    fn push(&mut self, mut segment: Segment) -> Vec<Segment> {
+        // nothing to be discontinuous with
+        segment.discontinuity &= !self.retained.is_empty();
+        if segment.discontinuity {
+            self.discontinuities += 1;
+        }
+        self.retained.push_back(segment);
+        self.added += 1;
+        self.finished = false;
+        self.rendered = None;
+        _ = self.updated.send(());
+        if self.playlist_type == PlaylistType::Event {
+            return vec![];
+        }
+        let excess = self.retained.len().saturating_sub(self.retention);
+        self.retained.drain(..excess).collect()
+    }
 
-    if path.ends_with(".m3u8") {
-        //http://127.0.0.1:3000/api/app_name.m3u8
-        let temp = &path[0..(path.len() - 5)];
-        let parts: Vec<_> = temp.split("/").collect();
-        let app_name = String::from(parts[1]);
-        let mut temp_data = vec![];
-        let mut seq = 0;
-        let lock = DATA.read().await;
-        match lock.get(&app_name) {
-            Some(d) => {
-                for i in &d.0 {
-                    temp_data.push((i.0, i.1));
-                }
-                seq = d.1;
+    fn finish(&mut self) {
+        self.finished = true;
+        self.rendered = None;
+        _ = self.updated.send(());
+    }
+
+    /// Writes the state sidecar. A replica reading it half written fails
+    /// to parse it and keeps the previous state until the next sync.
+    fn save(&self, stream_dir: &Path) {
+        let state = State {
+            added: self.added,
+            discontinuities: self.discontinuities,
+            finished: self.finished,
+            segments: self.retained.iter().copied().collect(),
+        };
+        _ = fs::write(stream_dir.join(SEQUENCE_FILE), self.added.to_string());
+        if let Ok(state) = serde_json::to_vec(&state) {
+            _ = fs::write(stream_dir.join(STATE_FILE), state);
+        }
+    }
+
+    /// Takes over the state written by the ingest, true if it changed.
+    fn load(&mut self, state: State) -> bool {
+        if state.added == self.added && state.finished == self.finished {
+            return false;
+        }
+        self.retained = state.segments.into();
+        self.added = state.added;
+        self.discontinuities = state.discontinuities;
+        self.finished = state.finished;
+        self.rendered = None;
+        _ = self.updated.send(());
+        true
+    }
+
+    fn rotated_out(&self, file_name: i64) -> bool {
+        match self.retained.front() {
+            Some(oldest) => file_name < oldest.name,
+            None => false,
+        }
+    }
+
+    /// The listed segments with their media and discontinuity sequence numbers.
+    fn playlist(&self, playlist_length: usize) -> (Vec<Segment>, u32, u32) {
+        let skip = match self.playlist_type {
+            PlaylistType::Live => self.retained.len().saturating_sub(playlist_length),
+            PlaylistType::Event => 0,
+        };
+        let listed: Vec<_> = self.retained.iter().skip(skip).copied().collect();
+        let seq = self.added - listed.len() as u32;
+        let listed_discontinuities = listed.iter().filter(|s| s.discontinuity).count() as u32;
+        let discontinuity_seq = self.discontinuities - listed_discontinuities;
+        (listed, seq, discontinuity_seq)
+    }
+
+    /// The cached playlist, if it was rendered with the current key.
+    fn cached(&self, app_name: &str) -> Option<Bytes> {
+        let tag = self.key(app_name).as_ref().map(Key::tag);
+        match &self.rendered {
+            Some((rendered_tag, m3u8)) if *rendered_tag == tag => Some(m3u8.clone()),
+            _ => None,
+        }
+    }
+
+    fn m3u8(&mut self, app_name: &str, config: &config::Hls) -> Bytes {
+        if let Some(m3u8) = self.cached(app_name) {
+            return m3u8;
+        }
+        let (listed, seq, discontinuity_seq) = self.playlist(config.playlist_length);
+        let key = self.key(app_name);
+        let tag = key.as_ref().map(Key::tag);
+        let m3u8 = Bytes::from(render_m3u8(
+            app_name,
+            listed,
+            seq,
+            discontinuity_seq,
+            self.playlist_type,
+            self.finished,
+            config.program_date_time || self.sync_group.is_some(),
+            key,
+        ));
+        self.rendered = Some((tag, m3u8.clone()));
+        m3u8
+    }
+
+    fn key(&self, app_name: &str) -> Option<Key> {
+        self.keys.as_ref().and_then(|keys| keys.key(app_name))
+    }
+}
+
+async fn playlist(config: Arc<config::Hls>, req: Request<Body>, params: Params) -> Response<Body> {
+    //http://127.0.0.1:3000/app_name.m3u8
+    let app_name = &params["app"];
+    let query = router::query(&req);
+    let msn = match (query.get("_HLS_msn"), query.contains_key("_HLS_part")) {
+        (Some(msn), _) => match msn.parse() {
+            Ok(msn) => Some(msn),
+            Err(_) => {
+                return Problem::BAD_REQUEST
+                    .detail("invalid _HLS_msn")
+                    .into_response()
+            }
+        },
+        (None, true) => {
+            return Problem::BAD_REQUEST
+                .detail("_HLS_part needs _HLS_msn")
+                .into_response()
+        }
+        (None, false) => None,
+    };
+    if let Some(msn) = msn {
+        // segments are whole, so a requested part waits for its segment
+        let timeout = Duration::from_secs(3 * config.ts_duration);
+        match tokio::time::timeout(timeout, await_segment(app_name, msn)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(problem)) => return problem.into_response(),
+            Err(_) => {
+                return Problem::UNAVAILABLE
+                    .detail(format!("segment {} not ready in time", msn))
+                    .into_response()
+            }
+        }
+    }
+    let lock = DATA.read().await;
+    let cached = lock
+        .get(app_name)
+        .map(|d| (d.cached(app_name), d.headers.clone()));
+    drop(lock);
+    let (m3u8, headers) = match cached {
+        Some((Some(m3u8), headers)) => (Some(m3u8), headers),
+        // rendered once per segment or key, concurrent misses wait for the first
+        Some((None, headers)) => {
+            let m3u8 = DATA
+                .write()
+                .await
+                .get_mut(app_name)
+                .map(|d| d.m3u8(app_name, &config));
+            (m3u8, headers)
+        }
+        None => (None, HeaderMap::new()),
+    };
+    match m3u8 {
+        Some(m3u8) => with_headers(Response::new(Body::from(m3u8)), headers),
+        None => not_found(Problem::STREAM_NOT_FOUND),
+    }
+}
+
+#[derive(Serialize)]
+struct GroupManifest<'a> {
+    group: &'a str,
+    /// The earliest instant every live member still lists.
+    program_date_time: String,
+    streams: Vec<GroupMember<'a>>,
+}
+
+#[derive(Serialize)]
+struct GroupMember<'a> {
+    app: &'a str,
+    playlist: String,
+    /// Segment holding `program_date_time` and how far into it, in seconds.
+    media_sequence: u32,
+    offset: f64,
+    finished: bool,
+}
+
+/// Lists the streams of a sync group with the point in each playlist where
+/// they line up, so players can switch between angles without drifting.
+async fn group(config: Arc<config::Hls>, _req: Request<Body>, params: Params) -> Response<Body> {
+    //http://127.0.0.1:3000/groups/group_name.json
+    let group = &params["group"];
+    let lock = DATA.read().await;
+    let mut members: Vec<_> = lock
+        .iter()
+        .filter(|(_, segments)| segments.sync_group.as_deref() == Some(group.as_str()))
+        .map(|(app_name, segments)| {
+            (
+                app_name,
+                segments,
+                segments.playlist(config.playlist_length),
+            )
+        })
+        .filter(|(_, _, (listed, _, _))| !listed.is_empty())
+        .collect();
+    members.sort_unstable_by_key(|(app_name, _, _)| *app_name);
+    let aligned = match members
+        .iter()
+        .map(|(_, _, (listed, _, _))| listed[0].started_at)
+        .max()
+    {
+        Some(aligned) => aligned,
+        None => {
+            return not_found(
+                Problem::STREAM_NOT_FOUND.detail("no stream of the sync group is live"),
+            )
+        }
+    };
+    let streams = members
+        .iter()
+        .map(|(app_name, segments, (listed, seq, _))| {
+            let i = listed
+                .iter()
+                .rposition(|s| s.started_at <= aligned)
+                .unwrap_or(0);
+            GroupMember {
+                app: app_name.as_str(),
+                playlist: format!("/{}.m3u8", app_name),
+                media_sequence: seq + i as u32,
+                offset: (aligned - listed[i].started_at) as f64 / 1000.0,
+                finished: segments.finished,
             }
-            None => {}
+        })
+        .collect();
+    let manifest = GroupManifest {
+        group,
+        program_date_time: Utc
+            .timestamp_millis(aligned)
+            .to_rfc3339_opts(SecondsFormat::Millis, true),
+        streams,
+    };
+    Response::builder()
+        .header("Content-Type", "application/json")
+        .body(serde_json::to_vec(&manifest).unwrap().into())
+        .unwrap()
+}
+
+/// Master playlist listing the stream once per origin, this one first, so
+/// players fail over to a backup origin when a request here fails.
+async fn master(config: Arc<config::Hls>, _req: Request<Body>, params: Params) -> Response<Body> {
+    //http://127.0.0.1:3000/master/app_name.m3u8
+    let app_name = &params["app"];
+    let lock = DATA.read().await;
+    let (listed, origins, headers, key) = match lock.get(app_name) {
+        Some(d) => (
+            d.playlist(config.playlist_length).0,
+            d.backup_origins.clone(),
+            d.headers.clone(),
+            d.key(app_name),
+        ),
+        None => return not_found(Problem::STREAM_NOT_FOUND),
+    };
+    drop(lock);
+    let bandwidth = peak_bandwidth(&Path::new(&config.data_path).join(app_name), &listed).await;
+    let m3u8 = render_master(app_name, bandwidth, &origins, key);
+    with_headers(Response::new(Body::from(m3u8)), headers)
+}
+
+/// Bits per second of the largest listed segment, which is what
+/// #EXT-X-STREAM-INF's BANDWIDTH stands for.
+async fn peak_bandwidth(stream_dir: &Path, listed: &[Segment]) -> u64 {
+    let mut peak = None;
+    for segment in listed {
+        let path = stream_dir.join(format!("{}.ts", segment.name));
+        if let Ok(metadata) = tokio::fs::metadata(path).await {
+            let bits = metadata.len() * 8 / u64::from(segment.duration.max(1));
+            peak = peak.max(Some(bits));
         }
+    }
+    peak.unwrap_or(DEFAULT_BANDWIDTH)
+}
+
+/// Parks a blocking playlist reload until the segment with media sequence
+/// number `msn` is listed. Requests more than two segments ahead of the
+/// playlist are refused, as RFC 8216bis requires. Returns early for unknown,
+/// removed or ended streams, which the playlist lookup answers.
+async fn await_segment(app_name: &str, msn: u32) -> std::result::Result<(), Problem> {
+    loop {
+        let lock = DATA.read().await;
+        let segments = match lock.get(app_name) {
+            Some(segments) => segments,
+            None => return Ok(()),
+        };
+        if msn < segments.added || segments.finished {
+            return Ok(());
+        }
+        if msn > segments.added + 1 {
+            return Err(Problem::BAD_REQUEST.detail("_HLS_msn is too far ahead of the playlist"));
+        }
+        // subscribed under the lock, so no segment added after the check is missed
+        let mut updated = segments.updated.subscribe();
         drop(lock);
-        let m3u8 = render_m3u8(app_name, temp_data, seq);
-        let body = Body::from(m3u8);
-        return Ok(Response::new(body));
-    } else if path.ends_with(".ts") {
-        //http://127.0.0.1:3000/data/app_name/ts_name.m3u8
-        let temp = &path[0..(path.len() - 3)];
-        let part: Vec<_> = temp.split("/").collect();
-        let app_name = String::from(part[2]);
-        let ts_name = String::from(part[3]);
-        file_path = format!("./data/{}/{}.ts", app_name, ts_name);
-    }
-
-    if let Ok(file) = File::open(file_path.as_str()).await {
-        let stream = FramedRead::new(file, BytesCodec::new());
-        let body = Body::wrap_stream(stream);
-        return Ok(Response::new(body));
-    }
-    Ok(Response::builder()
-        .status(StatusCode::NOT_FOUND)
-        .body(NOTFOUND.into())
-        .unwrap())
-}
-
-pub async fn run(mut recv: TsMessageReceiver, port: u32) -> Result<()> {
-    let listen_address = format!("[::]:{}", port);
+        if updated.changed().await.is_err() {
+            return Ok(());
+        }
+    }
+}
+
+async fn segment(config: Arc<config::Hls>, _req: Request<Body>, params: Params) -> Response<Body> {
+    //http://127.0.0.1:3000/data/app_name/ts_name.ts
+    let app_name = &params["app"];
+    let ts_name = &params["segment"];
+
+    let lock = DATA.read().await;
+    let (rotated_out, headers) = match lock.get(app_name) {
+        Some(d) => (
+            ts_name.parse().map_or(false, |name| d.rotated_out(name)),
+            d.headers.clone(),
+        ),
+        None => (false, HeaderMap::new()),
+    };
+    drop(lock);
+    if rotated_out {
+        metrics::SEGMENTS_GONE.inc();
+        let mut res = Problem::SEGMENT_GONE.into_response();
+        res.headers_mut()
+            .insert("Cache-Control", "public, max-age=3600".parse().unwrap());
+        return res;
+    }
+    let res = serve_file(format!("{}/{}/{}.ts", config.data_path, app_name, ts_name)).await;
+    if res.status().is_success() {
+        if let Ok(name) = ts_name.parse::<i64>() {
+            watched(app_name, name);
+        }
+    }
+    with_headers(res, headers)
+}
+
+/// Counts a request for the segment named `name`, the unix time it started.
+fn watched(app_name: &str, name: i64) {
+    let mut watched = WATCHED.lock().unwrap();
+    let minutes = match watched.get_mut(app_name) {
+        Some(minutes) => minutes,
+        None => watched.entry(app_name.to_owned()).or_default(),
+    };
+    *minutes.entry(name - name.rem_euclid(60)).or_default() += 1;
+}
+
+#[derive(Serialize)]
+pub struct Heatmap {
+    pub app: String,
+    /// The broadcast ended, the counts are final unless it is played back.
+    pub finished: bool,
+    pub minutes: Vec<WatchedMinute>,
+}
+
+#[derive(Serialize)]
+pub struct WatchedMinute {
+    pub start: String,
+    /// Segment requests for this minute of the broadcast.
+    pub requests: u64,
+}
+
+/// Segment requests of a stream per minute of the broadcast, kept until
+/// its directory is removed.
+pub async fn heatmap(app_name: &str) -> Option<Heatmap> {
+    let minutes = WATCHED.lock().unwrap().get(app_name)?.clone();
+    let finished = DATA
+        .read()
+        .await
+        .get(app_name)
+        .map_or(true, |segments| segments.finished);
+    let minutes = minutes
+        .into_iter()
+        .map(|(start, requests)| WatchedMinute {
+            start: Utc
+                .timestamp(start, 0)
+                .to_rfc3339_opts(SecondsFormat::Secs, true),
+            requests,
+        })
+        .collect();
+    Some(Heatmap {
+        app: app_name.to_owned(),
+        finished,
+        minutes,
+    })
+}
+
+/// Adds an app's configured headers, replacing defaults of the same name.
+/// Errors go without, so a long cache policy never applies to a 404.
+fn with_headers(mut res: Response<Body>, headers: HeaderMap) -> Response<Body> {
+    if res.status().is_success() {
+        res.headers_mut().extend(headers);
+    }
+    res
+}
+
+#[cfg(feature = "keyframe_image")]
+async fn keyframe(
+    _config: Arc<config::Hls>,
+    _req: Request<Body>,
+    params: Params,
+) -> Response<Body> {
+    //http://127.0.0.1:3000/keyframe/app_name.vtt
+    let name = &params["file"];
+    if name.contains("..") {
+        return not_found(Problem::NOT_FOUND);
+    }
+    let mut res = serve_file(format!("./data/keyframe/{}", name)).await;
+    if name.ends_with(".vtt") && res.status().is_success() {
+        res.headers_mut()
+            .insert("Content-Type", "text/vtt".parse().unwrap());
+    }
+    res
+}
+
+async fn serve_file(file_path: String) -> Response<Body> {
+    match File::open(file_path).await {
+        Ok(file) => Response::new(Body::wrap_stream(FramedRead::new(file, BytesCodec::new()))),
+        Err(_) => not_found(Problem::NOT_FOUND),
+    }
+}
+
+fn not_found(problem: Problem) -> Response<Body> {
+    metrics::NOT_FOUND.inc();
+    problem.into_response()
+}
+
+pub async fn run(
+    mut recv: TsMessageReceiver,
+    config: config::Hls,
+    apps: HashMap<String, config::App>,
+    socket: config::Socket,
+    http: config::Http,
+    keys: Option<Arc<dyn KeyProvider>>,
+    shutdown: Shutdown,
+) -> Result<()> {
+    let listen_address = format!("[::]:{}", config.port);
     let sock_addr = listen_address.parse().unwrap();
+    let data_path = PathBuf::from(&config.data_path);
 
-    let new_service = make_service_fn(move |_| async {
-        Ok::<_, GenericError>(service_fn(move |req| handle_connection(req)))
-    });
-
-    tokio::spawn(async move {
-        while let Some(msg) = recv.recv().await {
-            let mut lock = DATA.write().await;
-            match msg {
-                TsMessageQueue::Ts(app_name, file_name, duration) => {
-                    match lock.get_mut(&app_name) {
-                        Some(d) => {
-                            d.0.push_back((file_name, duration));
-                            if d.0.len() > 6 {
-                                let temp = d.0.pop_front();
-                                let stream_path = PathBuf::from(format!(
-                                    "data/{}/{}.ts",
-                                    app_name,
-                                    temp.unwrap().0
-                                ));
-                                if stream_path.exists() {
-                                    _ = fs::remove_file(stream_path);
-                                }
-                            }
-                            d.1 += 1;
+    let new_segments = {
+        let config = config.clone();
+        let default = config::App::default();
+        move |app_name: &str| {
+            let app = apps.get(app_name).unwrap_or(&default);
+            Segments::new(&config, app, keys.clone())
+        }
+    };
+
+    if config.replica {
+        // another instance writes the segments, never touch them
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(REPLICA_SYNC);
+            loop {
+                interval.tick().await;
+                sync(&data_path, &new_segments).await;
+            }
+        });
+    } else {
+        DATA.write().await.extend(recover(&config, &new_segments));
+        tokio::spawn(async move {
+            while let Some(msg) = recv.recv().await {
+                let mut lock = DATA.write().await;
+                match msg {
+                    TsMessageQueue::Ts(app_name, segment) => {
+                        let stream_dir = data_path.join(&app_name);
+                        let segments = lock
+                            .entry(app_name.clone())
+                            .or_insert_with(|| new_segments(&app_name));
+                        let expired = segments.push(segment);
+                        segments.save(&stream_dir);
+                        for segment in expired {
+                            remove_segment(&stream_dir, segment.name);
                         }
-                        None => {
-                            let mut d = VecDeque::new();
-                            d.push_back((file_name, duration));
-                            lock.insert(app_name, (d, 1));
+                    }
+                    TsMessageQueue::Finished(app_name) => {
+                        if let Some(segments) = lock.get_mut(&app_name) {
+                            segments.finish();
+                            segments.save(&data_path.join(&app_name));
                         }
                     }
                 }
+                drop(lock);
             }
-            drop(lock);
-        }
-    });
+        });
+    }
+
+    let router = Router::new(config)
+        .get("/metrics", |_, _, _| async {
+            Response::new(Body::from(metrics::render()))
+        })
+        .describe("metrics", "Prometheus metrics")
+        .get("/readyz", health::readyz)
+        .describe(
+            "readyz",
+            "Readiness of all listeners and the stream manager",
+        )
+        .get("/groups/{group}.json", group)
+        .describe(
+            "group",
+            "Streams of a sync group and where their playlists line up",
+        )
+        .get("/master/{app}.m3u8", master)
+        .describe(
+            "master",
+            "Master playlist listing the stream on this and the backup origins",
+        )
+        .get("/{app}.m3u8", playlist)
+        .describe("playlist", "Live HLS playlist of a stream")
+        .query_param(
+            "_HLS_msn",
+            "Blocking reload, answer once this media sequence number is listed",
+        )
+        .query_param(
+            "_HLS_part",
+            "Accepted with _HLS_msn, segments have no parts",
+        )
+        .get("/data/{app}/{segment}.ts", segment)
+        .describe("segment", "MPEG-TS segment listed in a playlist");
+    #[cfg(feature = "keyframe_image")]
+    let router = router
+        .get("/keyframe/{file}", keyframe)
+        .describe("keyframe", "WebVTT thumbnail track or thumbnail");
+    let router = router
+        .openapi("xlive hls")
+        .fallback(|_, _, _| async { not_found(Problem::NOT_FOUND) });
 
-    let server = Server::bind(&sock_addr).serve(new_service);
+    let server = router.bind(sock_addr, &socket, &http, &shutdown)?;
+    health::bound(Listener::Hls);
     log::info!("Hls services listening on http://{}", sock_addr);
-    server.await?;
+    let result = server.await;
+    health::unbound(Listener::Hls);
+    Ok(result?)
+}
+
+/// Drops the playlist of a stream whose directory was removed.
+pub(crate) async fn forget(app_name: &str) {
+    DATA.write().await.remove(app_name);
+    WATCHED.lock().unwrap().remove(app_name);
+}
+
+/// Replaces the segment lists with the state sidecars found in `data_path`,
+/// forgetting streams whose sidecar is gone.
+async fn sync(data_path: &Path, new_segments: &impl Fn(&str) -> Segments) {
+    let mut states = HashMap::new();
+    if let Ok(entries) = fs::read_dir(data_path) {
+        for entry in entries.flatten() {
+            let app_name = match entry.file_name().into_string() {
+                Ok(app_name) => app_name,
+                Err(_) => continue,
+            };
+            let state = fs::read(entry.path().join(STATE_FILE))
+                .ok()
+                .and_then(|state| serde_json::from_slice::<State>(&state).ok());
+            match state {
+                Some(state) => _ = states.insert(app_name, Some(state)),
+                // being written, or left by an ingest that crashed
+                None if entry.path().join(STATE_FILE).exists() => _ = states.insert(app_name, None),
+                None => {}
+            }
+        }
+    }
+    let mut lock = DATA.write().await;
+    lock.retain(|app_name, _| states.contains_key(app_name));
+    for (app_name, state) in states {
+        let state = match state {
+            Some(state) => state,
+            None => continue,
+        };
+        let segments = lock
+            .entry(app_name.clone())
+            .or_insert_with(|| new_segments(&app_name));
+        if segments.load(state) {
+            log::debug!("Synced playlist of {}, {} segments", app_name, segments.added);
+        }
+    }
+}
+
+/// Restores the segment lists of streams that were live shortly before a
+/// restart from their state sidecars in `data_path`. Streams whose newest
+/// segment is older than the retention window are left alone. Recovered
+/// playlists are finished until a writer attaches again.
+fn recover(
+    config: &config::Hls,
+    new_segments: impl Fn(&str) -> Segments,
+) -> HashMap<String, Segments> {
+    let mut streams = HashMap::new();
+    let entries = match fs::read_dir(&config.data_path) {
+        Ok(entries) => entries,
+        Err(_) => return streams,
+    };
+
+    for entry in entries.flatten() {
+        let stream_dir = entry.path();
+        let app_name = match entry.file_name().into_string() {
+            Ok(app_name) if stream_dir.is_dir() => app_name,
+            _ => continue,
+        };
+        let state = match fs::read(stream_dir.join(STATE_FILE))
+            .ok()
+            .and_then(|state| serde_json::from_slice::<State>(&state).ok())
+        {
+            Some(state) => state,
+            None => continue,
+        };
+        let mut segments = new_segments(&app_name);
+        // segment names are the unix time they started at
+        let window = (segments.retention as u64 * config.ts_duration) as i64;
+        match state.segments.last() {
+            Some(newest) if newest.name >= Utc::now().timestamp() - window => {}
+            _ => continue,
+        }
+
+        segments.load(state);
+        // the retention may have been lowered since
+        if segments.playlist_type == PlaylistType::Live {
+            let excess = segments.retained.len().saturating_sub(segments.retention);
+            for expired in segments.retained.drain(..excess) {
+                remove_segment(&stream_dir, expired.name);
+            }
+        }
+        segments.finish();
+        segments.save(&stream_dir);
+        log::info!(
+            "Recovered {} HLS segments of {}",
+            segments.retained.len(),
+            app_name
+        );
+        streams.insert(app_name, segments);
+    }
+    streams
+}
 
-    Ok(())
+fn remove_segment(stream_dir: &Path, file_name: i64) {
+    let path = stream_dir.join(format!("{}.ts", file_name));
+    if path.exists() {
+        _ = fs::remove_file(path);
+    }
 }
 
-fn render_m3u8(app_name: String, d: Vec<(i64, u8)>, seq: u32) -> String {
+/// Live playlists carry no #EXT-X-PLAYLIST-TYPE, event playlists turn into
+/// VOD ones when the stream ends.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn render_m3u8(
+    app_name: &str,
+    d: Vec<Segment>,
+    seq: u32,
+    discontinuity_seq: u32,
+    playlist_type: PlaylistType,
+    finished: bool,
+    program_date_time: bool,
+    key: Option<Key>,
+) -> String {
     let mut max_duration: u32 = 0;
     for i in &d {
-        if i.1 as u32 > max_duration {
-            max_duration = i.1 as u32
+        if i.duration as u32 > max_duration {
+            max_duration = i.duration as u32
         }
     }
-    let mut m3u8 = format!("#EXTM3U\n");
-    m3u8 += format!("#EXT-X-VERSION:3\n").as_str();
+    let mut m3u8 = "#EXTM3U\n".to_string();
+    let version = key.as_ref().map_or(3, |key| key.version().max(3));
+    m3u8 += format!("#EXT-X-VERSION:{}\n", version).as_str();
     m3u8 += format!("#EXT-X-TARGETDURATION:{}\n", max_duration).as_str();
+    m3u8 += "#EXT-X-SERVER-CONTROL:CAN-BLOCK-RELOAD=YES\n";
+    match (playlist_type, finished) {
+        (PlaylistType::Live, _) => {}
+        (PlaylistType::Event, false) => m3u8 += "#EXT-X-PLAYLIST-TYPE:EVENT\n",
+        (PlaylistType::Event, true) => m3u8 += "#EXT-X-PLAYLIST-TYPE:VOD\n",
+    }
     m3u8 += format!("#EXT-X-MEDIA-SEQUENCE:{}\n", seq).as_str();
+    if discontinuity_seq > 0 {
+        m3u8 += format!("#EXT-X-DISCONTINUITY-SEQUENCE:{}\n", discontinuity_seq).as_str();
+    }
+    // applies to every segment that follows
+    if let Some(key) = &key {
+        m3u8 += key.tag().as_str();
+    }
     for i in &d {
-        m3u8 += format!("#EXTINF:{:.3}\ndata/{}/{}.ts\n", i.1 as f64, app_name, i.0).as_str();
+        if i.discontinuity {
+            m3u8 += "#EXT-X-DISCONTINUITY\n";
+        }
+        if program_date_time {
+            let date = Utc.timestamp_millis(i.started_at);
+            m3u8 += format!(
+                "#EXT-X-PROGRAM-DATE-TIME:{}\n",
+                date.to_rfc3339_opts(SecondsFormat::Millis, true)
+            )
+            .as_str();
+        }
+        m3u8 += format!(
+            "#EXTINF:{:.3}\ndata/{}/{}.ts\n",
+            i.duration as f64, app_name, i.name
+        )
+        .as_str();
+    }
+    if finished {
+        m3u8 += "#EXT-X-ENDLIST\n";
+    }
+    m3u8
+}
+
+/// Redundant variants share their attributes and differ in the URI only,
+/// players try them in order.
+fn render_master(app_name: &str, bandwidth: u64, origins: &[String], key: Option<Key>) -> String {
+    let version = key.as_ref().map_or(3, |key| key.version().max(3));
+    let mut m3u8 = format!("#EXTM3U\n#EXT-X-VERSION:{}\n", version);
+    if let Some(key) = &key {
+        m3u8 += key.session_tag().as_str();
+    }
+    let uris = std::iter::once(String::new()).chain(origins.iter().cloned());
+    for origin in uris {
+        m3u8 += format!(
+            "#EXT-X-STREAM-INF:BANDWIDTH={}\n{}/{}.m3u8\n",
+            bandwidth, origin, app_name
+        )
+        .as_str();
     }
     m3u8
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drm::Method;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    /// Hands out a new key URI each time `rotate` is called.
+    #[derive(Default)]
+    struct Rotating(AtomicU32);
+
+    impl Rotating {
+        fn rotate(&self) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    impl KeyProvider for Rotating {
+        fn key(&self, app: &str) -> Option<Key> {
+            Some(Key {
+                method: Method::Aes128,
+                uri: format!("https://keys/{}/{}", app, self.0.load(Ordering::Relaxed)),
+                iv: None,
+                keyformat: None,
+                keyformat_versions: None,
+            })
+        }
+    }
+
+    #[test]
+    fn renders_the_playlist_again_once_the_key_rotates() {
+        let config = config::Hls::default();
+        let keys = Arc::new(Rotating::default());
+        let mut segments = Segments::new(&config, &config::App::default(), Some(keys.clone()));
+        segments.push(Segment {
+            name: 1_600_000_000,
+            started_at: 1_600_000_000_000,
+            duration: 5,
+            discontinuity: false,
+        });
+
+        let first = segments.m3u8("live", &config);
+        assert!(contains(&first, "URI=\"https://keys/live/0\""));
+        assert_eq!(segments.cached("live"), Some(first));
+
+        keys.rotate();
+        assert_eq!(segments.cached("live"), None);
+        let rotated = segments.m3u8("live", &config);
+        assert!(contains(&rotated, "URI=\"https://keys/live/1\""));
+        assert_eq!(segments.cached("live"), Some(rotated));
+    }
+
+    fn contains(m3u8: &Bytes, needle: &str) -> bool {
+        std::str::from_utf8(m3u8).unwrap().contains(needle)
+    }
+}
@@ -1,90 +1,724 @@
+use crate::audit;
+use crate::codec::flv::index::KeyframeIndex;
+use crate::codec::flv::writer::tag;
+use crate::codec::flv::VideoData;
+use crate::config;
+use crate::dump;
 use crate::error::Error as PError;
-use crate::packet::{Packet, PacketType};
-use crate::transport::{ChannelMessage, ManagerHandle};
+use crate::health::{self, Listener};
+use crate::icecast::{self, Encoder, METAINT};
+use crate::metrics::{self, DropReason};
+use crate::naming;
+use crate::packet::PacketType;
+use crate::problem::Problem;
+use crate::router::{self, Params, Router};
+use crate::shutdown::Shutdown;
+#[cfg(feature = "keyframe_image")]
+use crate::snapshot::Snapshots;
+use crate::token;
+use crate::transport::{ChannelMessage, ManagerHandle, Sink};
 use crate::Message;
-use crate::{put_i24_be, put_i32_be, FLV_HEADER};
+use crate::FLV_HEADER;
 use bytes::{Bytes, BytesMut};
+use chrono::Utc;
+use futures::future::BoxFuture;
+use futures::{stream, FutureExt, StreamExt};
 use hyper::body::Sender;
-use hyper::service::{make_service_fn, service_fn};
-use hyper::{Body, Request, Response, Server, StatusCode};
-use std::collections::HashMap;
-use std::convert::Infallible;
+use hyper::{header, Body, Method, Request, Response, StatusCode};
+use serde::Serialize;
+use std::convert::TryFrom;
+use std::io::SeekFrom;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
 use tokio::sync::oneshot;
+use tokio_util::codec::{BytesCodec, Decoder, FramedRead};
 
-async fn http_flv(
-    manager_handle: ManagerHandle,
-    req: Request<Body>,
-) -> Result<Response<Body>, PError> {
-    let params: HashMap<String, String> = req
-        .uri()
-        .query()
-        .map(|v| {
-            url::form_urlencoded::parse(v.as_bytes())
-                .into_owned()
-                .collect()
-        })
-        .unwrap_or_else(HashMap::new);
-
-    if let Some(token) = params.get("token") {
-        //check token
-    } else {
-        return Ok(Response::builder()
-            .status(StatusCode::FORBIDDEN)
-            .body(Body::empty())
-            .unwrap());
-    }
-
-    let path = req.uri().path();
-
-    if path.is_empty() || !path.ends_with(".flv") {
-        return Ok(Response::builder()
-            .status(StatusCode::NOT_FOUND)
-            .body(Body::empty())
-            .unwrap());
-    }
-    let app_name = &path[1..(path.len() - 4)];
+/// With a `token_secret` or `token_keys` configured the token must be
+/// signed for the request path, see `xlive mint-token`.
+fn require_token(keyring: &token::Keyring, req: &Request<Body>) -> Option<Response<Body>> {
+    // probes from the orchestrator carry no token
+    if req.uri().path() == "/readyz" {
+        return None;
+    }
+    match router::query(req).get("token") {
+        Some(token)
+            if keyring.is_empty()
+                || keyring.verify(req.uri().path(), token, Utc::now().timestamp()) =>
+        {
+            None
+        }
+        Some(_) => Some(Problem::FORBIDDEN.detail("invalid token").into_response()),
+        None => Some(Problem::FORBIDDEN.detail("missing token").into_response()),
+    }
+}
+
+//http://127.0.0.1:3006/vod/app_name_1600000000.flv?start=60000
+async fn recording(service: Arc<Service>, req: Request<Body>, params: Params) -> Response<Body> {
+    let file_name = format!("{}.flv", params["name"]);
+    let start = router::query(&req)
+        .get("start")
+        .and_then(|v| v.parse().ok());
+    let range = req
+        .headers()
+        .get(header::RANGE)
+        .and_then(|range| range.to_str().ok());
+    match vod(&service.vod_path, &file_name, start, range).await {
+        Ok(res) => res,
+        Err(e) => {
+            log::debug!("vod {} err {}", file_name, e);
+            Problem::RECORDING_NOT_FOUND.into_response()
+        }
+    }
+}
+
+//http://127.0.0.1:3006/streams
+async fn streams(service: Arc<Service>, _req: Request<Body>, _params: Params) -> Response<Body> {
+    let (request, response) = oneshot::channel();
+    if service
+        .manager_handle
+        .send(ChannelMessage::List(request))
+        .is_err()
+    {
+        return Problem::UNAVAILABLE.into_response();
+    }
+    match response.await {
+        Ok(streams) => Response::builder()
+            .header("Content-Type", "application/json")
+            .body(serde_json::to_vec(&streams).unwrap().into())
+            .unwrap(),
+        Err(_) => Problem::UNAVAILABLE.into_response(),
+    }
+}
+
+//http://127.0.0.1:3006/app_name.flv
+async fn live(service: Arc<Service>, _req: Request<Body>, params: Params) -> Response<Body> {
+    let app_name = &params["app"];
 
     log::info!("app name {}", app_name);
-    let mut conn = Conn::new(manager_handle);
+    let mut conn = Conn::new(service.manager_handle.clone());
     let (sender, body) = Body::channel();
     match conn.init(app_name.to_owned(), sender).await {
-        Ok(_) => {}
+        Ok(_) => Response::new(body),
         Err(e) => {
             log::error!("{}", e);
-            let mut res = Response::new(Body::empty());
+            Problem::from(&e).into_response()
+        }
+    }
+}
+
+//http://127.0.0.1:3006/radio/app_name
+async fn radio(service: Arc<Service>, req: Request<Body>, params: Params) -> Response<Body> {
+    let app = params["app"].to_owned();
+    if let Err(e) = naming::validate_app_name(&app) {
+        return Problem::from(&e).into_response();
+    }
+    let (format, cached, mut watcher) = match icecast::join(&service.manager_handle, &app).await {
+        Ok(joined) => joined,
+        Err(e) => {
+            log::debug!("radio {} err {}", app, e);
+            return Problem::from(&e).into_response();
+        }
+    };
+    // players ask for the title with `Icy-MetaData: 1`
+    let icy = req
+        .headers()
+        .get("icy-metadata")
+        .map_or(false, |v| v.as_bytes() == b"1");
+    let mut encoder = Encoder::new(format, Some(app.as_str()).filter(|_| icy));
+    let (mut sender, body) = Body::channel();
+    let name = app.clone();
+    tokio::spawn(async move {
+        use tokio::sync::broadcast::error::RecvError;
+        for packet in cached {
+            let data = encoder.encode(&packet.payload);
+            if !data.is_empty() && sender.send_data(data.into()).await.is_err() {
+                return;
+            }
+        }
+        loop {
+            let packet = match watcher.recv().await {
+                Ok(packet) if matches!(packet.kind, PacketType::Audio) => packet,
+                Ok(_) => continue,
+                Err(RecvError::Lagged(skipped)) => {
+                    metrics::dropped(&name, DropReason::Lag, skipped);
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            };
+            let data = encoder.encode(&packet.payload);
+            if !data.is_empty() && sender.send_data(data.into()).await.is_err() {
+                break;
+            }
+        }
+    });
+    let response = Response::builder()
+        .header(header::CONTENT_TYPE, format.content_type())
+        .header(header::CACHE_CONTROL, "no-cache")
+        .header("icy-name", &app);
+    let response = match icy {
+        true => response.header("icy-metaint", METAINT),
+        false => response,
+    };
+    response.body(body).unwrap()
+}
+
+/// Where encoders and players reach the listeners, for `/urls`.
+#[derive(Clone)]
+pub struct Endpoints {
+    host: Option<String>,
+    rtmp_port: i32,
+    hls_port: i32,
+    http_flv_port: i32,
+}
+
+impl Endpoints {
+    pub fn new(config: &config::Settings) -> Self {
+        Self {
+            host: Some(config.public_host.clone()).filter(|h| !h.is_empty()),
+            rtmp_port: config.rtmp.port,
+            hls_port: config.hls.port,
+            http_flv_port: config.http_flv.port,
+        }
+    }
+}
+
+/// Ingest and playback URLs of a stream, formats the build lacks are left
+/// out.
+#[derive(Serialize)]
+struct StreamUrls {
+    rtmp: String,
+    http_flv: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hls: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    snapshot: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thumbnails: Option<String>,
+}
+
+impl StreamUrls {
+    fn new(endpoints: &Endpoints, host: &str, app: &str, stream: &str) -> Self {
+        let http = |port, path: String| format!("http://{}:{}/{}", host, port, path);
+        let hls = |path| Some(http(endpoints.hls_port, path)).filter(|_| cfg!(feature = "hls"));
+        let keyframes = cfg!(feature = "keyframe_image");
+        Self {
+            rtmp: format!("rtmp://{}:{}/{}/{}", host, endpoints.rtmp_port, app, stream),
+            http_flv: http(endpoints.http_flv_port, format!("{}.flv", app)),
+            hls: hls(format!("{}.m3u8", app)),
+            snapshot: Some(http(
+                endpoints.http_flv_port,
+                format!("snapshot/{}.jpg", app),
+            ))
+            .filter(|_| keyframes),
+            thumbnails: hls(format!("keyframe/{}.vtt", app)).filter(|_| keyframes),
+        }
+    }
+}
+
+//http://127.0.0.1:3006/urls/app_name/stream_key
+async fn urls(service: Arc<Service>, req: Request<Body>, params: Params) -> Response<Body> {
+    let (app, stream) = (&params["app"], &params["stream"]);
+    if let Err(e) = naming::validate_app_name(app).and(naming::validate_stream_key(stream)) {
+        return Problem::from(&e).into_response();
+    }
+    // without a configured host, the one the client reached us by
+    let host = match &service.endpoints.host {
+        Some(host) => host.clone(),
+        None => req
+            .headers()
+            .get(header::HOST)
+            .and_then(|host| host.to_str().ok())
+            .and_then(|host| host.parse::<hyper::http::uri::Authority>().ok())
+            .map(|authority| authority.host().to_owned())
+            .unwrap_or_else(|| "localhost".to_owned()),
+    };
+    let urls = StreamUrls::new(&service.endpoints, &host, app, stream);
+    Response::builder()
+        .header("Content-Type", "application/json")
+        .body(serde_json::to_vec(&urls).unwrap().into())
+        .unwrap()
+}
+
+const DUMP_SECONDS: u64 = 10;
+const MAX_DUMP_SECONDS: u64 = 600;
+
+//curl -X POST http://127.0.0.1:3006/dump/app_name?seconds=30
+async fn dump(service: Arc<Service>, req: Request<Body>, params: Params) -> Response<Body> {
+    let app = &params["app"];
+    if let Err(e) = naming::validate_app_name(app) {
+        return Problem::from(&e).into_response();
+    }
+    let seconds = match router::query(&req).get("seconds").map(|s| s.parse()) {
+        None => DUMP_SECONDS,
+        Some(Ok(seconds)) if (1..=MAX_DUMP_SECONDS).contains(&seconds) => seconds,
+        Some(_) => {
+            return Problem::BAD_REQUEST
+                .detail(format!(
+                    "seconds must be between 1 and {}",
+                    MAX_DUMP_SECONDS
+                ))
+                .into_response()
+        }
+    };
+    match dump::start(&service.manager_handle, app, Duration::from_secs(seconds)).await {
+        Ok(file) => Response::builder()
+            .status(StatusCode::ACCEPTED)
+            .header("Content-Type", "application/json")
+            .body(
+                serde_json::json!({"file": file, "seconds": seconds})
+                    .to_string()
+                    .into(),
+            )
+            .unwrap(),
+        Err(e) => {
+            log::warn!("Failed to dump {}: {}", app, e);
+            Problem::from_anyhow(&e).into_response()
+        }
+    }
+}
+
+//http://127.0.0.1:3006/sinks/app_name/hls
+async fn sink(service: Arc<Service>, req: Request<Body>, params: Params) -> Response<Body> {
+    let app = &params["app"];
+    if let Err(e) = naming::validate_app_name(app) {
+        return Problem::from(&e).into_response();
+    }
+    let sink = match &params["sink"][..] {
+        "hls" => Sink::Hls,
+        "flv" => Sink::Flv,
+        other => {
+            return Problem::BAD_REQUEST
+                .detail(format!("unknown sink {}, expected hls or flv", other))
+                .into_response()
+        }
+    };
+    let enabled = req.method() == Method::PUT;
+    let (request, response) = oneshot::channel();
+    if service
+        .manager_handle
+        .send(ChannelMessage::SetSink(app.to_owned(), sink, enabled, request))
+        .is_err()
+    {
+        return Problem::UNAVAILABLE.into_response();
+    }
+    match response.await {
+        Ok(Some(sinks)) => Response::builder()
+            .header("Content-Type", "application/json")
+            .body(serde_json::json!({ "sinks": sinks }).to_string().into())
+            .unwrap(),
+        Ok(None) => Problem::from(&PError::NoSuchStream(app.to_owned())).into_response(),
+        Err(_) => Problem::UNAVAILABLE.into_response(),
+    }
+}
+
+/// Writes every request `handler` answers to the audit log, for the admin
+/// routes that change what the server does.
+fn audited<H, F>(
+    action: &'static str,
+    handler: H,
+) -> impl Fn(Arc<Service>, Request<Body>, Params) -> BoxFuture<'static, Response<Body>>
+where
+    H: Fn(Arc<Service>, Request<Body>, Params) -> F + Send + Sync + 'static,
+    F: std::future::Future<Output = Response<Body>> + Send + 'static,
+{
+    move |service, req, params| {
+        let peer = router::peer(&req);
+        let target = format!("{} {}", req.method(), req.uri().path());
+        let res = handler(service, req, params);
+        async move {
+            let res = res.await;
+            audit::record(peer, action, &target, res.status()).await;
+            res
+        }
+        .boxed()
+    }
+}
+
+//http://127.0.0.1:3006/audit
+async fn audit_log(_service: Arc<Service>, _req: Request<Body>, _params: Params) -> Response<Body> {
+    let body = match File::open(audit::AUDIT_PATH).await {
+        Ok(file) => {
+            let lines = FramedRead::new(file, BytesCodec::new()).map(|r| r.map(BytesMut::freeze));
+            Body::wrap_stream(lines)
+        }
+        // nothing was done yet
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Body::empty(),
+        Err(e) => {
+            log::error!("Failed to open the audit log {}: {}", audit::AUDIT_PATH, e);
+            return Problem::UNAVAILABLE.into_response();
+        }
+    };
+    Response::builder()
+        .header("Content-Type", "application/x-ndjson")
+        .body(body)
+        .unwrap()
+}
+
+//http://127.0.0.1:3006/heatmap/app_name.json
+#[cfg(feature = "hls")]
+async fn heatmap(_service: Arc<Service>, _req: Request<Body>, params: Params) -> Response<Body> {
+    let app = &params["app"];
+    if let Err(e) = naming::validate_app_name(app) {
+        return Problem::from(&e).into_response();
+    }
+    match crate::hls::heatmap(app).await {
+        Some(heatmap) => Response::builder()
+            .header("Content-Type", "application/json")
+            .body(serde_json::to_vec(&heatmap).unwrap().into())
+            .unwrap(),
+        None => Problem::STREAM_NOT_FOUND
+            .detail("no segment of the stream was requested")
+            .into_response(),
+    }
+}
+
+//http://127.0.0.1:3006/snapshot/app_name.jpg
+#[cfg(feature = "keyframe_image")]
+async fn snapshot(service: Arc<Service>, _req: Request<Body>, params: Params) -> Response<Body> {
+    match service
+        .snapshots
+        .get(&service.manager_handle, &params["app"])
+        .await
+    {
+        Ok(image) => Response::builder()
+            .header("Content-Type", "image/jpeg")
+            .header("Cache-Control", "no-cache")
+            .body(image.into())
+            .unwrap(),
+        Err(problem) => problem.into_response(),
+    }
+}
+
+// metadata and sequence headers before the first keyframe, several
+// times what encoders write
+const MAX_HEAD_LEN: u64 = 1 << 20;
+
+/// Serves a finished recording. With `start` (ms) the stream begins at the
+/// closest keyframe before it, preceded by the recording's metadata and
+/// sequence headers so players can initialize their decoders, and with the
+/// timestamps moved back so the keyframe is at 0. Otherwise
+/// the file is served as stored, or the byte `range` of it players ask for
+/// when scrubbing.
+async fn vod(
+    vod_path: &Path,
+    file_name: &str,
+    start: Option<u64>,
+    range: Option<&str>,
+) -> std::io::Result<Response<Body>> {
+    if file_name.contains('/') || file_name.contains("..") {
+        return Err(std::io::ErrorKind::InvalidInput.into());
+    }
+    let path = vod_path.join(file_name);
+    let mut file = File::open(&path).await?;
+    // a recording still being written is served up to its current end
+    let len = file.metadata().await?.len();
+
+    if let Some(start) = start {
+        let index = KeyframeIndex::load(&path).await?;
+        if let (Some((_, first)), Some((_, offset))) = (index.first(), index.seek(start)) {
+            // the index is a sidecar file, don't trust it with allocations
+            let head_len = FLV_HEADER.len() as u64..=MAX_HEAD_LEN;
+            if !head_len.contains(&first) || first > offset || offset > len {
+                log::warn!("Ignoring the keyframe index of {}", file_name);
+            } else {
+                let mut head = vec![0u8; first as usize];
+                file.read_exact(&mut head).await?;
+                let mut prefix = BytesMut::new();
+                prefix.extend_from_slice(&FLV_HEADER);
+                prefix.extend(header_tags(&head[FLV_HEADER.len()..]));
+                file.seek(SeekFrom::Start(offset)).await?;
+                let prefix = stream::iter(Some(Ok(prefix.freeze())));
+                let rest = FramedRead::new(file, RebasedTags::default());
+                return Ok(Response::new(Body::wrap_stream(prefix.chain(rest))));
+            }
+        }
+    }
+
+    let res = Response::builder().header(header::ACCEPT_RANGES, "bytes");
+    let (res, range) = match range.map(|range| byte_range(range, len)) {
+        Some(Ok(Some(range))) => {
+            let content_range = format!("bytes {}-{}/{}", range.start, range.end - 1, len);
+            let res = res
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(header::CONTENT_RANGE, content_range);
+            (res, range)
+        }
+        Some(Err(())) => {
+            let mut res = Problem::RANGE_NOT_SATISFIABLE.into_response();
+            let content_range = format!("bytes */{}", len).parse().unwrap();
             res.headers_mut()
-                .insert("Access-Control-Allow-Origin", "*".parse().unwrap());
-            *res.status_mut() = StatusCode::NOT_FOUND;
+                .insert(header::CONTENT_RANGE, content_range);
             return Ok(res);
         }
+        Some(Ok(None)) | None => (res, 0..len),
+    };
+    file.seek(SeekFrom::Start(range.start)).await?;
+    let body = FramedRead::new(file.take(range.end - range.start), BytesCodec::new())
+        .map(|r| r.map(BytesMut::freeze));
+    Ok(res
+        .header(header::CONTENT_LENGTH, range.end - range.start)
+        .body(Body::wrap_stream(body))
+        .unwrap())
+}
+
+/// The single byte range of a `Range` header, clamped to the file.
+/// `Ok(None)` for headers that are ignored, several ranges among them,
+/// `Err` if the range starts past the end.
+fn byte_range(header: &str, len: u64) -> Result<Option<Range<u64>>, ()> {
+    let spec = match header.trim().strip_prefix("bytes=") {
+        Some(spec) if !spec.contains(',') => spec.trim(),
+        _ => return Ok(None),
+    };
+    let (first, last) = match spec.split_once('-') {
+        Some(bounds) => bounds,
+        None => return Ok(None),
+    };
+    let range = match (first.parse::<u64>(), last.parse::<u64>()) {
+        (Ok(first), Ok(last)) if first <= last => first..last.saturating_add(1).min(len),
+        (Ok(first), Err(_)) if last.is_empty() => first..len,
+        // the last `suffix` bytes
+        (Err(_), Ok(suffix)) if first.is_empty() => len.saturating_sub(suffix)..len,
+        _ => return Ok(None),
+    };
+    if range.is_empty() {
+        return Err(());
+    }
+    Ok(Some(range))
+}
+
+/// Splits the tags after a seek point, moving their timestamps back by the
+/// first one's so players start near 0.
+#[derive(Default)]
+struct RebasedTags {
+    base: Option<u32>,
+}
+
+impl Decoder for RebasedTags {
+    type Item = Bytes;
+    type Error = std::io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> std::io::Result<Option<Bytes>> {
+        if src.len() < 11 {
+            return Ok(None);
+        }
+        let data_len = (src[1] as usize) << 16 | (src[2] as usize) << 8 | src[3] as usize;
+        let tag_len = 11 + data_len + 4;
+        if src.len() < tag_len {
+            src.reserve(tag_len - src.len());
+            return Ok(None);
+        }
+        let mut tag = src.split_to(tag_len);
+        // 24 bits and the extension as the high byte
+        let timestamp = u32::from_be_bytes([tag[7], tag[4], tag[5], tag[6]]);
+        let base = *self.base.get_or_insert(timestamp);
+        let [extended, high, middle, low] = timestamp.saturating_sub(base).to_be_bytes();
+        tag[4..8].copy_from_slice(&[high, middle, low, extended]);
+        Ok(Some(tag.freeze()))
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> std::io::Result<Option<Bytes>> {
+        let tag = self.decode(src)?;
+        // a recording still being written ends inside a tag
+        if tag.is_none() {
+            src.clear();
+        }
+        Ok(tag)
     }
-    let mut res = Response::new(body);
-    res.headers_mut()
-        .insert("Access-Control-Allow-Origin", "*".parse().unwrap());
-    Ok(res)
 }
 
+// Keeps the metadata and sequence header tags found before the first keyframe.
+fn header_tags(mut tags: &[u8]) -> BytesMut {
+    let mut kept = BytesMut::new();
+    while tags.len() >= 11 {
+        let data_len = (tags[1] as usize) << 16 | (tags[2] as usize) << 8 | tags[3] as usize;
+        let tag_len = 11 + data_len + 4;
+        if tags.len() < tag_len {
+            break;
+        }
+        let body = &tags[11..11 + data_len];
+        let is_sequence_header = body.len() > 1 && body[1] == 0;
+        let keep = match tags[0] {
+            18 => true,
+            9 => is_sequence_header,
+            8 => match body.first().map(|b| b >> 4) {
+                Some(10) => is_sequence_header,
+                // Enhanced RTMP, the packet type is in the low nibble
+                Some(9) => body[0] & 0x0f == 0,
+                _ => false,
+            },
+            _ => false,
+        };
+        if keep {
+            kept.extend_from_slice(&tags[..tag_len]);
+        }
+        tags = &tags[tag_len..];
+    }
+    kept
+}
+
+#[derive(Clone)]
 pub struct Service {
     manager_handle: ManagerHandle,
+    vod_path: PathBuf,
+    keyring: token::Keyring,
+    expose_streams: bool,
+    endpoints: Endpoints,
+    #[cfg(feature = "keyframe_image")]
+    snapshots: Arc<Snapshots>,
 }
 
 impl Service {
-    pub fn new(manager_handle: ManagerHandle) -> Self {
-        Self { manager_handle }
+    pub fn new(
+        manager_handle: ManagerHandle,
+        vod_path: String,
+        keyring: token::Keyring,
+        expose_streams: bool,
+        endpoints: Endpoints,
+    ) -> Self {
+        Self {
+            manager_handle,
+            vod_path: PathBuf::from(vod_path),
+            keyring,
+            expose_streams,
+            endpoints,
+            #[cfg(feature = "keyframe_image")]
+            snapshots: Arc::new(Snapshots::new()),
+        }
     }
 
-    pub async fn run(&self, port: i32) {
-        let manager_handle = self.manager_handle.clone();
-        let make_service = make_service_fn(move |_| {
-            let manager_handle = manager_handle.clone();
-            async move {
-                Ok::<_, Infallible>(service_fn(move |req| http_flv(manager_handle.clone(), req)))
+    pub async fn run(
+        &self,
+        port: i32,
+        admin_port: i32,
+        socket: &config::Socket,
+        http: &config::Http,
+        shutdown: &Shutdown,
+    ) {
+        if self.expose_streams && self.keyring.is_empty() {
+            log::warn!("/streams is public, set a token secret or http_flv.expose_streams: false");
+        }
+        let admin = async {
+            if admin_port == 0 {
+                return;
+            }
+            // local tooling and health scripts, reachable from this host only
+            let addr = ([127, 0, 0, 1], admin_port as u16).into();
+            match self.routes(None).bind(addr, socket, http, shutdown) {
+                Ok(server) => {
+                    log::info!("http-flv admin Listening on http://{}", addr);
+                    if let Err(e) = server.await {
+                        log::error!("http-flv admin stopped: {}", e);
+                    }
+                }
+                Err(e) => log::error!("http-flv admin failed to bind {}: {}", addr, e),
             }
-        });
-        let addr = format!("[::]:{}", port).parse().unwrap();
-        let server = Server::bind(&addr).serve(make_service);
-        log::info!("http-flv service Listening on http://{}", addr);
-        _ = server.await;
+        };
+        let public = async {
+            let addr = format!("[::]:{}", port).parse().unwrap();
+            let router = self.routes(Some(self.keyring.clone()));
+            let server = match router.bind(addr, socket, http, shutdown) {
+                Ok(server) => server,
+                Err(e) => {
+                    log::error!("http-flv service failed to bind {}: {}", addr, e);
+                    return;
+                }
+            };
+            health::bound(Listener::HttpFlv);
+            log::info!("http-flv service Listening on http://{}", addr);
+            if let Err(e) = server.await {
+                log::error!("http-flv service stopped: {}", e);
+            }
+            health::unbound(Listener::HttpFlv);
+        };
+        futures::join!(public, admin);
+    }
+
+    /// The routes, checking tokens against `keyring` if given. Without one,
+    /// on the admin listener, every route is open, `/streams` is always
+    /// served and so are `/dump`, `/sinks` and `/audit`.
+    fn routes(&self, keyring: Option<token::Keyring>) -> Router<Self> {
+        let admin = keyring.is_none();
+        let expose_streams = self.expose_streams || admin;
+        let router = Router::new(self.clone());
+        let router = match keyring {
+            Some(keyring) => router.before(move |req| require_token(&keyring, req)),
+            None => router,
+        };
+        let router = router
+            .after(router::allow_any_origin)
+            .get("/vod/{name}.flv", recording)
+            .describe("recording", "Recorded FLV file")
+            .query_param("start", "Offset in milliseconds to start playback at")
+            .query_param("token", "Access token")
+            .get("/readyz", health::readyz)
+            .describe(
+                "readyz",
+                "Readiness of all listeners and the stream manager",
+            );
+        // stream names often are the stream keys
+        let router = match expose_streams {
+            true => router
+                .get("/streams", streams)
+                .describe("streams", "Live streams and their publishers")
+                .query_param("token", "Access token"),
+            false => router,
+        };
+        // operator actions, only on the loopback admin listener
+        let router = match admin {
+            true => router
+                .route(&[Method::POST], "/dump/{app}", audited("dump", dump))
+                .describe(
+                    "dump",
+                    "Start capturing a stream's packets to data/dump for replay",
+                )
+                .query_param("seconds", "How long to capture, 10 by default, at most 600")
+                .route(
+                    &[Method::PUT, Method::DELETE],
+                    "/sinks/{app}/{sink}",
+                    audited("sink", sink),
+                )
+                .describe(
+                    "sink",
+                    "Attach (PUT) or detach (DELETE) the hls or flv sink of a live stream",
+                )
+                .get("/audit", audit_log)
+                .describe("audit", "Admin actions taken, one JSON object per line"),
+            false => router,
+        };
+        let router = router
+            .get("/urls/{app}/{stream}", urls)
+            .describe(
+                "urls",
+                "Ingest and playback URLs of a stream, with the public host",
+            )
+            .query_param("token", "Access token")
+            .get("/radio/{app}", radio)
+            .describe(
+                "radio",
+                "Audio of a stream as AAC (ADTS) or MP3 for internet radio players",
+            )
+            .query_param("token", "Access token")
+            .get("/{*app}.flv", live)
+            .describe("live", "Live HTTP-FLV stream")
+            .query_param("token", "Access token");
+        #[cfg(feature = "hls")]
+        let router = router
+            .get("/heatmap/{app}.json", heatmap)
+            .describe(
+                "heatmap",
+                "HLS segment requests per minute of a broadcast",
+            )
+            .query_param("token", "Access token");
+        #[cfg(feature = "keyframe_image")]
+        let router = router
+            .get("/snapshot/{app}.jpg", snapshot)
+            .describe("snapshot", "Latest cached keyframe of a stream as JPEG")
+            .query_param("token", "Access token");
+        router.openapi("xlive http-flv")
     }
 }
 
@@ -100,7 +734,7 @@ impl Conn {
     async fn init(&mut self, app_name: String, mut body_sender: Sender) -> Result<(), PError> {
         let (request, response) = oneshot::channel();
         self.manager_handle
-            .send(ChannelMessage::Join((app_name, request)))
+            .send(ChannelMessage::Join((app_name.clone(), request)))
             .map_err(|_| PError::ChannelJoinFailed)?;
 
         match response.await {
@@ -124,19 +758,21 @@ impl Conn {
                             return;
                         }
                     }
+                    // without a cached GOP the viewer joins mid-GOP, so hold video
+                    // back until the next keyframe instead of sending broken frames
+                    let mut awaiting_keyframe = true;
                     if let Ok((meta, video, audio, gop)) = response.await {
                         log::info!("send init data");
-                        meta.map(|m| retrun_data.push(Bytes::from(packet_to_bytes(&m))));
-                        audio.map(|a| retrun_data.push(Bytes::from(packet_to_bytes(&a))));
-                        video.map(|v| retrun_data.push(Bytes::from(packet_to_bytes(&v))));
-                        gop.map(|gop| {
-                            for g in gop {
-                                retrun_data.push(Bytes::from(packet_to_bytes(&g)));
-                            }
-                        });
+                        awaiting_keyframe = gop.as_ref().map_or(true, |gop| gop.is_empty());
+                        for p in meta.into_iter().chain(audio).chain(video) {
+                            retrun_data.push(Bytes::from(tag(&p)));
+                        }
+                        for g in gop.into_iter().flatten() {
+                            retrun_data.push(Bytes::from(tag(&g)));
+                        }
                     }
 
-                    let packets: Vec<Bytes> = retrun_data.drain(..).collect();
+                    let packets: Vec<Bytes> = std::mem::take(&mut retrun_data);
                     for p in packets {
                         match body_sender.send_data(p).await {
                             Ok(_) => {}
@@ -146,9 +782,34 @@ impl Conn {
                             }
                         }
                     }
-                    while let Ok(packet) = session_receiver.recv().await {
+                    use tokio::sync::broadcast::error::RecvError;
+                    loop {
+                        let packet = match session_receiver.recv().await {
+                            Ok(packet) => packet,
+                            Err(RecvError::Lagged(skipped)) => {
+                                metrics::dropped(&app_name, DropReason::Lag, skipped);
+                                // frames are missing, wait for the next keyframe
+                                awaiting_keyframe = true;
+                                continue;
+                            }
+                            Err(RecvError::Closed) => break,
+                        };
+                        if awaiting_keyframe && matches!(packet.kind, PacketType::Video) {
+                            match VideoData::try_from(&packet.payload[..]) {
+                                Ok(video) if video.is_sequence_header() => {}
+                                Ok(video) if video.is_keyframe() => awaiting_keyframe = false,
+                                Ok(_) => {
+                                    metrics::dropped(&app_name, DropReason::Keyframe, 1);
+                                    continue;
+                                }
+                                Err(_) => {
+                                    metrics::dropped(&app_name, DropReason::Parse, 1);
+                                    continue;
+                                }
+                            }
+                        }
                         match body_sender
-                            .send_data(Bytes::from(packet_to_bytes(&packet)))
+                            .send_data(Bytes::from(tag(&packet)))
                             .await
                         {
                             Ok(_) => {}
@@ -160,43 +821,79 @@ impl Conn {
                     }
                 });
             }
-            Err(e) => {
-                log::error!("join channel  err {}", e);
-                return Err(PError::ChannelJoinFailed);
-            }
+            // the manager drops the request of an unknown stream
+            Err(_) => return Err(PError::NoSuchStream(app_name)),
         }
         Ok(())
     }
 }
 
-pub fn packet_to_bytes(packet: &Packet) -> BytesMut {
-    let type_id = match packet.kind {
-        PacketType::Audio => 8,
-        PacketType::Meta => 18,
-        PacketType::Video => 9,
-    };
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::flv::writer::{self, Writer};
+    use crate::packet::Packet;
 
-    let data_len = packet.payload.len();
-    let timestamp: u64 = match packet.timestamp {
-        Some(u) => u.into(),
-        None => 0,
-    };
+    const KEYFRAME: [u8; 5] = [0x17, 1, 0, 0, 0];
+    const INTERFRAME: [u8; 5] = [0x27, 1, 0, 0, 0];
 
-    let pre_data_len = data_len + 11;
-    let timestamp_base = timestamp & 0xffffff;
-    let timestamp_ext = timestamp >> 24 & 0xff;
-    let mut h = [0u8; 11];
+    fn tag_timestamp(tag: &[u8]) -> u32 {
+        u32::from_be_bytes([tag[7], tag[4], tag[5], tag[6]])
+    }
 
-    h[0] = type_id;
-    put_i24_be(&mut h[1..4], data_len as i32);
-    put_i24_be(&mut h[4..7], timestamp_base as i32);
-    h[7] = timestamp_ext as u8;
+    #[test]
+    fn rebases_extended_timestamps() {
+        let mut src = BytesMut::new();
+        for timestamp in [0xfffff0u64, 0x1000010, 0x1000100] {
+            src.extend(writer::tag(&Packet::new_video(timestamp, &INTERFRAME[..])));
+        }
+        let mut tags = RebasedTags::default();
+        let mut rebased = vec![];
+        while let Some(tag) = tags.decode(&mut src).unwrap() {
+            rebased.push(tag_timestamp(&tag));
+        }
+        assert_eq!(rebased, [0, 0x20, 0x110]);
+
+        let mut src = BytesMut::new();
+        for timestamp in [0x2000000u64, 0x3000001] {
+            src.extend(writer::tag(&Packet::new_video(timestamp, &INTERFRAME[..])));
+        }
+        let mut tags = RebasedTags::default();
+        assert_eq!(tag_timestamp(&tags.decode(&mut src).unwrap().unwrap()), 0);
+        let tag = tags.decode(&mut src).unwrap().unwrap();
+        // the difference needs the extension byte as well
+        assert_eq!(&tag[4..8], &[0, 0, 1, 1]);
+    }
 
-    let mut b = BytesMut::new();
-    b.extend(&h);
-    b.extend(&packet.payload);
+    #[tokio::test]
+    async fn indexes_extended_keyframe_timestamps() {
+        let dir = std::env::temp_dir().join(format!("xlive-index-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("recording.flv");
+        let mut recording = Writer::new(&path).await.unwrap();
+        for (timestamp, payload) in [
+            (0xfffff0u64, KEYFRAME),
+            (0x1000000, INTERFRAME),
+            (0x1000010, KEYFRAME),
+            (0xffffffff, KEYFRAME),
+        ] {
+            recording
+                .write(&Packet::new_video(timestamp, payload.to_vec()))
+                .await
+                .unwrap();
+        }
+        drop(recording);
 
-    put_i32_be(&mut h[0..4], pre_data_len as i32);
-    b.extend(&h[0..4]);
-    b
+        let index = KeyframeIndex::load(&path).await.unwrap();
+        let file = std::fs::read(&path).unwrap();
+        _ = std::fs::remove_dir_all(&dir);
+        assert_eq!(index.first().map(|(ts, _)| ts), Some(0xfffff0));
+        for wanted in [0x1000010, 0xffffffff] {
+            let (timestamp, offset) = index.seek(wanted).unwrap();
+            assert_eq!(timestamp, wanted);
+            let tag = &file[offset as usize..];
+            assert_eq!(tag[0], 9);
+            assert_eq!(u64::from(tag_timestamp(tag)), timestamp);
+        }
+    }
 }
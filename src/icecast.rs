@@ -0,0 +1,164 @@
+use crate::codec::aac::{self, AacCoder};
+use crate::codec::flv::{audio::AudioFormat, AudioData};
+use crate::codec::{FormatReader, FormatWriter};
+use crate::error::Error;
+use crate::packet::{Packet, PacketType};
+use crate::transport::{ChannelMessage, ManagerHandle, Message, Watcher};
+use std::convert::TryFrom;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{broadcast::error::RecvError, oneshot};
+
+/// Audio bytes between two metadata blocks, sent as `icy-metaint`.
+pub const METAINT: usize = 16000;
+/// How long a listener waits for the first audio of a stream without an
+/// AAC sequence header, which tells the format.
+const FIRST_AUDIO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What an audio-only listener is sent.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    /// AAC in ADTS frames.
+    Aac,
+    /// MP3 frames as published.
+    Mp3,
+}
+
+impl Format {
+    /// The format of an FLV audio tag, if listeners can be sent it.
+    fn of(payload: &[u8]) -> Option<Self> {
+        match payload.first()? >> 4 {
+            10 => Some(Format::Aac),
+            2 => Some(Format::Mp3),
+            _ => None,
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Aac => "audio/aac",
+            Format::Mp3 => "audio/mpeg",
+        }
+    }
+}
+
+/// Joins channel `app` for a listener. Returns the format, the cached
+/// packets to encode first and the watcher for the rest.
+pub async fn join(
+    manager: &ManagerHandle,
+    app: &str,
+) -> Result<(Format, Vec<Arc<Packet>>, Watcher), Error> {
+    let (request, response) = oneshot::channel();
+    manager
+        .send(ChannelMessage::Join((app.to_owned(), request)))
+        .map_err(|_| Error::ChannelJoinFailed)?;
+    let (handle, mut watcher) = response
+        .await
+        .map_err(|_| Error::NoSuchStream(app.to_owned()))?;
+    let (request, response) = oneshot::channel();
+    handle
+        .send(Message::InitData(request))
+        .map_err(|_| Error::NoSuchStream(app.to_owned()))?;
+    let (_, _, audio, _) = response
+        .await
+        .map_err(|_| Error::NoSuchStream(app.to_owned()))?;
+    if let Some(audio) = audio {
+        let format = Format::of(&audio.payload).ok_or(Error::InvalidInput)?;
+        return Ok((format, vec![audio], watcher));
+    }
+    // MP3 has no sequence header, the first frame tells
+    let first = tokio::time::timeout(FIRST_AUDIO_TIMEOUT, async {
+        loop {
+            match watcher.recv().await {
+                Ok(packet) if matches!(packet.kind, PacketType::Audio) => return Some(packet),
+                Ok(_) | Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    })
+    .await
+    .map_err(|_| Error::NoMediaReceived(FIRST_AUDIO_TIMEOUT.as_secs()))?
+    .ok_or_else(|| Error::NoSuchStream(app.to_owned()))?;
+    let format = Format::of(&first.payload).ok_or(Error::InvalidInput)?;
+    Ok((format, vec![first], watcher))
+}
+
+/// Turns the audio tags of a channel into the byte stream of a listener,
+/// with `StreamTitle` metadata blocks every `METAINT` bytes if asked for.
+pub struct Encoder {
+    format: Format,
+    aac: AacCoder,
+    // the metadata block and the audio bytes left before it is due
+    metadata: Option<(Vec<u8>, usize)>,
+}
+
+impl Encoder {
+    pub fn new(format: Format, title: Option<&str>) -> Self {
+        Self {
+            format,
+            aac: AacCoder::new(),
+            metadata: title.map(|title| (metadata(title), METAINT)),
+        }
+    }
+
+    /// What to send for an audio tag, empty for tags that carry no frames.
+    pub fn encode(&mut self, payload: &[u8]) -> Vec<u8> {
+        let frames = match self.format {
+            Format::Mp3 if Format::of(payload) == Some(Format::Mp3) => payload[1..].to_vec(),
+            Format::Mp3 => vec![],
+            Format::Aac => match AudioData::try_from(payload) {
+                Ok(audio) if matches!(audio.format, AudioFormat::Aac) => self.adts(&audio),
+                _ => vec![],
+            },
+        };
+        self.interleave(frames)
+    }
+
+    fn adts(&mut self, audio: &AudioData) -> Vec<u8> {
+        if audio.is_sequence_header() {
+            self.aac = AacCoder::new();
+            if let Err(e) = self.aac.set_asc(audio.body.as_ref()) {
+                log::warn!("Invalid AAC sequence header: {:?}", e);
+            }
+            return vec![];
+        }
+        match self.aac.read_format(aac::Raw, &audio.body) {
+            Ok(Some(raw)) => self
+                .aac
+                .write_format(aac::AudioDataTransportStream, raw)
+                .unwrap_or_default(),
+            _ => vec![],
+        }
+    }
+
+    fn interleave(&mut self, audio: Vec<u8>) -> Vec<u8> {
+        let (block, due) = match &mut self.metadata {
+            Some(metadata) => metadata,
+            None => return audio,
+        };
+        let mut out = Vec::with_capacity(audio.len() + block.len());
+        let mut rest = &audio[..];
+        while rest.len() >= *due {
+            out.extend_from_slice(&rest[..*due]);
+            out.extend_from_slice(block);
+            rest = &rest[*due..];
+            *due = METAINT;
+        }
+        *due -= rest.len();
+        out.extend_from_slice(rest);
+        out
+    }
+}
+
+/// A metadata block: its length in 16 byte units, then the zero padded
+/// text.
+fn metadata(title: &str) -> Vec<u8> {
+    let title: String = title.chars().filter(|c| *c != '\'').take(200).collect();
+    let text = format!("StreamTitle='{}';", title);
+    let units = (text.len() + 15) / 16;
+    let mut block = Vec::with_capacity(1 + units * 16);
+    block.push(units as u8);
+    block.extend_from_slice(text.as_bytes());
+    block.resize(1 + units * 16, 0);
+    block
+}
@@ -0,0 +1,156 @@
+use crate::hls;
+use crate::transport::{ChannelMessage, ManagerHandle};
+use anyhow::{bail, Result};
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tokio::sync::oneshot;
+
+const SWEEP_INTERVAL: Duration = Duration::from_secs(300);
+
+struct StreamDir {
+    name: String,
+    path: PathBuf,
+    size: u64,
+    touched: SystemTime,
+}
+
+/// Removes the HLS directories of streams that are no longer published,
+/// once they have been untouched for `idle` or, oldest first, while the
+/// data directory is over its quota. Live streams are never touched.
+pub struct Janitor {
+    manager_handle: ManagerHandle,
+    data_path: PathBuf,
+    // directories under data_path owned by other services
+    reserved: Vec<PathBuf>,
+    idle: Option<Duration>,
+    quota: Option<u64>,
+}
+
+impl Janitor {
+    pub fn new(
+        manager_handle: ManagerHandle,
+        data_path: String,
+        reserved: Vec<String>,
+        idle: Option<Duration>,
+        quota: Option<u64>,
+    ) -> Self {
+        Self {
+            manager_handle,
+            data_path: PathBuf::from(data_path),
+            reserved: reserved.iter().map(|p| canonical(Path::new(p))).collect(),
+            idle,
+            quota,
+        }
+    }
+
+    pub async fn run(self) {
+        let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.sweep().await {
+                log::error!("Failed to clean up {}: {}", self.data_path.display(), e);
+            }
+        }
+    }
+
+    async fn sweep(&self) -> Result<()> {
+        let active = self.active_streams().await?;
+        let mut dirs = self.stream_dirs()?;
+        dirs.sort_by_key(|dir| dir.touched);
+
+        let mut total: u64 = dirs.iter().map(|dir| dir.size).sum();
+        let now = SystemTime::now();
+        for dir in dirs {
+            if active.contains(&dir.name) {
+                continue;
+            }
+            let untouched = now.duration_since(dir.touched).unwrap_or_default();
+            let idle = self.idle.map_or(false, |idle| untouched >= idle);
+            let over_quota = self.quota.map_or(false, |quota| total > quota);
+            if !idle && !over_quota {
+                continue;
+            }
+            match fs::remove_dir_all(&dir.path) {
+                Ok(_) => {
+                    log::info!(
+                        "Removed stream directory {} ({} bytes, untouched for {}s)",
+                        dir.path.display(),
+                        dir.size,
+                        untouched.as_secs()
+                    );
+                    total -= dir.size;
+                    hls::forget(&dir.name).await;
+                }
+                Err(e) => log::error!("Failed to remove {}: {}", dir.path.display(), e),
+            }
+        }
+        if let Some(quota) = self.quota.filter(|&quota| total > quota) {
+            log::warn!(
+                "{} uses {} bytes, over its {} byte quota with only live streams left",
+                self.data_path.display(),
+                total,
+                quota
+            );
+        }
+        Ok(())
+    }
+
+    async fn active_streams(&self) -> Result<HashSet<String>> {
+        let (request, response) = oneshot::channel();
+        if self
+            .manager_handle
+            .send(ChannelMessage::List(request))
+            .is_err()
+        {
+            bail!("Manager is gone");
+        }
+        let streams = response.await?;
+        Ok(streams.into_iter().map(|stream| stream.name).collect())
+    }
+
+    /// Directories holding nothing but segments and their sidecars.
+    fn stream_dirs(&self) -> Result<Vec<StreamDir>> {
+        let mut dirs = vec![];
+        for entry in fs::read_dir(&self.data_path)?.flatten() {
+            let path = entry.path();
+            let name = match entry.file_name().into_string() {
+                Ok(name) if path.is_dir() => name,
+                _ => continue,
+            };
+            if self.reserved.contains(&canonical(&path)) {
+                continue;
+            }
+            let mut size = 0;
+            let mut touched = entry.metadata()?.modified()?;
+            let mut segments_only = true;
+            for file in fs::read_dir(&path)?.flatten() {
+                let file_path = file.path();
+                let is_segment = file_path.extension().map_or(false, |ext| ext == "ts");
+                let is_sidecar =
+                    file.file_name() == hls::SEQUENCE_FILE || file.file_name() == hls::STATE_FILE;
+                if !is_segment && !is_sidecar {
+                    segments_only = false;
+                    break;
+                }
+                let metadata = file.metadata()?;
+                size += metadata.len();
+                touched = touched.max(metadata.modified()?);
+            }
+            if segments_only {
+                dirs.push(StreamDir {
+                    name,
+                    path,
+                    size,
+                    touched,
+                });
+            }
+        }
+        Ok(dirs)
+    }
+}
+
+fn canonical(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or_else(|_| path.to_owned())
+}
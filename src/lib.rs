@@ -2,23 +2,55 @@ mod connection;
 mod packet;
 mod rtmp;
 pub mod service;
+mod socket;
 
 mod channel;
+pub mod clock;
+pub mod dump;
 pub mod config;
 mod error;
+mod failover;
+pub mod health;
 mod manager;
+pub mod memory;
+pub mod metrics;
+pub mod plugin;
+pub mod shutdown;
+mod naming;
+mod sampling;
+pub mod testsrc;
+mod timestamp;
 pub mod transport;
 pub mod user;
+mod webhook;
 
 #[cfg(feature = "flv")]
 pub mod flv;
 
+#[cfg(feature = "http-flv")]
+mod audit;
 #[cfg(feature = "http-flv")]
 pub mod http_flv;
+#[cfg(feature = "http-flv")]
+mod icecast;
+pub mod token;
+
+#[cfg(any(feature = "http-flv", feature = "hls"))]
+mod problem;
+#[cfg(any(feature = "http-flv", feature = "hls"))]
+mod router;
 
+#[cfg(feature = "hls")]
+pub mod drm;
 #[cfg(feature = "hls")]
 pub mod hls;
 #[cfg(feature = "hls")]
+pub mod janitor;
+#[cfg(feature = "hls")]
+pub mod playlist;
+#[cfg(feature = "hls")]
+pub mod selfcheck;
+#[cfg(feature = "hls")]
 mod transport_stream;
 #[cfg(feature = "hls")]
 pub mod ts;
@@ -26,7 +58,17 @@ pub mod ts;
 #[cfg(feature = "hls")]
 pub mod mq_sender;
 
+#[cfg(feature = "mosaic")]
+pub mod mosaic;
+
+#[cfg(all(feature = "http-flv", feature = "keyframe_image"))]
+mod snapshot;
+#[cfg(feature = "keyframe_image")]
+mod thumbnails;
+
 mod codec;
+#[cfg(any(feature = "hls", feature = "keyframe_image"))]
+mod elementary;
 type Event = &'static str;
 type AppName = String;
 type StreamKey = String;
@@ -37,7 +79,10 @@ use anyhow::{bail, Result};
 
 pub use self::{
     manager::Manager,
-    transport::{trigger_channel, ChannelMessage, Handle, ManagerHandle, Message, Watcher},
+    packet::{Packet, PacketType},
+    transport::{
+        trigger_channel, ChannelMessage, Handle, ManagerHandle, Message, StreamEvent, Watcher,
+    },
 };
 
 const FLV_HEADER: [u8; 13] = [
@@ -66,6 +111,6 @@ fn prepare_stream_directory<P: AsRef<Path>>(path: P) -> Result<()> {
         );
     }
     log::debug!("Creating HLS directory at '{}'", stream_path.display());
-    fs::create_dir_all(&stream_path)?;
+    fs::create_dir_all(stream_path)?;
     Ok(())
 }
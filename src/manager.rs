@@ -1,12 +1,34 @@
 use crate::channel::Channel;
+use crate::codec::flv::VideoData;
+use crate::config;
+use crate::failover::Pair;
+use crate::metrics::DropReason;
+use crate::packet::PacketType;
+use crate::plugin::Plugin;
 use crate::transport::{
-    ChannelMessage, ChannelReceiver, Handle, ManagerHandle, OutgoingBroadcast, Trigger,
+    ChannelMessage, ChannelReceiver, Handle, ManagerHandle, Message, OutgoingBroadcast, Publisher,
+    Role, Sink, StreamEvent, StreamHealth, StreamInfo, Trigger, Watcher,
 };
 use crate::user::UserCheck;
-use crate::{AppName, Event};
+use crate::{health, metrics, naming, webhook, AppName, Event};
 use anyhow::{bail, Result};
-use std::{collections::HashMap, sync::Arc};
-use tokio::sync::{broadcast, mpsc, RwLock};
+use chrono::Utc;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::convert::TryFrom;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::{sync::Arc, time::Duration};
+use tokio::sync::{broadcast, mpsc, oneshot, RwLock};
+use tokio::task::JoinHandle;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::{Stream, StreamExt};
+
+/// Seconds without media after which a channel is reported stalled.
+const STALLED_AFTER: i64 = 10;
+/// How often channels are checked for a publisher or task that went away
+/// without a release.
+const RECONCILE_INTERVAL: Duration = Duration::from_secs(30);
+/// How often viewer counts are compared for `StreamEvent::Viewers`.
+const VIEWERS_INTERVAL: Duration = Duration::from_secs(5);
 
 pub struct Manager<D>
 where
@@ -17,15 +39,57 @@ where
     incoming: ChannelReceiver,
     channels: Arc<RwLock<HashMap<AppName, (Handle, OutgoingBroadcast)>>>,
     triggers: Arc<RwLock<HashMap<Event, Vec<Trigger>>>>,
+    // connection currently publishing each channel
+    publishers: HashMap<AppName, Publisher>,
+    // channels whose publisher left and that wait for a resume, by client id
+    releasing: HashMap<AppName, u64>,
+    // unix time of the last packet each channel received
+    activity: HashMap<AppName, Arc<AtomicI64>>,
+    // sinks that reported failing per channel
+    failing: HashMap<AppName, BTreeSet<Sink>>,
+    // relays feeding the sinks attached to each channel
+    attached: HashMap<AppName, BTreeMap<Sink, JoinHandle<()>>>,
+    // shutting down, no channels are created anymore
+    draining: bool,
+    // channels opened along with each channel, repeating its packets
+    mirrors: HashMap<AppName, Vec<AppName>>,
+    // open mirror channels and the channel they repeat
+    mirrored: HashMap<AppName, AppName>,
+    // apps whose publisher takes over another app's channel while its own
+    // publisher is away, with the app they back up
+    backups: HashMap<AppName, AppName>,
+    // publishers of the channels that have a backup
+    pairs: HashMap<AppName, Pair>,
+    // silence after which a connected primary is passed over for its backup
+    failover_after: Option<Duration>,
+    // looking at the packets of every channel, in registration order
+    plugins: Vec<Arc<dyn Plugin>>,
+    // longest continuous publish, per app and for the others
+    max_durations: HashMap<AppName, Duration>,
+    max_duration: Option<Duration>,
+    // how long before the stop the webhook is warned
+    duration_warning: Duration,
+    webhook: Option<String>,
+    events: broadcast::Sender<StreamEvent>,
+    // viewer count last reported per channel
+    viewers: HashMap<AppName, usize>,
     full_gop: bool,
     auth_enable: bool,
+    reconnect_grace: Duration,
 }
 
 impl<D> Manager<D>
 where
     D: UserCheck + 'static + Send + Sync,
 {
-    pub fn new(user_checker: Option<D>, full_gop: bool, auth_enable: bool) -> Self {
+    pub fn new(
+        user_checker: Option<D>,
+        full_gop: bool,
+        auth_enable: bool,
+        reconnect_grace: Duration,
+        apps: &HashMap<AppName, config::App>,
+        failover_after: Option<Duration>,
+    ) -> Self {
         let (handle, incoming) = mpsc::unbounded_channel();
         let channels = Arc::new(RwLock::new(HashMap::new()));
         let triggers = Arc::new(RwLock::new(HashMap::new()));
@@ -36,70 +100,495 @@ where
             incoming,
             channels,
             triggers,
+            publishers: HashMap::new(),
+            releasing: HashMap::new(),
+            activity: HashMap::new(),
+            failing: HashMap::new(),
+            attached: HashMap::new(),
+            draining: false,
+            mirrors: apps
+                .iter()
+                .filter(|(_, app)| !app.mirrors.is_empty())
+                .map(|(name, app)| (name.clone(), app.mirrors.clone()))
+                .collect(),
+            mirrored: HashMap::new(),
+            backups: apps
+                .iter()
+                .filter_map(|(name, app)| Some((app.backup.clone()?, name.clone())))
+                .collect(),
+            pairs: HashMap::new(),
+            failover_after,
+            plugins: Vec::new(),
+            max_durations: apps
+                .iter()
+                .filter_map(|(name, app)| {
+                    Some((name.clone(), Duration::from_secs(app.max_duration?)))
+                })
+                .collect(),
+            max_duration: None,
+            duration_warning: Duration::ZERO,
+            webhook: None,
+            events: broadcast::channel(256).0,
+            viewers: HashMap::new(),
             full_gop,
             auth_enable,
+            reconnect_grace,
         }
     }
 
+    /// Registers a plugin that is attached to every channel opened from
+    /// now on, mirrors included.
+    pub fn with_plugin(mut self, plugin: Arc<dyn Plugin>) -> Self {
+        self.plugins.push(plugin);
+        self
+    }
+
+    /// Stops channels published longer than `max_duration`, unless their
+    /// app sets its own, warning the webhook `warning` before.
+    pub fn with_max_duration(mut self, max_duration: Option<Duration>, warning: Duration) -> Self {
+        self.max_duration = max_duration;
+        self.duration_warning = warning;
+        self
+    }
+
+    /// Posts stream events to `webhook`.
+    pub fn with_webhook(mut self, webhook: Option<String>) -> Self {
+        self.webhook = webhook;
+        self
+    }
+
     pub fn handle(&self) -> ManagerHandle {
         self.handle.clone()
     }
 
+    /// Stream events from now on, for hosts embedding the manager. Taken
+    /// before [`run`](Self::run) consumes it; events a slow consumer falls
+    /// too far behind on are skipped.
+    pub fn events(&self) -> impl Stream<Item = StreamEvent> {
+        BroadcastStream::new(self.events.subscribe()).filter_map(|event| event.ok())
+    }
+
     async fn process_message(&mut self, message: ChannelMessage) -> Result<()> {
         match message {
-            ChannelMessage::Create((name, key, responder)) => {
+            ChannelMessage::Create((name, key, publisher, responder)) => {
+                if self.draining {
+                    bail!("Refusing to publish {}, shutting down", name);
+                }
+                naming::validate_app_name(&name)?;
+                naming::validate_stream_key(&key)?;
+                if let Some(source) = self.mirrored.get(&name) {
+                    bail!("Refusing to publish {}, it mirrors {}", name, source);
+                }
+
                 //验证用户
                 if self.auth_enable {
                     self.auth(&name, &key).await?;
                 }
 
-                let (handle, incoming) = mpsc::unbounded_channel();
-                let (outgoing, _watcher) = broadcast::channel(64);
-                let mut sessions = self.channels.write().await;
-                sessions.insert(name.clone(), (handle.clone(), outgoing.clone()));
-
-                let triggers = self.triggers.read().await;
-                if let Some(event_triggers) = triggers.get("create_session") {
-                    for trigger in event_triggers {
-                        trigger.send((name.clone(), outgoing.subscribe()))?;
+                if let Some((name, role)) = self.pair_role(&name) {
+                    let handle = self.join_pair(name, role, publisher).await;
+                    if responder.send(handle).is_err() {
+                        bail!("Failed to send response");
                     }
+                    return Ok(());
                 }
 
-                let full_gop = self.full_gop;
-                let name_copy = name.clone();
-                tokio::spawn(async move {
-                    Channel::new(name_copy, incoming, outgoing, full_gop)
-                        .run()
-                        .await;
-                });
+                if self.releasing.remove(&name).is_some() {
+                    match publisher.resume {
+                        Some(timestamp) => {
+                            let sessions = self.channels.read().await;
+                            if let Some((handle, _)) = sessions.get(&name) {
+                                log::info!("Resuming channel {} from {}", name, timestamp);
+                                if handle.send(Message::Resume(timestamp)).is_err() {
+                                    bail!("Failed to resume channel {}", name);
+                                }
+                                self.publishers.insert(name, publisher);
+                                if responder.send(handle.clone()).is_err() {
+                                    bail!("Failed to send response");
+                                }
+                                return Ok(());
+                            }
+                        }
+                        None => self.close_channel(&name).await,
+                    }
+                } else if self.publishers.contains_key(&name) {
+                    // the release of the replaced publisher is ignored, so
+                    // its channel and the sinks fed by it are stopped here
+                    log::warn!("{} is published again, closing its previous channel", name);
+                    self.close_channel(&name).await;
+                }
 
-                if let Err(_) = responder.send(handle) {
+                let handle = self.open_mirrored(&name, publisher).await;
+                if responder.send(handle).is_err() {
                     bail!("Failed to send response");
                 }
             }
             ChannelMessage::Join((name, responder)) => {
                 let sessions = self.channels.read().await;
                 if let Some((handle, watcher)) = sessions.get(&name) {
-                    if let Err(_) = responder.send((handle.clone(), watcher.subscribe())) {
+                    if responder
+                        .send((handle.clone(), watcher.subscribe()))
+                        .is_err()
+                    {
                         bail!("Failed to send response");
                     }
                 }
             }
-            ChannelMessage::Release(name) => {
-                let mut sessions = self.channels.write().await;
-                sessions.remove(&name);
+            ChannelMessage::Release((name, client_id)) => {
+                let name = match self.pair_role(&name) {
+                    Some((name, role)) => {
+                        let pair = match self.pairs.get_mut(&name) {
+                            Some(pair) => pair,
+                            None => return Ok(()),
+                        };
+                        if !pair.leave(role, client_id) {
+                            return Ok(());
+                        }
+                        if !pair.is_empty() {
+                            self.switch(&name);
+                            return Ok(());
+                        }
+                        // the last one left, released like any other publisher
+                        self.pairs.remove(&name);
+                        name
+                    }
+                    None => name,
+                };
+                // a late release from a publisher that was already replaced
+                if self.publishers.get(&name).map(|p| p.client_id) != Some(client_id) {
+                    return Ok(());
+                }
+
+                if self.reconnect_grace.is_zero() {
+                    self.close_channel(&name).await;
+                } else {
+                    self.releasing.insert(name.clone(), client_id);
+                    let handle = self.handle.clone();
+                    let grace = self.reconnect_grace;
+                    tokio::spawn(async move {
+                        tokio::time::sleep(grace).await;
+                        _ = handle.send(ChannelMessage::Expire((name, client_id)));
+                    });
+                }
+            }
+            ChannelMessage::Expire((name, client_id)) => {
+                if self.releasing.get(&name) == Some(&client_id) {
+                    log::info!("Channel {} was not resumed, closing", name);
+                    self.releasing.remove(&name);
+                    self.close_channel(&name).await;
+                }
             }
             ChannelMessage::RegisterTrigger(event, trigger) => {
                 log::debug!("Registering trigger for {}", event);
                 let mut triggers = self.triggers.write().await;
-                triggers.entry(event).or_insert_with(Vec::new).push(trigger);
+                let event_triggers = triggers.entry(event).or_insert_with(Vec::new);
+                let registered = event_triggers.len();
+                event_triggers.retain(|trigger| !trigger.is_closed());
+                prune_closed(registered - event_triggers.len());
+                event_triggers.push(trigger);
+                count_triggers(&triggers);
+            }
+            ChannelMessage::DeregisterTrigger(event, trigger) => {
+                log::debug!("Deregistering trigger for {}", event);
+                let mut triggers = self.triggers.write().await;
+                if let Some(event_triggers) = triggers.get_mut(event) {
+                    event_triggers.retain(|t| !t.same_channel(&trigger));
+                }
+                count_triggers(&triggers);
+            }
+            ChannelMessage::List(responder) => {
+                let sessions = self.channels.read().await;
+                let streams = self
+                    .publishers
+                    .iter()
+                    .map(|(name, publisher)| StreamInfo {
+                        name: name.clone(),
+                        client_id: publisher.client_id,
+                        addr: publisher.addr,
+                        subscribers: sessions
+                            .get(name)
+                            .map_or(0, |(_, outgoing)| outgoing.receiver_count()),
+                        resuming: self.releasing.contains_key(name),
+                        health: self.health(name, sessions.get(name).map(|(handle, _)| handle)),
+                        source: self
+                            .pairs
+                            .get(name)
+                            .and_then(|pair| pair.active())
+                            .map(|(role, _)| role),
+                        sinks: self.sinks(name),
+                        drops: metrics::drops(name),
+                    })
+                    .collect();
+                if responder.send(streams).is_err() {
+                    bail!("Failed to send response");
+                }
+            }
+            ChannelMessage::CloseAll(responder) => {
+                self.draining = true;
+                self.releasing.clear();
+                let names: Vec<_> = self.publishers.keys().cloned().collect();
+                for name in names {
+                    self.close_channel(&name).await;
+                }
+                _ = responder.send(());
+            }
+            ChannelMessage::Reconcile => self.reconcile().await,
+            ChannelMessage::Overtime(name, channel) => {
+                let current = self
+                    .channels
+                    .read()
+                    .await
+                    .get(&name)
+                    .map_or(false, |(handle, _)| handle.same_channel(&channel));
+                if current {
+                    log::warn!("Stopping {}, it reached its maximum duration", name);
+                    self.close_channel(&name).await;
+                    self.notify(serde_json::json!({
+                        "event": "duration_exceeded",
+                        "app": name,
+                    }));
+                }
+            }
+            ChannelMessage::CountViewers => {
+                if self.events.receiver_count() == 0 {
+                    return Ok(());
+                }
+                let sessions = self.channels.read().await;
+                for (name, (_, outgoing)) in sessions.iter() {
+                    let count = outgoing.receiver_count();
+                    if self.viewers.insert(name.clone(), count) != Some(count) {
+                        _ = self.events.send(StreamEvent::Viewers {
+                            app: name.clone(),
+                            count,
+                        });
+                    }
+                }
+            }
+            ChannelMessage::SegmentWritten(app, segment) => {
+                _ = self
+                    .events
+                    .send(StreamEvent::SegmentWritten { app, segment });
+            }
+            ChannelMessage::CheckSources => {
+                let names: Vec<_> = self.pairs.keys().cloned().collect();
+                for name in names {
+                    self.switch(&name);
+                }
+            }
+            ChannelMessage::SinkFailing(name, sink, failing) => {
+                // a late report from a sink of a channel that already closed
+                if !self.publishers.contains_key(&name) {
+                    return Ok(());
+                }
+                let sinks = self.failing.entry(name.clone()).or_default();
+                match failing {
+                    true if sinks.insert(sink) => {
+                        log::warn!("{:?} sink of {} is failing", sink, name)
+                    }
+                    false if sinks.remove(&sink) => {
+                        log::info!("{:?} sink of {} recovered", sink, name)
+                    }
+                    _ => {}
+                }
+            }
+            ChannelMessage::SetSink(name, sink, enabled, responder) => {
+                let session = self.channels.read().await.get(&name).cloned();
+                let (handle, outgoing) = match session {
+                    Some(session) => session,
+                    None => {
+                        _ = responder.send(None);
+                        return Ok(());
+                    }
+                };
+                let attached = self
+                    .attached
+                    .get(&name)
+                    .map_or(false, |sinks| sinks.contains_key(&sink));
+                match enabled {
+                    true if !attached => {
+                        log::info!("Attaching {:?} sink to {}", sink, name);
+                        self.attach(&name, sink, &handle, &outgoing).await;
+                    }
+                    false if attached => {
+                        log::info!("Detaching {:?} sink from {}", sink, name);
+                        if let Some(relay) = self
+                            .attached
+                            .get_mut(&name)
+                            .and_then(|sinks| sinks.remove(&sink))
+                        {
+                            // the sink sees the channel end and finishes its output
+                            relay.abort();
+                        }
+                        if let Some(sinks) = self.failing.get_mut(&name) {
+                            sinks.remove(&sink);
+                        }
+                    }
+                    _ => {}
+                }
+                if responder.send(Some(self.sinks(&name))).is_err() {
+                    bail!("Failed to send response");
+                }
             }
         }
 
         Ok(())
     }
 
+    /// Cleans up channels whose task stopped on its own, and releases the
+    /// ones whose publisher went away without releasing them, as if it had.
+    /// Pairs are left to failover, which notices a silent publisher.
+    async fn reconcile(&mut self) {
+        let sessions = self.channels.read().await;
+        let mut stopped = vec![];
+        for (name, publisher) in &self.publishers {
+            if self.mirrored.contains_key(name) || self.releasing.contains_key(name) {
+                continue;
+            }
+            if sessions
+                .get(name)
+                .map_or(true, |(handle, _)| handle.is_closed())
+            {
+                stopped.push(name.clone());
+            } else if publisher.gone() && !self.pairs.contains_key(name) {
+                log::warn!("Publisher of {} went away without releasing it", name);
+                metrics::CHANNELS_RECLAIMED.inc();
+                let release = ChannelMessage::Release((name.clone(), publisher.client_id));
+                _ = self.handle.send(release);
+            }
+        }
+        drop(sessions);
+        for name in stopped {
+            log::warn!("Channel {} stopped without being closed, cleaning up", name);
+            metrics::CHANNELS_RECLAIMED.inc();
+            self.close_channel(&name).await;
+        }
+    }
+
+    /// Warns the webhook and then stops the channel once it reaches the
+    /// maximum duration of its app, unless it closed before.
+    fn limit_duration(&self, name: &AppName, channel: &Handle) {
+        let max_duration = self
+            .max_durations
+            .get(name)
+            .copied()
+            .or(self.max_duration)
+            .filter(|d| !d.is_zero());
+        let max_duration = match max_duration {
+            Some(max_duration) => max_duration,
+            None => return,
+        };
+        let warning = self.duration_warning.min(max_duration);
+        let manager = self.handle.clone();
+        let webhook = self.webhook.clone();
+        let channel = channel.clone();
+        let name = name.clone();
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = channel.closed() => return,
+                _ = tokio::time::sleep(max_duration - warning) => {}
+            }
+            if !warning.is_zero() {
+                log::info!("{} stops in {:?}, its maximum duration", name, warning);
+                if let Some(url) = webhook {
+                    let event = serde_json::json!({
+                        "event": "duration_warning",
+                        "app": name,
+                        "stops_in": warning.as_secs(),
+                    });
+                    tokio::spawn(webhook::post(url, event));
+                }
+                tokio::select! {
+                    _ = channel.closed() => return,
+                    _ = tokio::time::sleep(warning) => {}
+                }
+            }
+            _ = manager.send(ChannelMessage::Overtime(name, channel));
+        });
+    }
+
+    fn notify(&self, event: serde_json::Value) {
+        if let Some(url) = &self.webhook {
+            tokio::spawn(webhook::post(url.clone(), event));
+        }
+    }
+
+    fn sinks(&self, name: &str) -> Vec<Sink> {
+        self.attached
+            .get(name)
+            .map_or_else(Vec::new, |sinks| sinks.keys().copied().collect())
+    }
+
+    /// Hands a channel to the sink's triggers through a relay, which is
+    /// aborted to detach the sink again. Sinks that are not running are
+    /// skipped.
+    async fn attach(
+        &mut self,
+        name: &AppName,
+        sink: Sink,
+        handle: &Handle,
+        outgoing: &OutgoingBroadcast,
+    ) {
+        let (relayed, _) = broadcast::channel(64);
+        let mut triggers = self.triggers.write().await;
+        if let Some(sink_triggers) = triggers.get_mut(sink.event()) {
+            let registered = sink_triggers.len();
+            sink_triggers
+                .retain(|trigger| trigger.send((name.clone(), relayed.subscribe())).is_ok());
+            prune_closed(registered - sink_triggers.len());
+        }
+        count_triggers(&triggers);
+        drop(triggers);
+        if relayed.receiver_count() == 0 {
+            return;
+        }
+        let relay = tokio::spawn(relay(
+            name.clone(),
+            handle.clone(),
+            outgoing.subscribe(),
+            relayed,
+        ));
+        self.attached
+            .entry(name.clone())
+            .or_default()
+            .insert(sink, relay);
+    }
+
     pub async fn run(mut self) {
+        let _running = health::manager_running();
+        if self.failover_after.is_some() && !self.backups.is_empty() {
+            let handle = self.handle.clone();
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(Duration::from_secs(1));
+                loop {
+                    interval.tick().await;
+                    if handle.send(ChannelMessage::CheckSources).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        let handle = self.handle.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(RECONCILE_INTERVAL);
+            // the first tick completes at once, nothing can have leaked yet
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                if handle.send(ChannelMessage::Reconcile).is_err() {
+                    break;
+                }
+            }
+        });
+        let handle = self.handle.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(VIEWERS_INTERVAL);
+            loop {
+                interval.tick().await;
+                if handle.send(ChannelMessage::CountViewers).is_err() {
+                    break;
+                }
+            }
+        });
         while let Some(message) = self.incoming.recv().await {
             if let Err(err) = self.process_message(message).await {
                 log::error!("{}", err);
@@ -107,6 +596,183 @@ where
         }
     }
 
+    fn health(&self, name: &str, handle: Option<&Handle>) -> StreamHealth {
+        if handle.map_or(true, |handle| handle.is_closed()) {
+            return StreamHealth::Closed;
+        }
+        let idle = self.activity.get(name).map_or(0, |last| {
+            Utc::now().timestamp() - last.load(Ordering::Relaxed)
+        });
+        if self.releasing.contains_key(name) || idle > STALLED_AFTER {
+            return StreamHealth::Stalled;
+        }
+        match self.failing.get(name) {
+            Some(sinks) if !sinks.is_empty() => StreamHealth::Degraded {
+                sinks: sinks.iter().copied().collect(),
+            },
+            _ => StreamHealth::Live,
+        }
+    }
+
+    /// Starts a channel and the mirrors configured for it, returns the
+    /// handle to publish into.
+    async fn open_mirrored(&mut self, name: &AppName, publisher: Publisher) -> Handle {
+        let (handle, outgoing) = self.open_channel(name, publisher.clone()).await;
+        self.limit_duration(name, &handle);
+        for mirror in self.mirrors.get(name).cloned().unwrap_or_default() {
+            if self.publishers.contains_key(&mirror) {
+                log::warn!("Not mirroring {} to {}, it is already live", name, mirror);
+                continue;
+            }
+            let (mirror_handle, _) = self.open_channel(&mirror, publisher.clone()).await;
+            self.mirrored.insert(mirror, name.clone());
+            tokio::spawn(forward(outgoing.subscribe(), mirror_handle));
+        }
+        handle
+    }
+
+    /// The channel `name` publishes into and its role there, if that
+    /// channel has a backup.
+    fn pair_role(&self, name: &str) -> Option<(AppName, Role)> {
+        match self.backups.get(name) {
+            Some(primary) => Some((primary.clone(), Role::Backup)),
+            None if self.backups.values().any(|primary| primary == name) => {
+                Some((name.to_owned(), Role::Primary))
+            }
+            None => None,
+        }
+    }
+
+    /// Adds a publisher to the pair of `name`, opening the channel for the
+    /// first one. Returns the handle to publish into.
+    async fn join_pair(&mut self, name: AppName, role: Role, publisher: Publisher) -> Handle {
+        // whoever publishes first opens the channel, a waiting one is kept
+        self.releasing.remove(&name);
+        let open = self
+            .channels
+            .read()
+            .await
+            .get(&name)
+            .map(|(h, _)| h.clone());
+        let channel = match open {
+            Some(channel) => channel,
+            None => self.open_mirrored(&name, publisher.clone()).await,
+        };
+        let failover_after = self.failover_after;
+        let handle = self
+            .pairs
+            .entry(name.clone())
+            .or_insert_with(|| Pair::new(failover_after))
+            .join(role, publisher, channel);
+        self.switch(&name);
+        handle
+    }
+
+    /// Lets the preferred publisher of a pair feed its channel.
+    fn switch(&mut self, name: &str) {
+        let pair = match self.pairs.get_mut(name) {
+            Some(pair) => pair,
+            None => return,
+        };
+        let previous = pair.role();
+        match (previous, pair.switch()) {
+            (Some(previous), Some(role)) => {
+                log::warn!(
+                    "{} switched from its {:?} to its {:?} publisher",
+                    name,
+                    previous,
+                    role
+                );
+                metrics::SOURCE_SWITCHES.inc();
+            }
+            (None, Some(role)) => log::info!("{} is fed by its {:?} publisher", name, role),
+            _ => {}
+        }
+        if let Some((_, publisher)) = pair.active() {
+            self.publishers.insert(name.to_owned(), publisher.clone());
+        }
+    }
+
+    /// Starts a channel and tells the sinks about it.
+    async fn open_channel(
+        &mut self,
+        name: &AppName,
+        publisher: Publisher,
+    ) -> (Handle, OutgoingBroadcast) {
+        let (handle, incoming) = mpsc::unbounded_channel();
+        let (outgoing, _watcher) = broadcast::channel(64);
+        let mut sessions = self.channels.write().await;
+        sessions.insert(name.clone(), (handle.clone(), outgoing.clone()));
+        drop(sessions);
+        self.publishers.insert(name.clone(), publisher);
+
+        let mut triggers = self.triggers.write().await;
+        if let Some(event_triggers) = triggers.get_mut("create_session") {
+            // a sink that stopped leaves a closed trigger behind
+            let registered = event_triggers.len();
+            event_triggers
+                .retain(|trigger| trigger.send((name.clone(), outgoing.subscribe())).is_ok());
+            prune_closed(registered - event_triggers.len());
+        }
+        count_triggers(&triggers);
+        drop(triggers);
+
+        let activity = Arc::new(AtomicI64::new(Utc::now().timestamp()));
+        self.activity.insert(name.clone(), activity.clone());
+        self.failing.remove(name);
+
+        let inspectors = self
+            .plugins
+            .iter()
+            .filter_map(|plugin| plugin.attach(name))
+            .collect();
+        let full_gop = self.full_gop;
+        let channel = Channel::new(
+            name.clone(),
+            incoming,
+            outgoing.clone(),
+            full_gop,
+            activity,
+            inspectors,
+        );
+        tokio::spawn(channel.run());
+        for sink in Sink::ALL {
+            self.attach(name, sink, &handle, &outgoing).await;
+        }
+        _ = self
+            .events
+            .send(StreamEvent::Published { app: name.clone() });
+        (handle, outgoing)
+    }
+
+    /// Closes a channel along with its mirrors.
+    async fn close_channel(&mut self, name: &str) {
+        let mut names = vec![name.to_owned()];
+        self.mirrored.retain(|mirror, source| {
+            if source == name {
+                names.push(mirror.clone());
+            }
+            source != name
+        });
+        let mut sessions = self.channels.write().await;
+        for name in &names {
+            self.pairs.remove(name);
+            self.publishers.remove(name);
+            self.activity.remove(name);
+            self.failing.remove(name);
+            // the relays run on until the channel ends, the sinks finish then
+            self.attached.remove(name);
+            metrics::forget_drops(name);
+            self.viewers.remove(name);
+            if let Some((handle, _)) = sessions.remove(name) {
+                _ = handle.send(Message::Disconnect);
+                _ = self
+                    .events
+                    .send(StreamEvent::Unpublished { app: name.clone() });
+            }
+        }
+    }
+
     async fn auth(&self, name: &str, key: &str) -> Result<()> {
         if let Some(checker) = &self.user_checker {
             if key.is_empty() {
@@ -122,3 +788,125 @@ where
         Ok(())
     }
 }
+
+/// Repeats the packets of a channel into one of its mirrors until either
+/// of them closes.
+async fn forward(mut watcher: Watcher, mirror: Handle) {
+    use tokio::sync::broadcast::error::RecvError;
+    loop {
+        match watcher.recv().await {
+            Ok(packet) => {
+                if mirror.send(Message::Packet((*packet).clone())).is_err() {
+                    break;
+                }
+            }
+            Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => break,
+        }
+    }
+}
+
+/// Feeds a sink from a channel until either of them goes away. A sink
+/// attached to a running channel is sent the metadata and sequence headers
+/// first, its video starts at the next keyframe.
+async fn relay(name: AppName, channel: Handle, mut watcher: Watcher, sink: OutgoingBroadcast) {
+    use tokio::sync::broadcast::error::RecvError;
+    let (request, response) = oneshot::channel();
+    if channel.send(Message::InitData(request)).is_err() {
+        return;
+    }
+    let (meta, video, audio, _) = match response.await {
+        Ok(init) => init,
+        Err(_) => return,
+    };
+    for packet in vec![meta, video, audio].into_iter().flatten() {
+        if sink.send(packet).is_err() {
+            return;
+        }
+    }
+    let mut started = false;
+    loop {
+        let packet = match watcher.recv().await {
+            Ok(packet) => packet,
+            Err(RecvError::Lagged(skipped)) => {
+                metrics::dropped(&name, DropReason::Lag, skipped);
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+        if !started && matches!(packet.kind, PacketType::Video) {
+            match VideoData::try_from(&packet.payload[..]) {
+                Ok(video) if video.is_sequence_header() => {}
+                Ok(video) if video.is_keyframe() => started = true,
+                Ok(_) => {
+                    metrics::dropped(&name, DropReason::Keyframe, 1);
+                    continue;
+                }
+                Err(_) => {
+                    metrics::dropped(&name, DropReason::Parse, 1);
+                    continue;
+                }
+            }
+        }
+        if sink.send(packet).is_err() {
+            break;
+        }
+    }
+}
+
+fn prune_closed(pruned: usize) {
+    if pruned > 0 {
+        log::info!("Removed {} closed trigger(s)", pruned);
+        metrics::TRIGGERS_PRUNED.add(pruned as u64);
+    }
+}
+
+fn count_triggers(triggers: &HashMap<Event, Vec<Trigger>>) {
+    metrics::TRIGGERS.set(triggers.values().map(Vec::len).sum::<usize>() as u64);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::user::Redis;
+
+    fn publisher(client_id: u64) -> Publisher {
+        Publisher {
+            client_id,
+            addr: ([127, 0, 0, 1], 1935).into(),
+            resume: None,
+            presence: Arc::downgrade(&Arc::new(())),
+        }
+    }
+
+    async fn create(manager: &ManagerHandle, client_id: u64) -> Handle {
+        let (responder, handle) = oneshot::channel();
+        let message = ChannelMessage::Create((
+            "live".to_owned(),
+            "key".to_owned(),
+            publisher(client_id),
+            responder,
+        ));
+        assert!(manager.send(message).is_ok());
+        handle.await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn publishing_again_stops_the_replaced_channel() {
+        let manager =
+            Manager::<Redis>::new(None, false, false, Duration::ZERO, &HashMap::new(), None);
+        let handle = manager.handle();
+        tokio::spawn(manager.run());
+
+        let replaced = create(&handle, 1).await;
+        let current = create(&handle, 2).await;
+        tokio::time::timeout(Duration::from_secs(1), replaced.closed())
+            .await
+            .expect("the replaced channel task is still running");
+        // the late release of the replaced publisher leaves the new one alone
+        let release = ChannelMessage::Release(("live".to_owned(), 1));
+        assert!(handle.send(release).is_ok());
+        tokio::task::yield_now().await;
+        assert!(!current.is_closed());
+    }
+}
@@ -0,0 +1,40 @@
+use crate::metrics::{GOP_CACHE_BYTES, MEMORY_LIMIT_BYTES};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+static CHANNELS: AtomicU64 = AtomicU64::new(0);
+
+/// Sets the budget in bytes for the GOP caches, 0 leaves them unbounded.
+/// Only GOP caches grow with a stream, HLS segments are written to disk and
+/// the broadcast rings of the channels have a fixed capacity, so the rings
+/// are not counted against it.
+pub fn set_limit(bytes: u64) {
+    MEMORY_LIMIT_BYTES.set(bytes);
+}
+
+pub(crate) fn register() {
+    CHANNELS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub(crate) fn unregister() {
+    CHANNELS.fetch_sub(1, Ordering::Relaxed);
+}
+
+pub(crate) fn charge(bytes: usize) {
+    GOP_CACHE_BYTES.add(bytes as u64);
+}
+
+pub(crate) fn release(bytes: usize) {
+    GOP_CACHE_BYTES.sub(bytes as u64);
+}
+
+/// Whether a channel caching `held` bytes should give its cache up rather
+/// than add `bytes` more. Once over budget, channels holding more than an
+/// even share of it go first, so one long or high bitrate GOP can't starve
+/// the others.
+pub(crate) fn over_share(held: usize, bytes: usize) -> bool {
+    let limit = MEMORY_LIMIT_BYTES.get();
+    if limit == 0 || GOP_CACHE_BYTES.get() + bytes as u64 <= limit {
+        return false;
+    }
+    (held + bytes) as u64 > limit / CHANNELS.load(Ordering::Relaxed).max(1)
+}
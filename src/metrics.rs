@@ -0,0 +1,227 @@
+use lazy_static::lazy_static;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// A monotonically increasing process wide counter.
+pub struct Counter {
+    name: &'static str,
+    help: &'static str,
+    value: AtomicU64,
+}
+
+impl Counter {
+    pub const fn new(name: &'static str, help: &'static str) -> Self {
+        Self {
+            name,
+            help,
+            value: AtomicU64::new(0),
+        }
+    }
+
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.value.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.value.load(Ordering::Relaxed)
+    }
+}
+
+/// A process wide value that can go up and down.
+pub struct Gauge {
+    name: &'static str,
+    help: &'static str,
+    value: AtomicU64,
+}
+
+impl Gauge {
+    pub const fn new(name: &'static str, help: &'static str) -> Self {
+        Self {
+            name,
+            help,
+            value: AtomicU64::new(0),
+        }
+    }
+
+    pub fn add(&self, n: u64) {
+        self.value.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn sub(&self, n: u64) {
+        self.value.fetch_sub(n, Ordering::Relaxed);
+    }
+
+    pub fn set(&self, n: u64) {
+        self.value.store(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.value.load(Ordering::Relaxed)
+    }
+}
+
+pub static SEGMENT_WRITE_ERRORS: Counter = Counter::new(
+    "xlive_hls_segment_write_errors_total",
+    "TS segments that failed to be written and were not advertised",
+);
+pub static SEGMENTS_GONE: Counter = Counter::new(
+    "xlive_hls_segments_gone_total",
+    "Segment requests answered 410 because the segment rotated out",
+);
+pub static NOT_FOUND: Counter = Counter::new(
+    "xlive_hls_not_found_total",
+    "Playlist or segment requests for unknown streams or files",
+);
+pub static STREAM_PARAMETER_CHANGES: Counter = Counter::new(
+    "xlive_stream_parameter_changes_total",
+    "New audio or video sequence headers received mid-stream",
+);
+
+pub static GOP_CACHE_EVICTIONS: Counter = Counter::new(
+    "xlive_gop_cache_evictions_total",
+    "GOP caches dropped or not started because of the memory limit",
+);
+pub static TRIGGERS_PRUNED: Counter = Counter::new(
+    "xlive_triggers_pruned_total",
+    "Session triggers removed because their sink stopped",
+);
+pub static SOURCE_SWITCHES: Counter = Counter::new(
+    "xlive_source_switches_total",
+    "Channels that switched between their primary and backup publisher",
+);
+pub static PLUGIN_DROPS: Counter = Counter::new(
+    "xlive_plugin_drops_total",
+    "Packets vetoed by a plugin",
+);
+pub static CHANNELS_RECLAIMED: Counter = Counter::new(
+    "xlive_channels_reclaimed_total",
+    "Channels cleaned up after their publisher or task went away without a release",
+);
+
+pub static GOP_CACHE_BYTES: Gauge = Gauge::new(
+    "xlive_gop_cache_bytes",
+    "Payload bytes held in the GOP caches of all channels",
+);
+pub static MEMORY_LIMIT_BYTES: Gauge = Gauge::new(
+    "xlive_memory_limit_bytes",
+    "Configured limit for cached media, 0 if unlimited",
+);
+pub static TRIGGERS: Gauge = Gauge::new(
+    "xlive_triggers",
+    "Sinks registered to be told about new channels",
+);
+pub static CLOCK_DRIFT_MS: Gauge = Gauge::new(
+    "xlive_clock_drift_ms",
+    "Milliseconds the system clock was last measured off NTP time",
+);
+
+static COUNTERS: [&Counter; 9] = [
+    &SEGMENT_WRITE_ERRORS,
+    &SEGMENTS_GONE,
+    &NOT_FOUND,
+    &STREAM_PARAMETER_CHANGES,
+    &GOP_CACHE_EVICTIONS,
+    &TRIGGERS_PRUNED,
+    &SOURCE_SWITCHES,
+    &PLUGIN_DROPS,
+    &CHANNELS_RECLAIMED,
+];
+
+static GAUGES: [&Gauge; 4] = [
+    &GOP_CACHE_BYTES,
+    &MEMORY_LIMIT_BYTES,
+    &TRIGGERS,
+    &CLOCK_DRIFT_MS,
+];
+
+/// Why a packet did not reach a watcher or sink.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DropReason {
+    /// The watcher fell behind the channel and missed it.
+    Lag,
+    /// It could not be parsed or muxed.
+    Parse,
+    /// A plugin vetoed it.
+    Policy,
+    /// Video held back until the next keyframe, undecodable without it.
+    Keyframe,
+}
+
+impl DropReason {
+    pub(crate) fn label(self) -> &'static str {
+        match self {
+            DropReason::Lag => "lag",
+            DropReason::Parse => "parse",
+            DropReason::Policy => "policy",
+            DropReason::Keyframe => "keyframe",
+        }
+    }
+}
+
+const PACKETS_DROPPED: &str = "xlive_packets_dropped_total";
+
+lazy_static! {
+    // dropped packets by stream and reason, for streams seen since they opened
+    static ref DROPS: Mutex<BTreeMap<(String, DropReason), u64>> = Mutex::new(BTreeMap::new());
+}
+
+/// Counts `n` packets of `stream` dropped for `reason`.
+pub fn dropped(stream: &str, reason: DropReason, n: u64) {
+    let mut drops = DROPS.lock().unwrap();
+    *drops.entry((stream.to_owned(), reason)).or_insert(0) += n;
+}
+
+/// Packets of `stream` dropped by reason since it opened.
+pub fn drops(stream: &str) -> BTreeMap<DropReason, u64> {
+    let drops = DROPS.lock().unwrap();
+    drops
+        .iter()
+        .filter(|((name, _), _)| name == stream)
+        .map(|((_, reason), n)| (*reason, *n))
+        .collect()
+}
+
+/// Drops the counts of a stream that closed.
+pub fn forget_drops(stream: &str) {
+    DROPS.lock().unwrap().retain(|(name, _), _| name != stream);
+}
+
+/// Renders all counters and gauges in the Prometheus text exposition format.
+pub fn render() -> String {
+    let mut out = String::new();
+    for counter in COUNTERS.iter() {
+        _ = writeln!(out, "# HELP {} {}", counter.name, counter.help);
+        _ = writeln!(out, "# TYPE {} counter", counter.name);
+        _ = writeln!(out, "{} {}", counter.name, counter.get());
+    }
+    for gauge in GAUGES.iter() {
+        _ = writeln!(out, "# HELP {} {}", gauge.name, gauge.help);
+        _ = writeln!(out, "# TYPE {} gauge", gauge.name);
+        _ = writeln!(out, "{} {}", gauge.name, gauge.get());
+    }
+    _ = writeln!(
+        out,
+        "# HELP {} Packets that did not reach a watcher or sink, by stream and reason",
+        PACKETS_DROPPED
+    );
+    _ = writeln!(out, "# TYPE {} counter", PACKETS_DROPPED);
+    for ((stream, reason), n) in DROPS.lock().unwrap().iter() {
+        _ = writeln!(
+            out,
+            "{}{{stream=\"{}\",reason=\"{}\"}} {}",
+            PACKETS_DROPPED,
+            stream,
+            reason.label(),
+            n
+        );
+    }
+    out
+}
@@ -0,0 +1,86 @@
+use crate::config;
+use crate::transport::{ChannelMessage, ManagerHandle};
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::process::Command;
+use tokio::sync::oneshot;
+
+/// How often a mosaic checks whether its sources are live, also the pause
+/// before ffmpeg is started again.
+const RETRY: Duration = Duration::from_secs(5);
+
+/// Publishes the sources of `mosaic` as a grid into channel `name` while
+/// all of them are live. An ffmpeg subprocess plays them from the RTMP
+/// listener at `rtmp_port` and publishes the grid back to it, so the
+/// mosaic is a channel like any other.
+pub async fn run(manager: ManagerHandle, name: String, mosaic: config::Mosaic, rtmp_port: i32) {
+    loop {
+        tokio::time::sleep(RETRY).await;
+        match all_live(&manager, &mosaic.sources).await {
+            Some(true) => {}
+            Some(false) => continue,
+            None => break,
+        }
+        log::info!("Composing {} from {}", name, mosaic.sources.join(", "));
+        match command(&name, &mosaic, rtmp_port).status().await {
+            Ok(status) => log::info!("Mosaic {} stopped, ffmpeg {}", name, status),
+            Err(e) => log::error!("Failed to run ffmpeg for mosaic {}: {}", name, e),
+        }
+    }
+}
+
+/// Whether every source is published, `None` once the manager stopped.
+async fn all_live(manager: &ManagerHandle, sources: &[String]) -> Option<bool> {
+    let (request, response) = oneshot::channel();
+    manager.send(ChannelMessage::List(request)).ok()?;
+    let streams = response.await.ok()?;
+    Some(
+        sources
+            .iter()
+            .all(|source| streams.iter().any(|stream| &stream.name == source)),
+    )
+}
+
+fn command(name: &str, mosaic: &config::Mosaic, rtmp_port: i32) -> Command {
+    let (width, height) = (mosaic.tile_width, mosaic.tile_height);
+    let columns = mosaic.columns();
+    let mut command = Command::new(&mosaic.ffmpeg);
+    command.args(["-hide_banner", "-loglevel", "error"]);
+    for source in &mosaic.sources {
+        let url = format!("rtmp://127.0.0.1:{}/{}", rtmp_port, source);
+        command.args(["-i", &url]);
+    }
+    let mut filter = String::new();
+    let mut layout = vec![];
+    for i in 0..mosaic.sources.len() {
+        filter.push_str(&format!(
+            "[{}:v]scale={}:{},setsar=1[v{}];",
+            i, width, height, i
+        ));
+        let (x, y) = (i % columns, i / columns);
+        layout.push(format!("{}_{}", x * width as usize, y * height as usize));
+    }
+    for i in 0..mosaic.sources.len() {
+        filter.push_str(&format!("[v{}]", i));
+    }
+    filter.push_str(&format!(
+        "xstack=inputs={}:layout={}:fill=black[out]",
+        mosaic.sources.len(),
+        layout.join("|")
+    ));
+    let output = format!("rtmp://127.0.0.1:{}/{}/{}", rtmp_port, name, mosaic.key);
+    command
+        .args(["-filter_complex", &filter, "-map", "[out]", "-an"])
+        .args([
+            "-c:v",
+            "libx264",
+            "-preset",
+            "veryfast",
+            "-tune",
+            "zerolatency",
+        ])
+        .args(["-g", "50", "-f", "flv", &output])
+        .stdin(Stdio::null())
+        .kill_on_drop(true);
+    command
+}
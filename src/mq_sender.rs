@@ -15,7 +15,7 @@ impl Sender for Redis {
                 .arg("lpush")
                 .arg(key)
                 .arg(data)
-                .query(&mut conn)?;
+                .query::<()>(&mut conn)?;
             return Ok(());
         }
         bail!("redis connect err")
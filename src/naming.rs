@@ -0,0 +1,45 @@
+use crate::error::Error;
+
+pub const MAX_APP_NAME_LEN: usize = 64;
+pub const MAX_STREAM_KEY_LEN: usize = 256;
+
+/// App names become directory and file names (recordings, HLS segments,
+/// thumbnails) and URL path segments, so only letters, digits, `-` and `_`
+/// are accepted.
+pub fn validate_app_name(name: &str) -> Result<(), Error> {
+    if name.is_empty() {
+        return Err(Error::EmptyAppName);
+    }
+    if name.len() > MAX_APP_NAME_LEN {
+        return Err(Error::InvalidAppName(format!(
+            "longer than {} bytes",
+            MAX_APP_NAME_LEN
+        )));
+    }
+    if !name
+        .bytes()
+        .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+    {
+        return Err(Error::InvalidAppName(
+            "only letters, digits, '-' and '_' are allowed".to_owned(),
+        ));
+    }
+    Ok(())
+}
+
+/// Stream keys are credentials and never touch the file system, printable
+/// ASCII is enough. The key itself is kept out of the error.
+pub fn validate_stream_key(key: &str) -> Result<(), Error> {
+    if key.len() > MAX_STREAM_KEY_LEN {
+        return Err(Error::InvalidStreamKey(format!(
+            "longer than {} bytes",
+            MAX_STREAM_KEY_LEN
+        )));
+    }
+    if !key.bytes().all(|b| b.is_ascii_graphic()) {
+        return Err(Error::InvalidStreamKey(
+            "only printable ASCII is allowed".to_owned(),
+        ));
+    }
+    Ok(())
+}
@@ -1,9 +1,9 @@
 use anyhow::Result;
-use bytes::{BufMut, Bytes};
+use bytes::Bytes;
 use rml_rtmp::sessions::StreamMetadata;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::convert::{TryFrom, TryInto};
+use std::convert::TryFrom;
 use std::str::FromStr;
 
 #[derive(Clone, Copy, Serialize, Deserialize)]
@@ -47,6 +47,14 @@ pub struct Packet {
     pub kind: PacketType,
     pub timestamp: Option<Timestamp>,
     pub payload: Bytes,
+    /// The video frame as AnnexB, set by channels that feed sinks needing
+    /// elementary streams. Never sent over the wire.
+    #[serde(skip)]
+    pub annexb: Option<Bytes>,
+    /// Unix milliseconds the timestamp corresponds to on the wall clock at
+    /// ingest, set by channels. Never sent over the wire.
+    #[serde(skip)]
+    pub wallclock: Option<i64>,
 }
 
 impl Packet {
@@ -60,6 +68,8 @@ impl Packet {
             kind,
             timestamp,
             payload: payload.into(),
+            annexb: None,
+            wallclock: None,
         }
     }
 
@@ -107,7 +117,7 @@ impl TryFrom<&[u8]> for Packet {
     type Error = anyhow::Error;
 
     fn try_from(val: &[u8]) -> Result<Self, Self::Error> {
-        Packet::unpack(&val)
+        Packet::unpack(val)
     }
 }
 
@@ -123,7 +133,7 @@ impl Metadata {
         K: AsRef<str>,
         V: FromStr,
     {
-        self.0.get(key.as_ref()).map(|v| v.parse().ok()).flatten()
+        self.0.get(key.as_ref()).and_then(|v| v.parse().ok())
     }
 }
 
@@ -170,6 +180,8 @@ impl TryFrom<Metadata> for Packet {
             kind: PacketType::Meta,
             timestamp: None,
             payload: Bytes::try_from(val)?,
+            annexb: None,
+            wallclock: None,
         })
     }
 }
@@ -0,0 +1,118 @@
+/// Checks a media playlist against the parts of RFC 8216 this server and
+/// common players rely on. Returns one message per problem, prefixed with
+/// the line it was found on.
+pub fn validate(text: &str) -> Vec<String> {
+    let mut problems = vec![];
+    let mut lines = text
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim()));
+
+    match lines.next() {
+        Some((_, "#EXTM3U")) => {}
+        _ => problems.push("line 1: playlist must start with #EXTM3U".to_owned()),
+    }
+
+    let mut target_duration: Option<u64> = None;
+    let mut pending_duration: Option<f64> = None;
+    let mut durations = vec![];
+    let mut segments = 0;
+    let mut playlist_type: Option<&str> = None;
+    let mut ended = false;
+    for (n, line) in lines {
+        if line.is_empty() {
+            continue;
+        }
+        let (tag, value) = match line.split_once(':') {
+            Some((tag, value)) => (tag, Some(value)),
+            None => (line, None),
+        };
+        match tag {
+            "#EXT-X-TARGETDURATION" => {
+                if target_duration.is_some() {
+                    problems.push(format!("line {}: duplicate {}", n, tag));
+                }
+                target_duration = integer(&mut problems, n, tag, value);
+            }
+            "#EXT-X-MEDIA-SEQUENCE" | "#EXT-X-DISCONTINUITY-SEQUENCE" => {
+                if segments > 0 || pending_duration.is_some() {
+                    problems.push(format!("line {}: {} must precede all segments", n, tag));
+                }
+                integer(&mut problems, n, tag, value);
+            }
+            "#EXT-X-PLAYLIST-TYPE" => {
+                if playlist_type.is_some() {
+                    problems.push(format!("line {}: duplicate {}", n, tag));
+                }
+                if segments > 0 || pending_duration.is_some() {
+                    problems.push(format!("line {}: {} must precede all segments", n, tag));
+                }
+                match value {
+                    Some(value @ "EVENT") | Some(value @ "VOD") => playlist_type = Some(value),
+                    _ => problems.push(format!("line {}: {} must be EVENT or VOD", n, tag)),
+                }
+            }
+            "#EXT-X-PROGRAM-DATE-TIME" => {
+                let date = value.map(chrono::DateTime::parse_from_rfc3339);
+                if !matches!(date, Some(Ok(_))) {
+                    problems.push(format!("line {}: {} needs an ISO 8601 date", n, tag));
+                }
+            }
+            "#EXT-X-ENDLIST" => {
+                if ended {
+                    problems.push(format!("line {}: duplicate {}", n, tag));
+                }
+                ended = true;
+            }
+            "#EXTINF" => {
+                let duration = value
+                    .and_then(|v| v.split(',').next())
+                    .and_then(|v| v.parse::<f64>().ok());
+                match duration {
+                    Some(duration) if duration >= 0.0 => pending_duration = Some(duration),
+                    _ => problems.push(format!("line {}: invalid #EXTINF duration", n)),
+                }
+            }
+            _ if line.starts_with('#') => {}
+            _ if ended => {
+                problems.push(format!("line {}: segment {} after #EXT-X-ENDLIST", n, line))
+            }
+            _ => match pending_duration.take() {
+                Some(duration) => {
+                    segments += 1;
+                    durations.push((n, duration));
+                }
+                None => problems.push(format!("line {}: segment {} has no #EXTINF", n, line)),
+            },
+        }
+    }
+
+    if playlist_type == Some("VOD") && !ended {
+        problems.push("VOD playlist has no #EXT-X-ENDLIST".to_owned());
+    }
+    if pending_duration.is_some() {
+        problems.push("last #EXTINF is not followed by a segment".to_owned());
+    }
+    match target_duration {
+        Some(target) => {
+            for (n, duration) in durations {
+                if duration.round() as u64 > target {
+                    problems.push(format!(
+                        "line {}: segment duration {} exceeds #EXT-X-TARGETDURATION {}",
+                        n, duration, target
+                    ));
+                }
+            }
+        }
+        None => problems.push("#EXT-X-TARGETDURATION is missing".to_owned()),
+    }
+    problems
+}
+
+fn integer(problems: &mut Vec<String>, n: usize, tag: &str, value: Option<&str>) -> Option<u64> {
+    let parsed = value.and_then(|v| v.parse().ok());
+    if parsed.is_none() {
+        problems.push(format!("line {}: {} needs an integer value", n, tag));
+    }
+    parsed
+}
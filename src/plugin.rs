@@ -0,0 +1,27 @@
+use crate::packet::Packet;
+
+/// Extension registered with the [`Manager`](crate::Manager) to look at the
+/// packets of channels, e.g. to count them or to watermark video.
+pub trait Plugin: Send + Sync {
+    /// Called when channel `name` opens. Returns the inspector for its
+    /// packets, or `None` to leave the channel alone.
+    fn attach(&self, name: &str) -> Option<Box<dyn Inspector>>;
+}
+
+/// Sees every packet of one channel before it is cached and sent to
+/// players and sinks. Inspectors run in the order their plugins were
+/// registered, on the channel's task, so they should not block.
+pub trait Inspector: Send {
+    /// The timestamp is already normalized. The packet may be changed.
+    fn inspect(&mut self, packet: &mut Packet) -> Verdict;
+
+    /// Called when the channel closes.
+    fn detach(&mut self) {}
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Pass,
+    /// Nobody receives the packet and later inspectors don't see it.
+    Drop,
+}
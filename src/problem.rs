@@ -0,0 +1,185 @@
+use crate::error::Error;
+use hyper::{Body, Response, StatusCode};
+use serde::Serialize;
+
+/// Version of the error body below. Bumped only on incompatible changes,
+/// new fields and new codes may be added at any time.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// An RFC 7807 `application/problem+json` error.
+///
+/// Besides the standard `type`, `title`, `status` and `detail` members every
+/// body carries `code`, a stable machine readable name that clients should
+/// match on, `retryable`, whether repeating the same request may succeed
+/// later, and `schema_version`.
+pub struct Problem {
+    status: StatusCode,
+    code: &'static str,
+    title: &'static str,
+    retryable: bool,
+    detail: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Document<'a> {
+    #[serde(rename = "type")]
+    kind: String,
+    title: &'a str,
+    status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<&'a str>,
+    code: &'a str,
+    retryable: bool,
+    schema_version: u32,
+}
+
+impl Problem {
+    pub const BAD_REQUEST: Problem =
+        Problem::new(StatusCode::BAD_REQUEST, "bad_request", "Bad Request", false);
+    pub const NOT_FOUND: Problem =
+        Problem::new(StatusCode::NOT_FOUND, "not_found", "Not Found", false);
+    pub const METHOD_NOT_ALLOWED: Problem = Problem::new(
+        StatusCode::METHOD_NOT_ALLOWED,
+        "method_not_allowed",
+        "Method Not Allowed",
+        false,
+    );
+    pub const FORBIDDEN: Problem =
+        Problem::new(StatusCode::FORBIDDEN, "forbidden", "Forbidden", false);
+    /// The stream is not live, it may be once its publisher connects.
+    pub const STREAM_NOT_FOUND: Problem = Problem::new(
+        StatusCode::NOT_FOUND,
+        "stream_not_found",
+        "Stream not found",
+        true,
+    );
+    pub const PAYLOAD_TOO_LARGE: Problem = Problem::new(
+        StatusCode::PAYLOAD_TOO_LARGE,
+        "payload_too_large",
+        "Payload Too Large",
+        false,
+    );
+    pub const URI_TOO_LONG: Problem = Problem::new(
+        StatusCode::URI_TOO_LONG,
+        "uri_too_long",
+        "URI Too Long",
+        false,
+    );
+    pub const SEGMENT_GONE: Problem = Problem::new(
+        StatusCode::GONE,
+        "segment_gone",
+        "Segment rotated out of the playlist",
+        false,
+    );
+    pub const RECORDING_NOT_FOUND: Problem = Problem::new(
+        StatusCode::NOT_FOUND,
+        "recording_not_found",
+        "Recording not found",
+        false,
+    );
+    pub const RANGE_NOT_SATISFIABLE: Problem = Problem::new(
+        StatusCode::RANGE_NOT_SATISFIABLE,
+        "range_not_satisfiable",
+        "Range Not Satisfiable",
+        false,
+    );
+    pub const UNAVAILABLE: Problem = Problem::new(
+        StatusCode::SERVICE_UNAVAILABLE,
+        "unavailable",
+        "Service Unavailable",
+        true,
+    );
+
+    const fn new(
+        status: StatusCode,
+        code: &'static str,
+        title: &'static str,
+        retryable: bool,
+    ) -> Self {
+        Self {
+            status,
+            code,
+            title,
+            retryable,
+            detail: None,
+        }
+    }
+
+    pub fn detail<D: Into<String>>(mut self, detail: D) -> Self {
+        self.detail = Some(detail.into());
+        self
+    }
+
+    /// The problem for an error that may be an [`Error`] of this crate,
+    /// anything else is reported as unavailable.
+    pub fn from_anyhow(error: &anyhow::Error) -> Self {
+        match error.downcast_ref::<Error>() {
+            Some(error) => error.into(),
+            None => Problem::UNAVAILABLE,
+        }
+    }
+
+    /// JSON schema of the body, for the OpenAPI description.
+    pub fn schema() -> serde_json::Value {
+        serde_json::json!({
+            "type": "object",
+            "required": ["type", "title", "status", "code", "retryable", "schema_version"],
+            "properties": {
+                "type": {"type": "string"},
+                "title": {"type": "string"},
+                "status": {"type": "integer"},
+                "detail": {"type": "string"},
+                "code": {"type": "string"},
+                "retryable": {"type": "boolean"},
+                "schema_version": {"type": "integer"}
+            }
+        })
+    }
+
+    pub fn into_response(self) -> Response<Body> {
+        let document = Document {
+            kind: format!("urn:xlive:problem:{}", self.code),
+            title: self.title,
+            status: self.status.as_u16(),
+            detail: self.detail.as_deref(),
+            code: self.code,
+            retryable: self.retryable,
+            schema_version: SCHEMA_VERSION,
+        };
+        Response::builder()
+            .status(self.status)
+            .header("Content-Type", "application/problem+json")
+            .body(serde_json::to_vec(&document).unwrap().into())
+            .unwrap()
+    }
+}
+
+impl From<&Error> for Problem {
+    fn from(error: &Error) -> Self {
+        let problem = match error {
+            Error::NoSuchStream(_) => Problem::STREAM_NOT_FOUND,
+            Error::EmptyAppName
+            | Error::InvalidAppName(_)
+            | Error::InvalidStreamKey(_)
+            | Error::HttpFlvAppNameErr
+            | Error::InvalidInput => Problem::BAD_REQUEST,
+            // the manager or a channel stopped, or a peer went away
+            Error::Disconnected(_)
+            | Error::ChannelCreationFailed
+            | Error::ChannelReleaseFailed
+            | Error::ChannelJoinFailed
+            | Error::ChannelSendFailed
+            | Error::ReturnPacketFailed(_)
+            | Error::ConnectionTimeout(_)
+            | Error::NoMediaReceived(_)
+            | Error::HandshakeFailed
+            | Error::ChannelInitializationFailed
+            | Error::ChannelNotInitialized
+            | Error::RequestRejected
+            | Error::NoStreamId
+            | Error::SendTsToMqErr
+            | Error::TooManyDumps(_) => Problem::UNAVAILABLE,
+        };
+        problem.detail(error.to_string())
+    }
+}
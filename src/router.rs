@@ -0,0 +1,474 @@
+use crate::config;
+use crate::problem::Problem;
+use crate::shutdown::{Phase, Shutdown};
+use crate::socket::{self, HeaderTimeout, RequestTimer};
+use futures::{stream, StreamExt};
+use hyper::server::accept;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{header, Body, Method, Request, Response, Server};
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Values captured by the `{name}` placeholders of a route pattern.
+pub type Params = HashMap<String, String>;
+
+type BoxFuture = Pin<Box<dyn Future<Output = Response<Body>> + Send>>;
+type Handler<S> = Box<dyn Fn(Arc<S>, Request<Body>, Params) -> BoxFuture + Send + Sync>;
+type Before = Box<dyn Fn(&Request<Body>) -> Option<Response<Body>> + Send + Sync>;
+type After = Box<dyn Fn(&mut Response<Body>) + Send + Sync>;
+
+enum Segment {
+    Literal(String),
+    /// `{name}` with an optional literal suffix, e.g. `{app}.m3u8`.
+    Param(String, String),
+    /// `{*name}`, matching all remaining segments. Only valid last.
+    Tail(String, String),
+}
+
+struct Pattern(Vec<Segment>);
+
+impl Pattern {
+    /// The pattern as an OpenAPI path template.
+    fn template(&self) -> String {
+        let mut template = String::new();
+        for segment in &self.0 {
+            template.push('/');
+            match segment {
+                Segment::Literal(literal) => template.push_str(literal),
+                Segment::Param(name, suffix) | Segment::Tail(name, suffix) => {
+                    template.push_str(&format!("{{{}}}{}", name, suffix))
+                }
+            }
+        }
+        template
+    }
+
+    fn names(&self) -> impl Iterator<Item = &str> {
+        self.0.iter().filter_map(|segment| match segment {
+            Segment::Literal(_) => None,
+            Segment::Param(name, _) | Segment::Tail(name, _) => Some(name.as_str()),
+        })
+    }
+
+    fn parse(pattern: &str) -> Self {
+        let segments = pattern
+            .trim_start_matches('/')
+            .split('/')
+            .map(
+                |s| match s.strip_prefix('{').and_then(|s| s.split_once('}')) {
+                    Some((name, suffix)) => match name.strip_prefix('*') {
+                        Some(name) => Segment::Tail(name.to_owned(), suffix.to_owned()),
+                        None => Segment::Param(name.to_owned(), suffix.to_owned()),
+                    },
+                    None => Segment::Literal(s.to_owned()),
+                },
+            )
+            .collect();
+        Self(segments)
+    }
+
+    fn matches(&self, path: &str) -> Option<Params> {
+        let mut parts = path.trim_start_matches('/').split('/');
+        let mut params = Params::new();
+        for segment in &self.0 {
+            let (name, value) = match segment {
+                Segment::Literal(literal) => {
+                    if parts.next()? != literal {
+                        return None;
+                    }
+                    continue;
+                }
+                Segment::Param(name, suffix) => {
+                    (name, parts.next()?.strip_suffix(suffix.as_str())?)
+                }
+                Segment::Tail(name, suffix) => {
+                    let rest = parts.by_ref().collect::<Vec<_>>().join("/");
+                    let value = rest.strip_suffix(suffix.as_str())?.to_owned();
+                    if value.is_empty() {
+                        return None;
+                    }
+                    params.insert(name.clone(), value);
+                    continue;
+                }
+            };
+            if value.is_empty() {
+                return None;
+            }
+            params.insert(name.clone(), value.to_owned());
+        }
+        match parts.next() {
+            Some(_) => None,
+            None => Some(params),
+        }
+    }
+}
+
+struct Route<S> {
+    methods: &'static [Method],
+    pattern: Pattern,
+    handler: Handler<S>,
+    operation: Option<(&'static str, &'static str)>,
+    query: Vec<(&'static str, &'static str)>,
+}
+
+/// Method and path router shared by the HTTP services.
+///
+/// Routes are tried in the order they were added. `before` middleware runs
+/// ahead of routing and may answer the request itself (auth, rate limits),
+/// `after` middleware sees every response (CORS, metrics).
+pub struct Router<S> {
+    state: Arc<S>,
+    routes: Vec<Route<S>>,
+    before: Vec<Before>,
+    after: Vec<After>,
+    fallback: Handler<S>,
+}
+
+impl<S: Send + Sync + 'static> Router<S> {
+    pub fn new(state: S) -> Self {
+        Self {
+            state: Arc::new(state),
+            routes: vec![],
+            before: vec![],
+            after: vec![],
+            fallback: Box::new(|_, _, _| Box::pin(async { Problem::NOT_FOUND.into_response() })),
+        }
+    }
+
+    pub fn get<H, F>(self, pattern: &str, handler: H) -> Self
+    where
+        H: Fn(Arc<S>, Request<Body>, Params) -> F + Send + Sync + 'static,
+        F: Future<Output = Response<Body>> + Send + 'static,
+    {
+        self.route(&[Method::GET, Method::HEAD], pattern, handler)
+    }
+
+    pub fn route<H, F>(mut self, methods: &'static [Method], pattern: &str, handler: H) -> Self
+    where
+        H: Fn(Arc<S>, Request<Body>, Params) -> F + Send + Sync + 'static,
+        F: Future<Output = Response<Body>> + Send + 'static,
+    {
+        self.routes.push(Route {
+            methods,
+            pattern: Pattern::parse(pattern),
+            handler: boxed(handler),
+            operation: None,
+            query: vec![],
+        });
+        self
+    }
+
+    /// Names the route last added in the OpenAPI description. Routes that
+    /// are never described are left out of it.
+    pub fn describe(mut self, operation_id: &'static str, summary: &'static str) -> Self {
+        if let Some(route) = self.routes.last_mut() {
+            route.operation = Some((operation_id, summary));
+        }
+        self
+    }
+
+    /// Documents a query parameter of the route last added.
+    pub fn query_param(mut self, name: &'static str, description: &'static str) -> Self {
+        if let Some(route) = self.routes.last_mut() {
+            route.query.push((name, description));
+        }
+        self
+    }
+
+    /// Serves an OpenAPI description of the routes described so far at
+    /// `/openapi.json`.
+    pub fn openapi(self, title: &str) -> Self {
+        let spec = Arc::new(self.spec(title).to_string());
+        self.get("/openapi.json", move |_, _, _| {
+            let spec = spec.clone();
+            async move {
+                Response::builder()
+                    .header("Content-Type", "application/json")
+                    .body(Body::from(spec.as_ref().clone()))
+                    .unwrap()
+            }
+        })
+    }
+
+    fn spec(&self, title: &str) -> Value {
+        let mut paths = Map::new();
+        for route in &self.routes {
+            let (operation_id, summary) = match route.operation {
+                Some(operation) => operation,
+                None => continue,
+            };
+            let path_params = route.pattern.names().map(|name| {
+                json!({"name": name, "in": "path", "required": true, "schema": {"type": "string"}})
+            });
+            let query_params = route.query.iter().map(|(name, description)| {
+                json!({"name": name, "in": "query", "description": description, "schema": {"type": "string"}})
+            });
+            let operation = json!({
+                "operationId": operation_id,
+                "summary": summary,
+                "parameters": path_params.chain(query_params).collect::<Vec<_>>(),
+                "responses": {
+                    "200": {"description": "OK"},
+                    "default": {
+                        "description": "Error",
+                        "content": {"application/problem+json": {"schema": {"$ref": "#/components/schemas/Problem"}}}
+                    }
+                }
+            });
+            let item = paths
+                .entry(route.pattern.template())
+                .or_insert_with(|| json!({}));
+            for method in route.methods.iter().filter(|&m| m != Method::HEAD) {
+                item[method.as_str().to_lowercase()] = operation.clone();
+            }
+        }
+        json!({
+            "openapi": "3.0.3",
+            "info": {"title": title, "version": env!("CARGO_PKG_VERSION")},
+            "paths": paths,
+            "components": {"schemas": {"Problem": Problem::schema()}}
+        })
+    }
+
+    /// Answers requests no route matched, 404 by default.
+    pub fn fallback<H, F>(mut self, handler: H) -> Self
+    where
+        H: Fn(Arc<S>, Request<Body>, Params) -> F + Send + Sync + 'static,
+        F: Future<Output = Response<Body>> + Send + 'static,
+    {
+        self.fallback = boxed(handler);
+        self
+    }
+
+    pub fn before<F>(mut self, middleware: F) -> Self
+    where
+        F: Fn(&Request<Body>) -> Option<Response<Body>> + Send + Sync + 'static,
+    {
+        self.before.push(Box::new(middleware));
+        self
+    }
+
+    pub fn after<F>(mut self, middleware: F) -> Self
+    where
+        F: Fn(&mut Response<Body>) + Send + Sync + 'static,
+    {
+        self.after.push(Box::new(middleware));
+        self
+    }
+
+    async fn handle(&self, req: Request<Body>) -> Response<Body> {
+        let mut res = match self.before.iter().find_map(|before| before(&req)) {
+            Some(res) => res,
+            None => self.dispatch(req).await,
+        };
+        for after in &self.after {
+            after(&mut res);
+        }
+        res
+    }
+
+    async fn dispatch(&self, req: Request<Body>) -> Response<Body> {
+        let mut path_known = false;
+        for route in &self.routes {
+            if let Some(params) = route.pattern.matches(req.uri().path()) {
+                if route.methods.contains(req.method()) {
+                    return (route.handler)(self.state.clone(), req, params).await;
+                }
+                path_known = true;
+            }
+        }
+        if path_known {
+            return Problem::METHOD_NOT_ALLOWED.into_response();
+        }
+        (self.fallback)(self.state.clone(), req, Params::new()).await
+    }
+
+    /// Binds `addr` and returns the server, which serves until it fails or
+    /// shutdown reaches `Phase::Stopping`. Binding up front lets callers
+    /// tell a port that is in use apart from a running listener.
+    pub fn bind(
+        self,
+        addr: SocketAddr,
+        options: &config::Socket,
+        limits: &config::Http,
+        shutdown: &Shutdown,
+    ) -> anyhow::Result<impl Future<Output = hyper::Result<()>>> {
+        let listener = socket::listen(addr, options)?;
+        let header_timeout = Duration::from_secs(limits.header_timeout);
+        let options = options.clone();
+        let incoming = stream::unfold(listener, move |listener| {
+            let options = options.clone();
+            async move {
+                loop {
+                    match listener.accept().await {
+                        Ok((stream, _)) => {
+                            _ = socket::tune(&stream, &options);
+                            let conn = HeaderTimeout::new(stream, header_timeout);
+                            return Some((Ok::<_, std::io::Error>(conn), listener));
+                        }
+                        // out of file descriptors and the like, keep serving
+                        Err(e) => {
+                            log::warn!("Failed to accept on {:?}: {}", listener.local_addr(), e);
+                            tokio::time::sleep(Duration::from_millis(100)).await;
+                        }
+                    }
+                }
+            }
+        });
+
+        let max_buf_size = limits.max_header_bytes.max(8192);
+        let router = Arc::new(self);
+        let limits = Arc::new(limits.clone());
+        let make_service = make_service_fn(move |conn: &HeaderTimeout| {
+            let router = router.clone();
+            let limits = limits.clone();
+            let timer = conn.timer();
+            let peer = conn.peer_addr();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |mut req: Request<Body>| {
+                    let router = router.clone();
+                    let limits = limits.clone();
+                    let timer = timer.clone();
+                    if let Some(peer) = peer {
+                        req.extensions_mut().insert(peer);
+                    }
+                    async move {
+                        timer.start();
+                        let res = match over_limit(&req, &limits) {
+                            Some(problem) => problem.into_response(),
+                            None => {
+                                let max = limits.max_body_bytes;
+                                router.handle(req.map(|body| limit_body(body, max))).await
+                            }
+                        };
+                        Ok::<_, Infallible>(finish_after_body(res, timer))
+                    }
+                }))
+            }
+        });
+        let shutdown = shutdown.clone();
+        Ok(Server::builder(accept::from_stream(incoming))
+            .http1_max_buf_size(max_buf_size)
+            .serve(make_service)
+            .with_graceful_shutdown(async move { shutdown.reached(Phase::Stopping).await }))
+    }
+}
+
+fn over_limit(req: &Request<Body>, limits: &config::Http) -> Option<Problem> {
+    if req.uri().to_string().len() > limits.max_uri_bytes {
+        return Some(Problem::URI_TOO_LONG);
+    }
+    let length = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    match length {
+        Some(length) if length > limits.max_body_bytes => Some(Problem::PAYLOAD_TOO_LARGE),
+        _ => None,
+    }
+}
+
+/// Fails `body` once more than `max` bytes were read, for bodies whose
+/// length `over_limit` can't check up front, e.g. chunked ones.
+fn limit_body(body: Body, max: u64) -> Body {
+    if hyper::body::HttpBody::is_end_stream(&body) {
+        return body;
+    }
+    let mut read = 0;
+    Body::wrap_stream(body.map(move |chunk| {
+        let chunk = chunk?;
+        read += chunk.len() as u64;
+        if read > max {
+            let message = format!("request body larger than {} bytes", max);
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, message).into());
+        }
+        Ok::<_, Box<dyn std::error::Error + Send + Sync>>(chunk)
+    }))
+}
+
+/// Restarts the header timeout once the response is sent. Bodies held in
+/// memory are sent right away, streamed ones are followed to their end.
+fn finish_after_body(res: Response<Body>, timer: RequestTimer) -> Response<Body> {
+    if hyper::body::HttpBody::size_hint(res.body())
+        .exact()
+        .is_some()
+    {
+        timer.finish();
+        return res;
+    }
+    struct Finish(RequestTimer);
+    impl Drop for Finish {
+        fn drop(&mut self) {
+            self.0.finish();
+        }
+    }
+    let finish = Finish(timer);
+    res.map(|body| {
+        Body::wrap_stream(body.map(move |chunk| {
+            let _ = &finish;
+            chunk
+        }))
+    })
+}
+
+fn boxed<S, H, F>(handler: H) -> Handler<S>
+where
+    H: Fn(Arc<S>, Request<Body>, Params) -> F + Send + Sync + 'static,
+    F: Future<Output = Response<Body>> + Send + 'static,
+{
+    Box::new(move |state, req, params| Box::pin(handler(state, req, params)))
+}
+
+/// The address of the client that sent `req`.
+pub fn peer(req: &Request<Body>) -> Option<SocketAddr> {
+    req.extensions().get().copied()
+}
+
+pub fn query(req: &Request<Body>) -> HashMap<String, String> {
+    req.uri()
+        .query()
+        .map(|v| {
+            url::form_urlencoded::parse(v.as_bytes())
+                .into_owned()
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// `after` middleware allowing any origin, for browser players.
+pub fn allow_any_origin(res: &mut Response<Body>) {
+    res.headers_mut()
+        .insert("Access-Control-Allow-Origin", "*".parse().unwrap());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunked(chunks: &[&'static [u8]]) -> Body {
+        let chunks: Vec<Result<_, std::io::Error>> = chunks.iter().map(|c| Ok(*c)).collect();
+        Body::wrap_stream(stream::iter(chunks))
+    }
+
+    #[tokio::test]
+    async fn reads_bodies_up_to_the_limit() {
+        let body = limit_body(chunked(&[b"01234", b"56789"]), 10);
+        assert_eq!(
+            &hyper::body::to_bytes(body).await.unwrap()[..],
+            b"0123456789"
+        );
+    }
+
+    #[tokio::test]
+    async fn fails_chunked_bodies_over_the_limit() {
+        let body = limit_body(chunked(&[b"01234", b"56789", b"a"]), 10);
+        assert!(hyper::body::to_bytes(body).await.is_err());
+    }
+}
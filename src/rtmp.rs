@@ -1,20 +1,43 @@
 use crate::error::Error;
+use crate::metrics::DropReason;
+use crate::naming;
 use crate::packet::{self, Packet, PacketType};
 use bytes::Bytes;
+use rml_amf0::Amf0Value;
+use rml_rtmp::chunk_io::ChunkDeserializer;
 use rml_rtmp::handshake::{Handshake, HandshakeProcessResult, PeerType};
+use rml_rtmp::messages::RtmpMessage;
 use rml_rtmp::sessions::{
     ServerSession, ServerSessionConfig, ServerSessionEvent, ServerSessionResult,
 };
 use rml_rtmp::time::RtmpTimestamp;
+use std::collections::{BTreeMap, HashMap};
 use std::convert::{TryFrom, TryInto};
 use std::rc::Rc;
 
+/// What the server received from a publisher since its previous report.
+#[derive(Default)]
+pub struct IngestStats {
+    pub bitrate_kbps: u64,
+    pub bytes: u64,
+    pub video_frames: u64,
+    pub audio_frames: u64,
+    /// Packets of the stream dropped since it opened, by reason.
+    pub dropped: BTreeMap<DropReason, u64>,
+}
+
+/// Chunk stream of the stats reports. The session's serializer only uses
+/// 2 to 6, so its header compression never refers to a report.
+const REPORT_CSID: u8 = 7;
+
+#[allow(dead_code)]
 pub enum Event {
     ReturnData(Bytes),
     SendPacket(Packet),
     AcquireChannel {
         app_name: String,
         stream_key: String,
+        resume: Option<u64>,
     },
     JoinChannel {
         app_name: String,
@@ -30,7 +53,7 @@ pub enum Event {
 enum State {
     HandshakePending,
     Ready,
-    Publishing,
+    Publishing { stream_id: u32 },
     Playing { stream_id: u32 },
     Finished,
 }
@@ -40,6 +63,10 @@ pub struct Protocol {
     return_queue: Vec<Event>,
     handshake: Handshake,
     session: Option<ServerSession>,
+    // reads the messages ahead of the session until the client publishes,
+    // the session keeps to itself which stream that happens on
+    lookout: Option<ChunkDeserializer>,
+    publish_stream_id: Option<u32>,
 }
 
 impl Protocol {
@@ -61,6 +88,7 @@ impl Protocol {
     }
 
     fn handle_input(&mut self, input: &[u8]) -> Result<(), Error> {
+        self.look_out(input)?;
         let results = self
             .session()?
             .handle_input(input)
@@ -101,6 +129,33 @@ impl Protocol {
         Ok(())
     }
 
+    /// Notes the message stream of the client's `publish` command.
+    fn look_out(&mut self, input: &[u8]) -> Result<(), Error> {
+        let lookout = match &mut self.lookout {
+            Some(lookout) => lookout,
+            None => return Ok(()),
+        };
+        let mut input = input;
+        while let Some(payload) = lookout
+            .get_next_message(input)
+            .map_err(|_| Error::InvalidInput)?
+        {
+            input = &[];
+            match payload.to_rtmp_message() {
+                Ok(RtmpMessage::SetChunkSize { size }) => {
+                    lookout
+                        .set_max_chunk_size(size as usize)
+                        .map_err(|_| Error::InvalidInput)?;
+                }
+                Ok(RtmpMessage::Amf0Command { command_name, .. }) if command_name == "publish" => {
+                    self.publish_stream_id = Some(payload.message_stream_id);
+                }
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
     fn initialize_session(&mut self) -> Result<(), Error> {
         let config = ServerSessionConfig::new();
         let (session, results) =
@@ -128,9 +183,9 @@ impl Protocol {
             .map(|v| v.bytes)
     }
 
-    pub fn pack_video(&mut self, packet: Packet) -> Result<Vec<u8>, Error> {
+    pub fn pack_video(&mut self, packet: &Packet) -> Result<Vec<u8>, Error> {
         let stream_id = self.stream_id()?;
-        let data = packet.payload;
+        let data = packet.payload.clone();
         let timestamp = packet
             .timestamp
             .map(|v| RtmpTimestamp::new(v.into()))
@@ -142,9 +197,9 @@ impl Protocol {
             .map(|v| v.bytes)
     }
 
-    pub fn pack_audio(&mut self, packet: Packet) -> Result<Vec<u8>, Error> {
+    pub fn pack_audio(&mut self, packet: &Packet) -> Result<Vec<u8>, Error> {
         let stream_id = self.stream_id()?;
-        let data = packet.payload;
+        let data = packet.payload.clone();
         let timestamp = packet
             .timestamp
             .map(|v| RtmpTimestamp::new(v.into()))
@@ -156,6 +211,42 @@ impl Protocol {
             .map(|v| v.bytes)
     }
 
+    /// An `onStatus` with code `NetStream.Publish.Stats` on the publishing
+    /// stream, so encoders can show how the server receives them.
+    pub fn pack_publish_stats(&mut self, stats: &IngestStats) -> Result<Vec<u8>, Error> {
+        let stream_id = match self.state {
+            State::Publishing { stream_id } => stream_id,
+            _ => return Err(Error::NoStreamId),
+        };
+        let number = |n: u64| Amf0Value::Number(n as f64);
+        let mut info = HashMap::new();
+        info.insert("level".to_owned(), Amf0Value::Utf8String("status".to_owned()));
+        info.insert(
+            "code".to_owned(),
+            Amf0Value::Utf8String("NetStream.Publish.Stats".to_owned()),
+        );
+        info.insert("bitrateKbps".to_owned(), number(stats.bitrate_kbps));
+        info.insert("bytes".to_owned(), number(stats.bytes));
+        info.insert("videoFrames".to_owned(), number(stats.video_frames));
+        info.insert("audioFrames".to_owned(), number(stats.audio_frames));
+        let dropped = stats
+            .dropped
+            .iter()
+            .map(|(reason, n)| (reason.label().to_owned(), number(*n)))
+            .collect();
+        info.insert("dropped".to_owned(), Amf0Value::Object(dropped));
+        let message = RtmpMessage::Amf0Command {
+            command_name: "onStatus".to_owned(),
+            transaction_id: 0.0,
+            command_object: Amf0Value::Null,
+            additional_arguments: vec![Amf0Value::Object(info)],
+        };
+        let payload = message
+            .into_message_payload(RtmpTimestamp::new(0), stream_id)
+            .map_err(|_| Error::InvalidInput)?;
+        Ok(chunk(&payload.data, payload.type_id, stream_id))
+    }
+
     fn handle_results(&mut self, results: Vec<ServerSessionResult>) -> Result<(), Error> {
         for result in results {
             match result {
@@ -181,10 +272,7 @@ impl Protocol {
                 app_name,
                 ..
             } => {
-                if app_name.is_empty() {
-                    return Err(Error::EmptyAppName);
-                }
-
+                naming::validate_app_name(&app_name)?;
                 self.accept_request(request_id)?;
             }
             PublishStreamRequested {
@@ -193,12 +281,18 @@ impl Protocol {
                 stream_key,
                 ..
             } => {
+                let stream_id = self.publish_stream_id.take().ok_or(Error::NoStreamId)?;
+                self.lookout = None;
+                let (stream_key, resume) = split_resume(&stream_key);
+                naming::validate_app_name(&app_name)?;
+                naming::validate_stream_key(&stream_key)?;
                 self.emit(Event::AcquireChannel {
                     app_name,
                     stream_key,
+                    resume,
                 });
                 self.accept_request(request_id)?;
-                self.state = State::Publishing;
+                self.state = State::Publishing { stream_id };
             }
             PublishStreamFinished { .. } => {
                 self.emit(Event::ReleaseChannel);
@@ -211,6 +305,7 @@ impl Protocol {
                 stream_id,
                 ..
             } => {
+                self.lookout = None;
                 self.emit(Event::JoinChannel {
                     app_name: app_name.clone(),
                     stream_key,
@@ -270,6 +365,41 @@ impl Default for Protocol {
             return_queue: Vec::with_capacity(8),
             handshake: Handshake::new(PeerType::Server),
             session: None,
+            lookout: Some(ChunkDeserializer::new()),
+            publish_stream_id: None,
+        }
+    }
+}
+
+/// A message as chunks of the session's size on [`REPORT_CSID`], the first
+/// with a full header and the rest with none.
+fn chunk(data: &[u8], type_id: u8, stream_id: u32) -> Vec<u8> {
+    let chunk_size = ServerSessionConfig::new().chunk_size as usize;
+    let length = (data.len() as u32).to_be_bytes();
+    let mut bytes = Vec::with_capacity(data.len() + 12 + data.len() / chunk_size);
+    bytes.push(REPORT_CSID);
+    bytes.extend_from_slice(&[0, 0, 0]);
+    bytes.extend_from_slice(&length[1..]);
+    bytes.push(type_id);
+    bytes.extend_from_slice(&stream_id.to_le_bytes());
+    for (i, piece) in data.chunks(chunk_size).enumerate() {
+        if i > 0 {
+            bytes.push(0b1100_0000 | REPORT_CSID);
+        }
+        bytes.extend_from_slice(piece);
+    }
+    bytes
+}
+
+/// Splits an optional `?resume=<timestamp>` suffix off a stream key.
+fn split_resume(stream_key: &str) -> (String, Option<u64>) {
+    match stream_key.split_once('?') {
+        Some((key, query)) => {
+            let resume = url::form_urlencoded::parse(query.as_bytes())
+                .find(|(k, _)| k == "resume")
+                .and_then(|(_, v)| v.parse().ok());
+            (key.to_owned(), resume)
         }
+        None => (stream_key.to_owned(), None),
     }
 }
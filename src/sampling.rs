@@ -0,0 +1,69 @@
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Identical messages logged per interval, the rest are only counted.
+const LIMIT: u32 = 5;
+const INTERVAL: Duration = Duration::from_secs(10);
+/// Intervals tracked before the expired ones are forgotten.
+const MAX_TRACKED: usize = 1024;
+
+struct Window {
+    started: Instant,
+    count: u32,
+}
+
+lazy_static! {
+    static ref WINDOWS: Mutex<HashMap<String, Window>> = Mutex::new(HashMap::new());
+}
+
+/// Logs `message` unless it was logged `LIMIT` times in the current
+/// interval already. The number suppressed is appended to the first
+/// occurrence of the next interval, for per-packet warnings that malformed
+/// input would otherwise repeat thousands of times a second.
+pub(crate) fn log(level: log::Level, message: String) {
+    if !log::log_enabled!(level) {
+        return;
+    }
+    match admit(&message) {
+        Some(0) => log::log!(level, "{}", message),
+        Some(suppressed) => log::log!(
+            level,
+            "{} ({} identical messages suppressed)",
+            message,
+            suppressed
+        ),
+        None => {}
+    }
+}
+
+/// Whether `message` may be logged, with how many identical ones the
+/// previous interval suppressed.
+fn admit(message: &str) -> Option<u32> {
+    let now = Instant::now();
+    let mut windows = WINDOWS.lock().unwrap();
+    if let Some(window) = windows.get_mut(message) {
+        if now.duration_since(window.started) < INTERVAL {
+            window.count += 1;
+            return Some(0).filter(|_| window.count <= LIMIT);
+        }
+        let suppressed = window.count.saturating_sub(LIMIT);
+        *window = Window {
+            started: now,
+            count: 1,
+        };
+        return Some(suppressed);
+    }
+    if windows.len() >= MAX_TRACKED {
+        windows.retain(|_, window| now.duration_since(window.started) < INTERVAL);
+    }
+    windows.insert(
+        message.to_owned(),
+        Window {
+            started: now,
+            count: 1,
+        },
+    );
+    Some(0)
+}
@@ -0,0 +1,226 @@
+use crate::codec::aac::{self, AacCoder};
+use crate::codec::hevc::config::HEVCDecoderConfigurationRecord;
+use crate::codec::hevc::nal;
+use crate::codec::{FormatReader, FormatWriter};
+use crate::config::PlaylistType;
+use crate::elementary::Converter;
+use crate::packet::Packet;
+use crate::transport::Segment;
+use crate::transport_stream::{AudioCodec, SuportCodec, TransportStream};
+use crate::{hls, playlist, testsrc};
+use anyhow::{anyhow, bail, ensure, Result};
+use bytes::{BufMut, Bytes, BytesMut};
+use std::convert::TryFrom;
+
+// HEVC parameter sets and an IDR slice. Only the NAL headers are looked
+// at on the way to a segment, the payloads need not decode.
+const VPS: &[u8] = &[
+    0x40, 0x01, 0x0c, 0x01, 0xff, 0xff, 0x01, 0x60, 0x00, 0x00, 0x03, 0x00, 0x90, 0x00, 0x00, 0x03,
+    0x00, 0x00, 0x03, 0x00, 0x5d, 0x95, 0x98, 0x09,
+];
+const SPS: &[u8] = &[
+    0x42, 0x01, 0x01, 0x01, 0x60, 0x00, 0x00, 0x03, 0x00, 0x90, 0x00, 0x00, 0x03, 0x00, 0x00, 0x03,
+    0x00, 0x5d, 0xa0, 0x02, 0x80, 0x80, 0x2d, 0x16, 0x59, 0x59, 0xa4, 0x93, 0x2b, 0xc0,
+];
+const PPS: &[u8] = &[0x44, 0x01, 0xc1, 0x72, 0xb4, 0x62, 0x40];
+const IDR_SLICE: &[u8] = &[0x26, 0x01, 0xaf, 0x06, 0xb8, 0x63, 0xef, 0x3a];
+
+/// AAC-LC, 44.1 kHz, stereo.
+const ASC: [u8; 2] = [0x12, 0x10];
+/// One silent AAC-LC frame of that configuration.
+const SILENCE: [u8; 9] = [0x21, 0x00, 0x49, 0x90, 0x02, 0x19, 0x00, 0x23, 0x80];
+
+/// A step of the self-check and why it failed, if it did.
+pub struct Check {
+    pub name: &'static str,
+    pub result: Result<()>,
+}
+
+/// Runs built-in vectors through the coders, the TS muxer and the playlist
+/// renderer, and with feature `keyframe_image` through the C decoder, to
+/// tell whether this build works before it serves streams.
+pub fn run() -> Vec<Check> {
+    let avc = avc();
+    let hevc = hevc();
+    let adts = adts();
+    let ts = match (&avc, &adts) {
+        (Ok(avc), Ok(adts)) => segment(avc, adts),
+        _ => Err(anyhow!("needs the AVC and AAC output")),
+    };
+    #[cfg(feature = "keyframe_image")]
+    let keyframe = match &avc {
+        Ok(avc) => keyframe(avc),
+        Err(_) => Err(anyhow!("needs the AVC output")),
+    };
+    let checks = vec![
+        Check {
+            name: "avc",
+            result: avc.map(drop),
+        },
+        Check {
+            name: "hevc",
+            result: hevc,
+        },
+        Check {
+            name: "aac",
+            result: adts.map(drop),
+        },
+        Check {
+            name: "ts",
+            result: ts,
+        },
+        Check {
+            name: "playlist",
+            result: m3u8(),
+        },
+    ];
+    #[cfg(feature = "keyframe_image")]
+    let checks = {
+        let mut checks = checks;
+        checks.push(Check {
+            name: "keyframe_image",
+            result: keyframe,
+        });
+        checks
+    };
+    checks
+}
+
+/// The AnnexB keyframe of the test source, converted like any channel's.
+fn avc() -> Result<Bytes> {
+    let idr = testsrc::idr(0);
+    let annexb = convert(testsrc::sequence_header(), testsrc::video_tag(true, &idr))?;
+    ensure!(annexb.starts_with(&[0, 0, 0, 1]), "no start code");
+    ensure!(contains(&annexb, &idr), "the IDR slice is missing");
+    Ok(annexb)
+}
+
+fn hevc() -> Result<()> {
+    let record = HEVCDecoderConfigurationRecord {
+        vps: vec![nal::Unit::try_from(VPS)?],
+        sps: vec![nal::Unit::try_from(SPS)?],
+        pps: vec![nal::Unit::try_from(PPS)?],
+        ..Default::default()
+    };
+    let annexb = convert(
+        hevc_tag(true, &record.to_bytes()),
+        hevc_tag(false, IDR_SLICE),
+    )?;
+    for unit in [VPS, SPS, PPS, IDR_SLICE] {
+        ensure!(
+            contains(&annexb, unit),
+            "NAL unit type {} is missing",
+            unit[0] >> 1
+        );
+    }
+    Ok(())
+}
+
+/// A keyframe tag, the sequence header when `header` is set.
+fn hevc_tag(header: bool, body: &[u8]) -> Bytes {
+    let mut tag = BytesMut::new();
+    tag.put_slice(&[0x1c, if header { 0 } else { 1 }, 0, 0, 0]);
+    if !header {
+        tag.put_u32(body.len() as u32);
+    }
+    tag.put_slice(body);
+    tag.freeze()
+}
+
+fn convert(sequence_header: Bytes, keyframe: Bytes) -> Result<Bytes> {
+    let mut converter = Converter::new();
+    converter.convert(&mut Packet::new_video(0u32, sequence_header))?;
+    let mut packet = Packet::new_video(0u32, keyframe);
+    converter.convert(&mut packet)?;
+    packet
+        .annexb
+        .ok_or_else(|| anyhow!("the keyframe was not converted"))
+}
+
+fn adts() -> Result<Vec<u8>> {
+    let mut coder = AacCoder::new();
+    coder.set_asc(&ASC[..])?;
+    let raw = coder
+        .read_format(aac::Raw, &SILENCE)?
+        .ok_or_else(|| anyhow!("no frame read"))?;
+    let adts = coder.write_format(aac::AudioDataTransportStream, raw)?;
+    ensure!(
+        adts.len() > 7 && adts[0] == 0xff && adts[1] & 0xf0 == 0xf0,
+        "no ADTS sync word"
+    );
+    let length = (adts[3] as usize & 3) << 11 | (adts[4] as usize) << 3 | adts[5] as usize >> 5;
+    ensure!(
+        length == adts.len(),
+        "ADTS frame length {} of {}",
+        length,
+        adts.len()
+    );
+    Ok(adts)
+}
+
+fn segment(avc: &Bytes, adts: &[u8]) -> Result<()> {
+    let mut ts = TransportStream::new();
+    ts.set_codec(SuportCodec::H264);
+    ts.set_audio_codec(AudioCodec::Aac);
+    ts.push_video(0, 0, true, avc.to_vec())?;
+    ts.push_audio(0, adts.to_vec())?;
+    let bytes = ts.write_to()?;
+    // PAT, PMT and at least a packet each of video and audio
+    ensure!(bytes.len() >= 4 * 188, "only {} bytes", bytes.len());
+    ensure!(
+        bytes.len() % 188 == 0,
+        "{} bytes are no whole packets",
+        bytes.len()
+    );
+    if let Some(i) = bytes.chunks(188).position(|packet| packet[0] != 0x47) {
+        bail!("packet {} has no sync byte", i);
+    }
+    Ok(())
+}
+
+fn m3u8() -> Result<()> {
+    let segments = vec![
+        Segment {
+            name: 1_600_000_000,
+            started_at: 1_600_000_000_000,
+            duration: 5,
+            discontinuity: false,
+        },
+        Segment {
+            name: 1_600_000_005,
+            started_at: 1_600_000_005_000,
+            duration: 4,
+            discontinuity: true,
+        },
+    ];
+    let text = hls::render_m3u8(
+        "check",
+        segments,
+        0,
+        0,
+        PlaylistType::Event,
+        true,
+        true,
+        None,
+    );
+    match playlist::validate(&text).first() {
+        Some(problem) => bail!("{}", problem),
+        None => Ok(()),
+    }
+}
+
+#[cfg(feature = "keyframe_image")]
+fn keyframe(avc: &Bytes) -> Result<()> {
+    let path = std::env::temp_dir().join(format!("xlive-check-{}.jpg", std::process::id()));
+    let decoded = pic::keyframe_to_jpg(avc.to_vec(), path.to_string_lossy().into_owned());
+    let written = std::fs::metadata(&path).map_or(0, |m| m.len());
+    _ = std::fs::remove_file(&path);
+    ensure!(decoded && written > 0, "the keyframe was not decoded");
+    Ok(())
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack
+        .windows(needle.len())
+        .any(|window| window == needle)
+}
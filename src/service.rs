@@ -1,18 +1,37 @@
+use crate::config;
 use crate::connection::Connection;
+use crate::health::{self, Listener};
+use crate::shutdown::{Phase, Shutdown};
+use crate::socket;
 use crate::ManagerHandle;
 use anyhow::Result;
+use std::net::SocketAddr;
+use std::time::Duration;
 use tokio::io::{AsyncRead, AsyncWrite};
-use tokio::net::TcpListener;
 
 pub struct Service {
     manager_handle: ManagerHandle,
+    socket: config::Socket,
+    first_packet_timeout: Option<Duration>,
+    stats_interval: Option<Duration>,
+    shutdown: Shutdown,
     client_id: u64,
 }
 
 impl Service {
-    pub fn new(manager_handle: ManagerHandle) -> Self {
+    pub fn new(
+        manager_handle: ManagerHandle,
+        socket: config::Socket,
+        first_packet_timeout: Option<Duration>,
+        stats_interval: Option<Duration>,
+        shutdown: Shutdown,
+    ) -> Self {
         Self {
             manager_handle,
+            socket,
+            first_packet_timeout,
+            stats_interval,
+            shutdown,
             client_id: 0,
         }
     }
@@ -20,26 +39,46 @@ impl Service {
         if let Err(err) = self.handle_rtmp(port).await {
             log::error!("{}", err);
         }
+        health::unbound(Listener::Rtmp);
     }
 
     async fn handle_rtmp(&mut self, port: i32) -> Result<()> {
-        let addr = format!("[::]:{}", port);
-        let listener = TcpListener::bind(&addr).await?;
+        let addr: SocketAddr = format!("[::]:{}", port).parse()?;
+        let listener = socket::listen(addr, &self.socket)?;
+        health::bound(Listener::Rtmp);
         log::info!("Listening for RTMP connections on {}", addr);
+        let shutdown = self.shutdown.clone();
         loop {
-            let (tcp_stream, _addr) = listener.accept().await?;
-            self.process(tcp_stream);
+            // publishers are the first to go on shutdown
+            let (tcp_stream, addr) = tokio::select! {
+                accepted = listener.accept() => accepted?,
+                _ = shutdown.reached(Phase::Draining) => {
+                    log::info!("No longer accepting RTMP connections");
+                    return Ok(());
+                }
+            };
+            if let Err(e) = socket::tune(&tcp_stream, &self.socket) {
+                log::warn!("Failed to set socket options for {}: {}", addr, e);
+            }
+            self.process(tcp_stream, addr);
             self.client_id += 1;
         }
     }
 
-    fn process<S>(&self, stream: S)
+    fn process<S>(&self, stream: S, addr: SocketAddr)
     where
         S: AsyncRead + AsyncWrite + Unpin + Send + Sync + 'static,
     {
-        log::info!("New client connection: {}", &self.client_id);
+        log::info!("New client connection: #{} {}", &self.client_id, addr);
         let id = self.client_id;
-        let conn = Connection::new(id, stream, self.manager_handle.clone());
+        let conn = Connection::new(
+            id,
+            addr,
+            stream,
+            self.manager_handle.clone(),
+            self.first_packet_timeout,
+            self.stats_interval,
+        );
 
         tokio::spawn(async move {
             if let Err(err) = conn.run().await {
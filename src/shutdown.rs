@@ -0,0 +1,151 @@
+use crate::transport::{ChannelMessage, ManagerHandle};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{oneshot, watch, Notify};
+
+/// Shutdown phases, entered in this order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Phase {
+    Running,
+    /// No new publishers are accepted and the channels are closed, sinks
+    /// write out what they still hold.
+    Draining,
+    /// The HTTP servers stop once the sinks flushed or the grace ran out.
+    Stopping,
+}
+
+#[derive(Default)]
+struct Sinks {
+    active: AtomicUsize,
+    idle: Notify,
+}
+
+/// Handed to the services so they follow the shutdown phases.
+#[derive(Clone)]
+pub struct Shutdown {
+    phase: watch::Receiver<Phase>,
+    sinks: Arc<Sinks>,
+}
+
+impl Shutdown {
+    /// Resolves once `phase` was entered.
+    pub async fn reached(&self, phase: Phase) {
+        let mut current = self.phase.clone();
+        while *current.borrow() < phase {
+            if current.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Held by a sink while it may have data to flush, shutdown waits for
+    /// all of them to be dropped before stopping the HTTP servers.
+    pub fn sink(&self) -> SinkGuard {
+        self.sinks.active.fetch_add(1, Ordering::SeqCst);
+        SinkGuard(self.sinks.clone())
+    }
+}
+
+pub struct SinkGuard(Arc<Sinks>);
+
+impl Drop for SinkGuard {
+    fn drop(&mut self) {
+        if self.0.active.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.idle.notify_waiters();
+        }
+    }
+}
+
+/// Runs the shutdown: stop accepting publishers, close the channels so
+/// sinks flush their last segment, then stop the HTTP servers.
+pub struct Coordinator {
+    phase: watch::Sender<Phase>,
+    token: Shutdown,
+}
+
+impl Default for Coordinator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Coordinator {
+    pub fn new() -> Self {
+        let (phase, receiver) = watch::channel(Phase::Running);
+        Self {
+            phase,
+            token: Shutdown {
+                phase: receiver,
+                sinks: Arc::new(Sinks::default()),
+            },
+        }
+    }
+
+    pub fn token(&self) -> Shutdown {
+        self.token.clone()
+    }
+
+    /// Waits for SIGINT or SIGTERM, then walks through the phases. Sinks get
+    /// at most `flush_grace` to finish. Instances without ingest pass no
+    /// manager.
+    pub async fn run(self, manager_handle: Option<ManagerHandle>, flush_grace: Duration) {
+        wait_for_signal().await;
+        log::info!("Shutting down, closing publishers");
+        _ = self.phase.send(Phase::Draining);
+
+        if let Some(manager_handle) = manager_handle {
+            let (request, response) = oneshot::channel();
+            if manager_handle
+                .send(ChannelMessage::CloseAll(request))
+                .is_ok()
+            {
+                _ = response.await;
+            }
+        }
+
+        if tokio::time::timeout(flush_grace, self.sinks_idle())
+            .await
+            .is_err()
+        {
+            log::warn!(
+                "{} sink(s) still flushing after {:?}, stopping anyway",
+                self.token.sinks.active.load(Ordering::SeqCst),
+                flush_grace
+            );
+        }
+        log::info!("Stopping HTTP services");
+        _ = self.phase.send(Phase::Stopping);
+    }
+
+    async fn sinks_idle(&self) {
+        let sinks = &self.token.sinks;
+        loop {
+            // registered before checking, so a sink finishing in between is not missed
+            let idle = sinks.idle.notified();
+            if sinks.active.load(Ordering::SeqCst) == 0 {
+                return;
+            }
+            idle.await;
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+    match signal(SignalKind::terminate()) {
+        Ok(mut terminate) => {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = terminate.recv() => {}
+            }
+        }
+        Err(_) => _ = tokio::signal::ctrl_c().await,
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() {
+    _ = tokio::signal::ctrl_c().await;
+}
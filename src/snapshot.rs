@@ -0,0 +1,116 @@
+use crate::problem::Problem;
+use crate::transport::{ChannelMessage, ManagerHandle, Message};
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::fs::{self, DirBuilder};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::{oneshot, Semaphore};
+
+/// How long a snapshot is served from cache before it is decoded again.
+const SNAPSHOT_TTL: Duration = Duration::from_secs(2);
+/// Snapshots decoded at the same time, requests beyond it get a 503.
+const MAX_DECODES: usize = 2;
+
+/// JPEG snapshots of the keyframe a channel has cached, decoded on demand.
+///
+/// The decoder writes its output to a file, which goes to a directory of
+/// this process only readable by its user, never to the served keyframe
+/// directory.
+pub struct Snapshots {
+    cache: Mutex<HashMap<String, (Instant, Bytes)>>,
+    decoding: Semaphore,
+    dir: PathBuf,
+    next_file: AtomicU64,
+}
+
+impl Snapshots {
+    pub fn new() -> Self {
+        let dir = std::env::temp_dir().join(format!("xlive-snapshots-{}", std::process::id()));
+        let mut builder = DirBuilder::new();
+        #[cfg(unix)]
+        std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+        if let Err(e) = builder.recursive(true).create(&dir) {
+            log::error!("Failed to create {}: {}", dir.display(), e);
+        }
+        Self {
+            cache: Mutex::new(HashMap::new()),
+            decoding: Semaphore::new(MAX_DECODES),
+            dir,
+            next_file: AtomicU64::new(0),
+        }
+    }
+
+    pub async fn get(
+        &self,
+        manager_handle: &ManagerHandle,
+        app_name: &str,
+    ) -> Result<Bytes, Problem> {
+        if let Some((taken, image)) = self.cache.lock().unwrap().get(app_name) {
+            if taken.elapsed() < SNAPSHOT_TTL {
+                return Ok(image.clone());
+            }
+        }
+
+        let _permit = self
+            .decoding
+            .try_acquire()
+            .map_err(|_| Problem::UNAVAILABLE.detail("too many snapshots in progress"))?;
+        let video = keyframe(manager_handle, app_name).await?;
+        let path = self.dir.join(format!(
+            "{}.jpg",
+            self.next_file.fetch_add(1, Ordering::Relaxed)
+        ));
+        let image = tokio::task::spawn_blocking(move || {
+            let file_name = path.to_string_lossy().into_owned();
+            let image = match pic::keyframe_to_jpg(video, file_name) {
+                true => fs::read(&path).ok(),
+                false => None,
+            };
+            _ = fs::remove_file(&path);
+            image
+        })
+        .await
+        .ok()
+        .flatten()
+        .map(Bytes::from)
+        .ok_or_else(|| Problem::UNAVAILABLE.detail("failed to decode keyframe"))?;
+
+        let mut cache = self.cache.lock().unwrap();
+        cache.retain(|_, (taken, _)| taken.elapsed() < SNAPSHOT_TTL);
+        cache.insert(app_name.to_owned(), (Instant::now(), image.clone()));
+        Ok(image)
+    }
+}
+
+impl Drop for Snapshots {
+    fn drop(&mut self) {
+        _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// The cached keyframe of a stream as AnnexB, ready for the decoder.
+async fn keyframe(manager_handle: &ManagerHandle, app_name: &str) -> Result<Vec<u8>, Problem> {
+    let (request, response) = oneshot::channel();
+    manager_handle
+        .send(ChannelMessage::Join((app_name.to_owned(), request)))
+        .map_err(|_| Problem::UNAVAILABLE)?;
+    let (handle, _watcher) = response.await.map_err(|_| Problem::STREAM_NOT_FOUND)?;
+
+    let (request, response) = oneshot::channel();
+    handle
+        .send(Message::InitData(request))
+        .map_err(|_| Problem::STREAM_NOT_FOUND)?;
+    let (_, _, _, gop) = response.await.map_err(|_| Problem::STREAM_NOT_FOUND)?;
+    // the channel already converted the frame, SPS and PPS included
+    match gop.and_then(|gop| gop.into_iter().next()) {
+        Some(keyframe) => keyframe
+            .annexb
+            .as_ref()
+            .map(|video| video.to_vec())
+            .ok_or_else(|| Problem::UNAVAILABLE.detail("keyframe could not be converted")),
+        None => Err(Problem::UNAVAILABLE.detail("no keyframe cached yet")),
+    }
+}
@@ -0,0 +1,138 @@
+use crate::config;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tokio::time::{Instant, Sleep};
+
+/// Binds `addr` with the configured buffer sizes. They have to be set
+/// before listening so accepted connections inherit them and the TCP
+/// window scale is negotiated accordingly.
+pub fn listen(addr: SocketAddr, options: &config::Socket) -> io::Result<TcpListener> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    #[cfg(unix)]
+    socket.set_reuseaddr(true)?;
+    if options.send_buffer > 0 {
+        socket.set_send_buffer_size(options.send_buffer)?;
+    }
+    if options.recv_buffer > 0 {
+        socket.set_recv_buffer_size(options.recv_buffer)?;
+    }
+    socket.bind(addr)?;
+    socket.listen(1024)
+}
+
+/// Applies the per-connection options to an accepted stream.
+pub fn tune(stream: &TcpStream, options: &config::Socket) -> io::Result<()> {
+    stream.set_nodelay(options.nodelay)?;
+    if let Some(idle) = options.keepalive() {
+        let keepalive = socket2::TcpKeepalive::new().with_time(idle);
+        socket2::SockRef::from(stream).set_tcp_keepalive(&keepalive)?;
+    }
+    Ok(())
+}
+
+/// An accepted HTTP connection that fails its reads once the client took
+/// longer than the header timeout to send a request, counted from the
+/// connection opening or the last response being sent. Nothing is enforced
+/// while requests are being answered, so long-lived responses are not cut.
+pub(crate) struct HeaderTimeout {
+    stream: TcpStream,
+    timer: RequestTimer,
+    sleep: Pin<Box<Sleep>>,
+}
+
+impl HeaderTimeout {
+    pub fn new(stream: TcpStream, timeout: Duration) -> Self {
+        let deadline = Instant::now() + timeout;
+        Self {
+            stream,
+            timer: RequestTimer {
+                state: Arc::new(Mutex::new((0, Some(deadline)))),
+                timeout,
+            },
+            sleep: Box::pin(tokio::time::sleep_until(deadline)),
+        }
+    }
+
+    pub fn timer(&self) -> RequestTimer {
+        self.timer.clone()
+    }
+
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.stream.peer_addr().ok()
+    }
+}
+
+/// Tells a `HeaderTimeout` connection when it is answering requests.
+#[derive(Clone)]
+pub(crate) struct RequestTimer {
+    // requests in flight and the deadline for the next one, if idle
+    state: Arc<Mutex<(usize, Option<Instant>)>>,
+    timeout: Duration,
+}
+
+impl RequestTimer {
+    pub fn start(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.0 += 1;
+        state.1 = None;
+    }
+
+    pub fn finish(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.0 = state.0.saturating_sub(1);
+        if state.0 == 0 {
+            state.1 = Some(Instant::now() + self.timeout);
+        }
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        self.state.lock().unwrap().1
+    }
+}
+
+impl AsyncRead for HeaderTimeout {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if let Some(deadline) = this.timer.deadline() {
+            if this.sleep.deadline() != deadline {
+                this.sleep.as_mut().reset(deadline);
+            }
+            if this.sleep.as_mut().poll(cx).is_ready() {
+                return Poll::Ready(Err(io::ErrorKind::TimedOut.into()));
+            }
+        }
+        Pin::new(&mut this.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for HeaderTimeout {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
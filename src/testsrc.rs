@@ -0,0 +1,283 @@
+use crate::config;
+use crate::packet::Packet;
+use crate::transport::{ChannelMessage, ManagerHandle, Message, Publisher};
+use anyhow::{bail, Result};
+use bytes::{BufMut, Bytes, BytesMut};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+const WIDTH: usize = 320;
+const HEIGHT: usize = 240;
+const FPS: u64 = 25;
+// frame_num is coded in log2_max_frame_num_minus4 + 4 bits
+const FRAME_NUM_BITS: u32 = 4;
+const CLIENT_ID: u64 = u64::MAX;
+// the sinks register their triggers once they run, publish after them
+const STARTUP_DELAY: Duration = Duration::from_secs(1);
+
+// 75% bars in BT.601 YCbCr: white, yellow, cyan, green, magenta, red, blue
+const BARS: [[u8; 3]; 7] = [
+    [180, 128, 128],
+    [162, 44, 142],
+    [131, 156, 44],
+    [112, 72, 58],
+    [84, 184, 198],
+    [65, 100, 212],
+    [35, 212, 114],
+];
+const BLACK: [u8; 3] = [16, 128, 128];
+const WHITE: [u8; 3] = [235, 128, 128];
+const BOX: usize = 32;
+
+/// Publishes color bars into `config.app` as if an encoder was connected,
+/// to check the pipeline and players without one. The H.264 stream is
+/// built here: a keyframe of uncompressed (I_PCM) macroblocks every second,
+/// with a box moving along the bottom, and frames repeating it in between.
+/// There is no audio.
+pub async fn run(manager: ManagerHandle, config: config::TestSource) -> Result<()> {
+    tokio::time::sleep(STARTUP_DELAY).await;
+    let presence = Arc::new(());
+    let publisher = Publisher {
+        client_id: CLIENT_ID,
+        addr: ([127, 0, 0, 1], 0).into(),
+        resume: None,
+        presence: Arc::downgrade(&presence),
+    };
+    let (request, response) = oneshot::channel();
+    let create = ChannelMessage::Create((config.app.clone(), config.key, publisher, request));
+    if manager.send(create).is_err() {
+        bail!("Manager stopped");
+    }
+    let handle = match response.await {
+        Ok(handle) => handle,
+        Err(_) => bail!("Refused to publish {}", config.app),
+    };
+    log::info!("Test source publishing {}", config.app);
+
+    let header = sequence_header();
+    _ = handle.send(Message::Packet(Packet::new_video(0u64, header)));
+    let mut interval = tokio::time::interval(Duration::from_millis(1000 / FPS));
+    for frame in 0u64.. {
+        interval.tick().await;
+        let (second, n) = (frame / FPS, frame % FPS);
+        let payload = if n == 0 {
+            video_tag(true, &idr(second))
+        } else {
+            video_tag(false, &repeat(n as u32))
+        };
+        let packet = Packet::new_video(frame * 1000 / FPS, payload);
+        if handle.send(Message::Packet(packet)).is_err() {
+            break;
+        }
+    }
+    log::info!("Test source {} stopped", config.app);
+    Ok(())
+}
+
+pub(crate) fn sequence_header() -> Bytes {
+    let (sps, pps) = (sps(), pps());
+    let mut tag = BytesMut::new();
+    // keyframe, AVC sequence header, composition time 0
+    tag.put_slice(&[0x17, 0, 0, 0, 0]);
+    // AVCDecoderConfigurationRecord with 4 byte NALU lengths
+    tag.put_slice(&[1, sps[1], sps[2], sps[3], 0xff, 0xe1]);
+    tag.put_u16(sps.len() as u16);
+    tag.put_slice(&sps);
+    tag.put_u8(1);
+    tag.put_u16(pps.len() as u16);
+    tag.put_slice(&pps);
+    tag.freeze()
+}
+
+pub(crate) fn video_tag(keyframe: bool, nal: &[u8]) -> Bytes {
+    let mut tag = BytesMut::with_capacity(nal.len() + 9);
+    tag.put_slice(&[if keyframe { 0x17 } else { 0x27 }, 1, 0, 0, 0]);
+    tag.put_u32(nal.len() as u32);
+    tag.put_slice(nal);
+    tag.freeze()
+}
+
+fn sps() -> Vec<u8> {
+    let mut w = BitWriter::default();
+    // constrained baseline, level 3.0
+    w.bits(66, 8);
+    w.bits(0xc0, 8);
+    w.bits(30, 8);
+    w.ue(0); // seq_parameter_set_id
+    w.ue(FRAME_NUM_BITS - 4);
+    w.ue(2); // pic_order_cnt_type, output order is decoding order
+    w.ue(1); // max_num_ref_frames
+    w.bit(false); // gaps_in_frame_num_value_allowed_flag
+    w.ue((WIDTH / 16 - 1) as u32);
+    w.ue((HEIGHT / 16 - 1) as u32);
+    w.bit(true); // frame_mbs_only_flag
+    w.bit(true); // direct_8x8_inference_flag
+    w.bit(false); // frame_cropping_flag
+    w.bit(false); // vui_parameters_present_flag
+    w.nal(0x67)
+}
+
+fn pps() -> Vec<u8> {
+    let mut w = BitWriter::default();
+    w.ue(0); // pic_parameter_set_id
+    w.ue(0); // seq_parameter_set_id
+    w.bit(false); // entropy_coding_mode_flag, CAVLC
+    w.bit(false); // bottom_field_pic_order_in_frame_present_flag
+    w.ue(0); // num_slice_groups_minus1
+    w.ue(0); // num_ref_idx_l0_default_active_minus1
+    w.ue(0); // num_ref_idx_l1_default_active_minus1
+    w.bit(false); // weighted_pred_flag
+    w.bits(0, 2); // weighted_bipred_idc
+    w.se(0); // pic_init_qp_minus26
+    w.se(0); // pic_init_qs_minus26
+    w.se(0); // chroma_qp_index_offset
+    w.bit(false); // deblocking_filter_control_present_flag
+    w.bit(false); // constrained_intra_pred_flag
+    w.bit(false); // redundant_pic_cnt_present_flag
+    w.nal(0x68)
+}
+
+/// An IDR picture of the bars, coding every macroblock as raw samples.
+pub(crate) fn idr(second: u64) -> Vec<u8> {
+    let [luma, cb, cr] = picture(second);
+    let mut w = BitWriter::default();
+    w.ue(0); // first_mb_in_slice
+    w.ue(7); // slice_type, I
+    w.ue(0); // pic_parameter_set_id
+    w.bits(0, FRAME_NUM_BITS);
+    w.ue((second % 2) as u32); // idr_pic_id, differs between neighbours
+    w.bit(false); // no_output_of_prior_pics_flag
+    w.bit(false); // long_term_reference_flag
+    w.se(0); // slice_qp_delta
+    for mb_y in 0..HEIGHT / 16 {
+        for mb_x in 0..WIDTH / 16 {
+            w.ue(25); // mb_type, I_PCM
+            w.align();
+            for y in mb_y * 16..mb_y * 16 + 16 {
+                w.bytes(&luma[y * WIDTH + mb_x * 16..][..16]);
+            }
+            for plane in [&cb, &cr] {
+                for y in mb_y * 8..mb_y * 8 + 8 {
+                    w.bytes(&plane[y * WIDTH / 2 + mb_x * 8..][..8]);
+                }
+            }
+        }
+    }
+    w.nal(0x65)
+}
+
+/// A P picture skipping every macroblock, repeating the previous one.
+fn repeat(frame_num: u32) -> Vec<u8> {
+    let mut w = BitWriter::default();
+    w.ue(0); // first_mb_in_slice
+    w.ue(5); // slice_type, P
+    w.ue(0); // pic_parameter_set_id
+    w.bits(frame_num % (1 << FRAME_NUM_BITS), FRAME_NUM_BITS);
+    w.bit(false); // num_ref_idx_active_override_flag
+    w.bit(false); // ref_pic_list_modification_flag_l0
+    w.bit(false); // adaptive_ref_pic_marking_mode_flag
+    w.se(0); // slice_qp_delta
+    w.ue((WIDTH / 16 * HEIGHT / 16) as u32); // mb_skip_run
+    w.nal(0x41)
+}
+
+/// Y, Cb and Cr planes of the bars, 4:2:0.
+fn picture(second: u64) -> [Vec<u8>; 3] {
+    let bars_end = HEIGHT * 2 / 3;
+    let box_x = (second as usize % (WIDTH / BOX)) * BOX;
+    let box_y = bars_end + (HEIGHT - bars_end - BOX) / 2;
+    let color = |x: usize, y: usize| {
+        if y < bars_end {
+            BARS[x * BARS.len() / WIDTH]
+        } else if (box_x..box_x + BOX).contains(&x) && (box_y..box_y + BOX).contains(&y) {
+            WHITE
+        } else {
+            BLACK
+        }
+    };
+    let mut planes = [
+        Vec::with_capacity(WIDTH * HEIGHT),
+        Vec::with_capacity(WIDTH * HEIGHT / 4),
+        Vec::with_capacity(WIDTH * HEIGHT / 4),
+    ];
+    for y in 0..HEIGHT {
+        for x in 0..WIDTH {
+            let [luma, cb, cr] = color(x, y);
+            planes[0].push(luma);
+            if x % 2 == 0 && y % 2 == 0 {
+                planes[1].push(cb);
+                planes[2].push(cr);
+            }
+        }
+    }
+    planes
+}
+
+#[derive(Default)]
+struct BitWriter {
+    data: Vec<u8>,
+    current: u8,
+    used: u32,
+}
+
+impl BitWriter {
+    fn bit(&mut self, bit: bool) {
+        self.current = self.current << 1 | bit as u8;
+        self.used += 1;
+        if self.used == 8 {
+            self.data.push(self.current);
+            self.current = 0;
+            self.used = 0;
+        }
+    }
+
+    fn bits(&mut self, value: u32, count: u32) {
+        for i in (0..count).rev() {
+            self.bit(value >> i & 1 == 1);
+        }
+    }
+
+    /// Exp-Golomb coded unsigned value.
+    fn ue(&mut self, value: u32) {
+        let value = value + 1;
+        let len = 32 - value.leading_zeros();
+        self.bits(0, len - 1);
+        self.bits(value, len);
+    }
+
+    /// Exp-Golomb coded signed value.
+    fn se(&mut self, value: i32) {
+        let mapped = if value > 0 { value * 2 - 1 } else { -value * 2 };
+        self.ue(mapped as u32);
+    }
+
+    fn align(&mut self) {
+        while self.used != 0 {
+            self.bit(false);
+        }
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        debug_assert_eq!(self.used, 0);
+        self.data.extend_from_slice(bytes);
+    }
+
+    /// Ends the RBSP and wraps it into a NAL unit, escaping start codes.
+    fn nal(mut self, header: u8) -> Vec<u8> {
+        self.bit(true);
+        self.align();
+        let mut nal = Vec::with_capacity(self.data.len() + self.data.len() / 64 + 1);
+        nal.push(header);
+        let mut zeros = 0;
+        for byte in self.data {
+            if zeros >= 2 && byte <= 3 {
+                nal.push(3);
+                zeros = 0;
+            }
+            nal.push(byte);
+            zeros = if byte == 0 { zeros + 1 } else { 0 };
+        }
+        nal
+    }
+}
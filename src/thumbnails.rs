@@ -0,0 +1,94 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+
+pub const THUMBNAIL_PATH: &str = "data/keyframe";
+/// Stream time (ms) between two thumbnails listed in the track.
+const THUMBNAIL_INTERVAL: u64 = 10_000;
+
+/// WebVTT thumbnail track for a stream, written to
+/// `data/keyframe/{name}.vtt` next to the keyframe images it points at.
+/// Every keyframe gets an image, the track lists one per
+/// `THUMBNAIL_INTERVAL` of them, each cue a whole image.
+///
+/// A cue can only be closed once the next thumbnail is known, so each one is
+/// appended when its successor arrives and the last one on `finish`.
+pub struct ThumbnailTrack {
+    name: String,
+    track: Option<File>,
+    start: u64,
+    last: Option<(u64, String)>,
+}
+
+impl ThumbnailTrack {
+    pub fn new(name: String) -> Self {
+        if let Err(e) = fs::create_dir_all(THUMBNAIL_PATH) {
+            log::error!("Failed to create {}: {}", THUMBNAIL_PATH, e);
+        }
+        Self {
+            name,
+            track: None,
+            start: 0,
+            last: None,
+        }
+    }
+
+    pub fn due(&self, timestamp: u64) -> bool {
+        match &self.last {
+            Some((last, _)) => timestamp >= last + THUMBNAIL_INTERVAL,
+            None => true,
+        }
+    }
+
+    pub fn image_path(&self, unix_time: i64) -> (String, String) {
+        let file_name = format!("{}_{}.jpg", self.name, unix_time);
+        let path = format!("{}/{}", THUMBNAIL_PATH, file_name);
+        (file_name, path)
+    }
+
+    pub fn add(&mut self, timestamp: u64, file_name: String) -> io::Result<()> {
+        if self.track.is_none() {
+            let mut track = OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(true)
+                .open(format!("{}/{}.vtt", THUMBNAIL_PATH, self.name))?;
+            track.write_all(b"WEBVTT\n\n")?;
+            self.track = Some(track);
+            self.start = timestamp;
+        }
+        self.write_cue(timestamp)?;
+        self.last = Some((timestamp, file_name));
+        Ok(())
+    }
+
+    pub fn finish(&mut self) -> io::Result<()> {
+        if let Some((last, _)) = self.last {
+            self.write_cue(last + THUMBNAIL_INTERVAL)?;
+        }
+        self.last = None;
+        Ok(())
+    }
+
+    fn write_cue(&mut self, end: u64) -> io::Result<()> {
+        if let (Some(track), Some((begin, file_name))) = (&mut self.track, &self.last) {
+            let cue = format!(
+                "{} --> {}\n/keyframe/{}\n\n",
+                cue_time(begin - self.start),
+                cue_time(end - self.start),
+                file_name
+            );
+            track.write_all(cue.as_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+fn cue_time(ms: u64) -> String {
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        ms / 3_600_000,
+        ms / 60_000 % 60,
+        ms / 1_000 % 60,
+        ms % 1_000
+    )
+}
@@ -0,0 +1,249 @@
+use crate::packet::PacketType;
+
+/// Backward steps smaller than this are treated as audio/video interleaving.
+const BACKWARD_TOLERANCE: u64 = 1_000;
+/// Forward steps larger than this are treated as a publisher side reset.
+const FORWARD_TOLERANCE: u64 = 10_000;
+/// RTMP timestamps wrap at 32 bits, some encoders already wrap at 24 bits.
+const WRAP_RANGES: [u64; 2] = [1 << 24, 1 << 32];
+/// Arrivals this much later than the wall clock mapping expects move it.
+const RESYNC_AFTER: i64 = 2_000;
+
+/// Rewrites incoming timestamps onto a monotonic per-channel timeline, so
+/// sinks never see a track going backwards or jumping by minutes.
+///
+/// Wrapped 24/32-bit publisher timestamps are extended to 64 bits first.
+#[derive(Default)]
+pub struct Normalizer {
+    video_raw: Unwrapper,
+    audio_raw: Unwrapper,
+    meta_raw: Unwrapper,
+    offset: i64,
+    last_input: Option<u64>,
+    last_output: u64,
+    last_video: u64,
+    last_audio: u64,
+}
+
+impl Normalizer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Maps `timestamp` (in the publisher's timeline, given after a
+    /// reconnect) onto the last timestamp already handed out.
+    pub fn resume(&mut self, timestamp: u64) {
+        self.video_raw = Unwrapper::resume(timestamp);
+        self.audio_raw = Unwrapper::resume(timestamp);
+        self.meta_raw = Unwrapper::resume(timestamp);
+        self.offset = self.last_output as i64 - timestamp as i64;
+        self.last_input = Some(timestamp);
+    }
+
+    pub fn normalize(&mut self, kind: PacketType, timestamp: u64) -> u64 {
+        let timestamp = match kind {
+            PacketType::Video => self.video_raw.unwrap(timestamp),
+            PacketType::Audio => self.audio_raw.unwrap(timestamp),
+            PacketType::Meta => self.meta_raw.unwrap(timestamp),
+        };
+        if let Some(last_input) = self.last_input {
+            let jumped_back = timestamp + BACKWARD_TOLERANCE < last_input;
+            let jumped_forward = timestamp > last_input + FORWARD_TOLERANCE;
+            if jumped_back || jumped_forward {
+                log::warn!(
+                    "Timestamp discontinuity {} -> {}, rebasing timeline",
+                    last_input,
+                    timestamp
+                );
+                self.offset = self.last_output as i64 + 1 - timestamp as i64;
+            }
+        }
+        self.last_input = Some(timestamp);
+
+        let output = (timestamp as i64 + self.offset).max(0) as u64;
+        let output = match kind {
+            PacketType::Video => {
+                self.last_video = self.last_video.max(output);
+                self.last_video
+            }
+            PacketType::Audio => {
+                self.last_audio = self.last_audio.max(output);
+                self.last_audio
+            }
+            PacketType::Meta => output,
+        };
+        self.last_output = self.last_output.max(output);
+        output
+    }
+}
+
+/// Extends the wrapping timestamps of one track to 64 bits. Each track
+/// wraps on its own, as audio from before a wrap can still arrive after
+/// the video's wrapped.
+#[derive(Default)]
+struct Unwrapper {
+    epoch: u64,
+    last_raw: Option<u64>,
+}
+
+impl Unwrapper {
+    fn resume(timestamp: u64) -> Self {
+        Self {
+            epoch: 0,
+            last_raw: Some(timestamp),
+        }
+    }
+
+    fn unwrap(&mut self, timestamp: u64) -> u64 {
+        if let Some(last) = self.last_raw {
+            let wrapped = WRAP_RANGES.iter().find(|&&range| {
+                last < range && range - last < FORWARD_TOLERANCE && timestamp < FORWARD_TOLERANCE
+            });
+            if let Some(range) = wrapped {
+                log::info!("Timestamp rolled over at {:#x}", range);
+                self.epoch += range;
+            }
+        }
+        self.last_raw = Some(timestamp);
+        self.epoch + timestamp
+    }
+}
+
+/// Maps normalized timestamps onto the wall clock at ingest.
+///
+/// Packets can be delayed on their way but never arrive early, so the
+/// mapping follows the earliest arrival seen. It only moves later when
+/// packets keep arriving more than `RESYNC_AFTER` late, after a stall or
+/// with a publisher clock running slow.
+#[derive(Default)]
+pub struct WallClock {
+    offset: Option<i64>,
+}
+
+impl WallClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records that `timestamp` arrived at `now`, in unix milliseconds, and
+    /// returns the wall clock time it stands for.
+    pub fn map(&mut self, timestamp: u64, now: i64) -> i64 {
+        let arrival = now - timestamp as i64;
+        let offset = match self.offset {
+            Some(offset) if arrival - offset <= RESYNC_AFTER => offset.min(arrival),
+            _ => arrival,
+        };
+        self.offset = Some(offset);
+        offset + timestamp as i64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use PacketType::{Audio, Video};
+
+    fn normalize(packets: &[(PacketType, u64)]) -> Vec<u64> {
+        let mut normalizer = Normalizer::new();
+        packets
+            .iter()
+            .map(|&(kind, timestamp)| normalizer.normalize(kind, timestamp))
+            .collect()
+    }
+
+    #[test]
+    fn unwraps_24_bit_rollover() {
+        let wrap = 1 << 24;
+        assert_eq!(
+            normalize(&[(Video, wrap - 40), (Video, 10)]),
+            [wrap - 40, wrap + 10]
+        );
+    }
+
+    #[test]
+    fn unwraps_32_bit_rollover() {
+        let wrap = 1 << 32;
+        assert_eq!(
+            normalize(&[(Video, wrap - 40), (Video, 10)]),
+            [wrap - 40, wrap + 10]
+        );
+    }
+
+    #[test]
+    fn passes_24_bit_boundary_of_32_bit_timestamps() {
+        let boundary = 1 << 24;
+        assert_eq!(
+            normalize(&[(Video, boundary - 40), (Video, boundary + 10)]),
+            [boundary - 40, boundary + 10]
+        );
+    }
+
+    #[test]
+    fn unwraps_each_track_on_its_own() {
+        let wrap = 1 << 32;
+        let packets = [
+            (Video, wrap - 40),
+            (Audio, wrap - 30),
+            (Video, 10),
+            // audio from before the wrap arriving after the video's
+            (Audio, wrap - 20),
+            (Audio, 5),
+            (Video, 33),
+        ];
+        assert_eq!(
+            normalize(&packets),
+            [
+                wrap - 40,
+                wrap - 30,
+                wrap + 10,
+                wrap - 20,
+                wrap + 5,
+                wrap + 33
+            ]
+        );
+    }
+
+    #[test]
+    fn tolerates_interleaving_up_to_backward_tolerance() {
+        assert_eq!(
+            normalize(&[(Video, 5_000), (Audio, 5_000 - BACKWARD_TOLERANCE)]),
+            [5_000, 5_000 - BACKWARD_TOLERANCE]
+        );
+        assert_eq!(
+            normalize(&[(Video, 5_000), (Audio, 4_999 - BACKWARD_TOLERANCE)]),
+            [5_000, 5_001]
+        );
+    }
+
+    #[test]
+    fn rebases_beyond_forward_tolerance() {
+        assert_eq!(
+            normalize(&[(Video, 0), (Video, FORWARD_TOLERANCE)]),
+            [0, FORWARD_TOLERANCE]
+        );
+        assert_eq!(
+            normalize(&[(Video, 0), (Video, FORWARD_TOLERANCE + 1)]),
+            [0, 1]
+        );
+    }
+
+    #[test]
+    fn resumes_where_the_timeline_left_off() {
+        let mut normalizer = Normalizer::new();
+        normalizer.normalize(Video, 1_000);
+        assert_eq!(normalizer.normalize(Video, 2_000), 2_000);
+        // the reconnected publisher counts on from its own timeline
+        normalizer.resume(500_000);
+        assert_eq!(normalizer.normalize(Video, 500_040), 2_040);
+        assert_eq!(normalizer.normalize(Audio, 500_020), 2_020);
+    }
+
+    #[test]
+    fn resumes_across_a_rollover() {
+        let wrap = 1 << 32;
+        let mut normalizer = Normalizer::new();
+        assert_eq!(normalizer.normalize(Video, 2_000), 2_000);
+        normalizer.resume(wrap - 40);
+        assert_eq!(normalizer.normalize(Video, 10), 2_050);
+    }
+}
@@ -0,0 +1,127 @@
+use crate::config::{self, TokenKey};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// The secrets playback tokens are signed and checked with: the legacy
+/// `token_secret` and the rotating `token_keys`.
+#[derive(Clone, Default)]
+pub struct Keyring {
+    secret: String,
+    keys: Vec<TokenKey>,
+}
+
+impl Keyring {
+    pub fn new(config: &config::HTTPFLV) -> Self {
+        Self {
+            secret: config.token_secret.clone(),
+            keys: config.token_keys.clone(),
+        }
+    }
+
+    /// Without any secret every token is accepted.
+    pub fn is_empty(&self) -> bool {
+        self.secret.is_empty() && self.keys.is_empty()
+    }
+
+    /// The key new tokens are signed with at `now`: the one most recently
+    /// taken into use that has not been retired.
+    pub fn signing_key(&self, now: i64) -> Option<&TokenKey> {
+        self.keys
+            .iter()
+            .filter(|key| key.not_before <= now && !key.retired(now))
+            .max_by_key(|key| key.not_before)
+    }
+
+    /// Signs with the current key, or the legacy secret if no key is in use.
+    pub fn mint(&self, path: &str, expires: i64, now: i64) -> Option<String> {
+        match self.signing_key(now) {
+            Some(key) => Some(mint_with_key(key, path, expires)),
+            None if !self.secret.is_empty() => Some(mint(&self.secret, path, expires)),
+            None => None,
+        }
+    }
+
+    /// Tokens naming a key are checked against it until it is retired, so
+    /// tokens issued before a rotation keep working. Tokens without a key
+    /// id need the legacy secret.
+    pub fn verify(&self, path: &str, token: &str, now: i64) -> bool {
+        match token.splitn(3, '.').collect::<Vec<_>>()[..] {
+            [expires, id, signature] => self
+                .keys
+                .iter()
+                .find(|key| key.id == id && !key.retired(now))
+                .map_or(false, |key| {
+                    check(
+                        &key.secret,
+                        &format!("{}:{}", path, id),
+                        expires,
+                        signature,
+                        now,
+                    )
+                }),
+            _ => !self.secret.is_empty() && verify(&self.secret, path, token, now),
+        }
+    }
+}
+
+/// Signs `path` until the unix time `expires`. The token is
+/// `{expires}.{signature}`, the signature being a hex HMAC-SHA256 over
+/// `{path}:{expires}`.
+pub fn mint(secret: &str, path: &str, expires: i64) -> String {
+    format!("{}.{}", expires, sign(secret, path, expires))
+}
+
+/// Signs like `mint`, naming the key: `{expires}.{id}.{signature}`. The
+/// id is signed along with the path.
+pub fn mint_with_key(key: &TokenKey, path: &str, expires: i64) -> String {
+    let signed = format!("{}:{}", path, key.id);
+    format!(
+        "{}.{}.{}",
+        expires,
+        key.id,
+        sign(&key.secret, &signed, expires)
+    )
+}
+
+pub fn verify(secret: &str, path: &str, token: &str, now: i64) -> bool {
+    match token.split_once('.') {
+        Some((expires, signature)) => check(secret, path, expires, signature, now),
+        None => false,
+    }
+}
+
+fn sign(secret: &str, path: &str, expires: i64) -> String {
+    let signature = mac(secret, path, expires).finalize().into_bytes();
+    signature.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn check(secret: &str, path: &str, expires: &str, signature: &str, now: i64) -> bool {
+    let expires: i64 = match expires.parse() {
+        Ok(expires) => expires,
+        Err(_) => return false,
+    };
+    match decode_hex(signature) {
+        Some(signature) if expires >= now => {
+            mac(secret, path, expires).verify_slice(&signature).is_ok()
+        }
+        _ => false,
+    }
+}
+
+fn mac(secret: &str, path: &str, expires: i64) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).expect("any key length");
+    mac.update(format!("{}:{}", path, expires).as_bytes());
+    mac
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
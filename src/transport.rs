@@ -1,13 +1,124 @@
+use crate::metrics::DropReason;
 use crate::packet::Packet;
 use crate::{AppName, Event, StreamKey};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Weak};
 use tokio::sync::{broadcast, mpsc, oneshot};
 
 pub type Responder<P> = oneshot::Sender<P>;
+
+/// The connection asking to publish into a channel.
+#[derive(Clone)]
+pub struct Publisher {
+    pub client_id: u64,
+    pub addr: SocketAddr,
+    /// Last timestamp the publisher had delivered before reconnecting.
+    pub resume: Option<u64>,
+    /// Upgrades while the publishing task runs, so the manager notices it
+    /// is gone even if its release was lost.
+    pub presence: Weak<()>,
+}
+
+impl Publisher {
+    pub fn gone(&self) -> bool {
+        self.presence.strong_count() == 0
+    }
+}
+
+/// A live channel as reported to the stream listing.
+#[derive(Serialize)]
+pub struct StreamInfo {
+    pub name: AppName,
+    pub client_id: u64,
+    pub addr: SocketAddr,
+    /// Players and sinks currently receiving the channel.
+    pub subscribers: usize,
+    /// The publisher left and the channel waits for it to reconnect.
+    pub resuming: bool,
+    pub health: StreamHealth,
+    /// Which publisher feeds a channel that has a backup.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<Role>,
+    /// Sinks currently writing the channel.
+    pub sinks: Vec<Sink>,
+    /// Packets that did not reach a watcher or sink, by reason.
+    pub drops: BTreeMap<DropReason, u64>,
+}
+
+/// Which publisher of a primary/backup pair feeds the channel.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Primary,
+    Backup,
+}
+
+/// A consumer of channels that can fail without affecting the others.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Sink {
+    Hls,
+    Flv,
+}
+
+impl Sink {
+    pub const ALL: [Sink; 2] = [Sink::Hls, Sink::Flv];
+
+    /// Event a sink registers its trigger for. Unlike `create_session` its
+    /// watcher can be detached from and attached to a running channel.
+    pub fn event(self) -> Event {
+        match self {
+            Sink::Hls => "hls_session",
+            Sink::Flv => "flv_session",
+        }
+    }
+}
+
+/// Health of a live channel.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "state", rename_all = "lowercase")]
+pub enum StreamHealth {
+    Live,
+    /// These sinks fail, players served by the others are unaffected.
+    Degraded {
+        sinks: Vec<Sink>,
+    },
+    /// The publisher left or sent no media for a while.
+    Stalled,
+    /// The channel task stopped, the listing has not caught up yet.
+    Closed,
+}
+
 pub enum ChannelMessage {
-    Create((AppName, StreamKey, Responder<Handle>)),
-    Release(AppName),
+    Create((AppName, StreamKey, Publisher, Responder<Handle>)),
+    Release((AppName, u64)),
+    Expire((AppName, u64)),
     Join((AppName, Responder<(Handle, Watcher)>)),
     RegisterTrigger(Event, Trigger),
+    /// Removes a trigger registered earlier, matched by channel. Triggers
+    /// whose receiver was dropped are removed without it.
+    DeregisterTrigger(Event, Trigger),
+    List(Responder<Vec<StreamInfo>>),
+    /// A sink started (`true`) or stopped failing on a channel.
+    SinkFailing(AppName, Sink, bool),
+    /// Attaches (`true`) a sink to a live channel or detaches it, answered
+    /// with the sinks writing the channel afterwards, `None` if it is not
+    /// live.
+    SetSink(AppName, Sink, bool, Responder<Option<Vec<Sink>>>),
+    /// Closes every channel and refuses new ones, for shutdown.
+    CloseAll(Responder<()>),
+    /// Re-evaluates which publisher of each primary/backup pair is active.
+    CheckSources,
+    /// Closes channels whose publisher or task went away without a release.
+    Reconcile,
+    /// The channel behind the handle reached its maximum duration.
+    Overtime(AppName, Handle),
+    /// Reports the channels whose number of viewers changed.
+    CountViewers,
+    /// The HLS sink wrote a segment of a channel.
+    SegmentWritten(AppName, Segment),
 }
 
 pub type ManagerHandle = mpsc::UnboundedSender<ChannelMessage>;
@@ -20,26 +131,58 @@ pub fn trigger_channel() -> (Trigger, TriggerHandle) {
     mpsc::unbounded_channel()
 }
 
+/// Metadata, video and audio sequence headers and the cached GOP of a
+/// channel, what a new watcher needs before the live packets.
+pub type InitData = (
+    Option<Arc<Packet>>,
+    Option<Arc<Packet>>,
+    Option<Arc<Packet>>,
+    Option<Vec<Arc<Packet>>>,
+);
+
 pub enum Message {
     Packet(Packet),
-    InitData(
-        Responder<(
-            Option<Packet>,
-            Option<Packet>,
-            Option<Packet>,
-            Option<Vec<Packet>>,
-        )>,
-    ),
+    InitData(Responder<InitData>),
+    Resume(u64),
     Disconnect,
 }
 
 pub type Handle = mpsc::UnboundedSender<Message>;
 pub(super) type IncomingBroadcast = mpsc::UnboundedReceiver<Message>;
-pub(super) type OutgoingBroadcast = broadcast::Sender<Packet>;
-pub type Watcher = broadcast::Receiver<Packet>;
+pub(super) type OutgoingBroadcast = broadcast::Sender<Arc<Packet>>;
+pub type Watcher = broadcast::Receiver<Arc<Packet>>;
+
+/// A TS segment written to disk, named after the unix time it started at.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct Segment {
+    pub name: i64,
+    /// Wall clock time the segment starts at, in unix milliseconds.
+    pub started_at: i64,
+    pub duration: u8,
+    /// The encoding parameters or timeline changed since the previous segment.
+    pub discontinuity: bool,
+}
+
+/// What happened to a stream, for hosts embedding the manager
+/// (see [`Manager::events`](crate::Manager::events)).
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum StreamEvent {
+    /// A channel opened, mirrors included.
+    Published { app: AppName },
+    /// A channel closed.
+    Unpublished { app: AppName },
+    /// The players and sinks receiving a channel changed, checked every few
+    /// seconds.
+    Viewers { app: AppName, count: usize },
+    /// A TS segment of a channel is on disk and about to be listed.
+    SegmentWritten { app: AppName, segment: Segment },
+}
 
 pub enum TsMessageQueue {
-    Ts(AppName, i64, u8),
+    Ts(AppName, Segment),
+    /// The stream ended, its last segment was already sent.
+    Finished(AppName),
 }
 
 pub type TsMessageQueueHandle = mpsc::UnboundedSender<TsMessageQueue>;
@@ -7,7 +7,7 @@ use {
     },
     std::{
         fs::File,
-        io::{self, Cursor},
+        io::{self, BufWriter, Cursor, Write},
         path::Path,
     },
 };
@@ -39,6 +39,9 @@ const VIDEO_ES_PID: u16 = 257;
 const AUDIO_ES_PID: u16 = 258;
 const PES_VIDEO_STREAM_ID: u8 = 224;
 const PES_AUDIO_STREAM_ID: u8 = 192;
+// ATSC A/52 carries Dolby audio in private_stream_1
+const PES_PRIVATE_STREAM_1_ID: u8 = 189;
+const REGISTRATION_DESCRIPTOR_TAG: u8 = 5;
 
 #[derive(Clone, Debug)]
 pub enum SuportCodec {
@@ -46,11 +49,24 @@ pub enum SuportCodec {
     H265,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AudioCodec {
+    Aac,
+    Ac3,
+    Eac3,
+}
+
 pub struct TransportStream {
     video_continuity_counter: ContinuityCounter,
     audio_continuity_counter: ContinuityCounter,
     pub packets: Vec<TsPacket>,
     codec: SuportCodec,
+    audio_codec: AudioCodec,
+    // PAT and PMT as written at the start of every segment, serialized
+    // once per codec so their CRCs aren't recomputed for each segment
+    psi: Option<Vec<u8>>,
+    // 90 kHz ticks added to every PCR, PTS and DTS
+    offset: u64,
 }
 
 impl TransportStream {
@@ -60,6 +76,20 @@ impl TransportStream {
 
     pub fn set_codec(&mut self, codec: SuportCodec) {
         self.codec = codec;
+        self.psi = None;
+    }
+
+    pub fn set_audio_codec(&mut self, codec: AudioCodec) {
+        if self.audio_codec != codec {
+            self.audio_codec = codec;
+            self.psi = None;
+        }
+    }
+
+    /// Shifts the output timeline by `ticks` of the 90 kHz clock, for
+    /// consumers that need streams to start at a given PCR base.
+    pub fn set_timestamp_offset(&mut self, ticks: u64) {
+        self.offset = ticks;
     }
 
     pub fn size(&self) -> usize {
@@ -70,53 +100,45 @@ impl TransportStream {
     where
         P: AsRef<Path>,
     {
-        use mpeg2ts::ts::{TsPacketWriter, WriteTsPacket};
-
-        let file = File::create(filename)?;
-        let packets: Vec<_> = self.packets.drain(..).collect();
-        let mut writer = TsPacketWriter::new(file);
+        // packets are serialized field by field, unbuffered that's a
+        // syscall for every few bytes
+        let file = BufWriter::new(File::create(filename)?);
+        let mut file = self.write_packets(file)?;
+        file.flush()?;
+        Ok(())
+    }
 
-        writer
-            .write_ts_packet(&default_pat_packet())
-            .map_err(|_| TsError::WriteError)?;
+    pub fn write_to(&mut self) -> Result<Vec<u8>, TsError> {
+        self.write_packets(Vec::new())
+    }
 
-        writer
-            .write_ts_packet(&default_pmt_packet(&self.codec))
-            .map_err(|_| TsError::WriteError)?;
+    fn write_packets<W: Write>(&mut self, mut stream: W) -> Result<W, TsError> {
+        use mpeg2ts::ts::{TsPacketWriter, WriteTsPacket};
 
-        for packet in &packets {
+        stream.write_all(self.psi()?)?;
+        let mut writer = TsPacketWriter::new(stream);
+        for packet in self.packets.drain(..) {
             writer
-                .write_ts_packet(packet)
+                .write_ts_packet(&packet)
                 .map_err(|_| TsError::WriteError)?;
         }
-
-        Ok(())
+        Ok(writer.into_stream())
     }
 
-    pub fn write_to(&mut self) -> Result<Vec<u8>, TsError> {
+    fn psi(&mut self) -> Result<&[u8], TsError> {
         use mpeg2ts::ts::{TsPacketWriter, WriteTsPacket};
-        let mut buf = Cursor::new(Vec::new());
-
-        let packets: Vec<_> = self.packets.drain(..).collect();
-        let mut writer = TsPacketWriter::new(&mut buf);
-
-        // if keyframe {
-        writer
-            .write_ts_packet(&default_pat_packet())
-            .map_err(|_| TsError::WriteError)?;
 
-        writer
-            .write_ts_packet(&default_pmt_packet(&self.codec))
-            .map_err(|_| TsError::WriteError)?;
-        // }
-
-        for packet in &packets {
+        if self.psi.is_none() {
+            let mut writer = TsPacketWriter::new(Vec::new());
+            writer
+                .write_ts_packet(&default_pat_packet())
+                .map_err(|_| TsError::WriteError)?;
             writer
-                .write_ts_packet(packet)
+                .write_ts_packet(&default_pmt_packet(&self.codec, self.audio_codec))
                 .map_err(|_| TsError::WriteError)?;
+            self.psi = Some(writer.into_stream());
         }
-
-        Ok(buf.into_inner())
+        Ok(self.psi.as_deref().unwrap_or_default())
     }
 
     pub fn push_video(
@@ -146,7 +168,7 @@ impl TransportStream {
             };
             buf.advance(data.len());
 
-            let pcr = make_clock_reference(timestamp * 90)?;
+            let pcr = make_clock_reference(to_90khz(timestamp, self.offset) * 300)?;
 
             let adaptation_field = if keyframe {
                 Some(AdaptationField {
@@ -163,8 +185,8 @@ impl TransportStream {
                 None
             };
 
-            let pts = make_timestamp((timestamp + composition_time) * 90)?;
-            let dts = make_timestamp(timestamp * 90)?;
+            let pts = make_timestamp(to_90khz(timestamp + composition_time, self.offset))?;
+            let dts = make_timestamp(to_90khz(timestamp, self.offset))?;
 
             TsPacket {
                 header: header.clone(),
@@ -196,7 +218,7 @@ impl TransportStream {
                 } else {
                     &buf.chunk()[..payload::Bytes::MAX_SIZE]
                 };
-                make_raw_payload(&pes_data)?
+                make_raw_payload(pes_data)?
             };
             buf.advance(raw_payload.len());
 
@@ -225,24 +247,28 @@ impl TransportStream {
             } else {
                 &buf.chunk()[..153]
             };
-            make_raw_payload(&pes_data)?
+            make_raw_payload(pes_data)?
         };
         buf.advance(data.len());
 
         let mut header = default_ts_header(AUDIO_ES_PID)?;
         header.continuity_counter = self.audio_continuity_counter;
+        let stream_id = match self.audio_codec {
+            AudioCodec::Aac => PES_AUDIO_STREAM_ID,
+            AudioCodec::Ac3 | AudioCodec::Eac3 => PES_PRIVATE_STREAM_1_ID,
+        };
 
         let packet = TsPacket {
             header: header.clone(),
             adaptation_field: None,
             payload: Some(TsPayload::Pes(payload::Pes {
                 header: PesHeader {
-                    stream_id: StreamId::new(PES_AUDIO_STREAM_ID),
+                    stream_id: StreamId::new(stream_id),
                     priority: false,
                     data_alignment_indicator: false,
                     copyright: false,
                     original_or_copy: false,
-                    pts: Some(make_timestamp(timestamp * 90)?),
+                    pts: Some(make_timestamp(to_90khz(timestamp, self.offset))?),
                     dts: None,
                     escr: None,
                 },
@@ -261,7 +287,7 @@ impl TransportStream {
                 } else {
                     &buf.chunk()[..payload::Bytes::MAX_SIZE]
                 };
-                make_raw_payload(&pes_data)?
+                make_raw_payload(pes_data)?
             };
             buf.advance(raw_payload.len());
 
@@ -288,12 +314,21 @@ impl Default for TransportStream {
             audio_continuity_counter: ContinuityCounter::new(),
             packets: Vec::new(),
             codec: SuportCodec::H264,
+            audio_codec: AudioCodec::Aac,
+            psi: None,
+            offset: 0,
         }
     }
 }
 
 fn make_raw_payload(pes_data: &[u8]) -> Result<ts::payload::Bytes, TsError> {
-    ts::payload::Bytes::new(&pes_data).map_err(|_| TsError::PayloadTooBig)
+    ts::payload::Bytes::new(pes_data).map_err(|_| TsError::PayloadTooBig)
+}
+
+// Milliseconds to the 33-bit 90kHz clock plus `offset` ticks, wrapping like
+// PTS/DTS do.
+fn to_90khz(ms: u64, offset: u64) -> u64 {
+    ms.wrapping_mul(90).wrapping_add(offset) & Timestamp::MAX
 }
 
 fn make_timestamp(ts: u64) -> Result<Timestamp, TsError> {
@@ -333,16 +368,35 @@ fn default_pat_packet() -> TsPacket {
     }
 }
 
-fn default_pmt_packet(codec: &SuportCodec) -> TsPacket {
+fn default_pmt_packet(codec: &SuportCodec, audio_codec: AudioCodec) -> TsPacket {
     use mpeg2ts::{
         es::StreamType,
-        ts::{payload::Pmt, EsInfo, VersionNumber},
+        ts::{payload::Pmt, Descriptor, EsInfo, VersionNumber},
     };
 
     let stream_type = match codec {
         SuportCodec::H264 => StreamType::H264,
         SuportCodec::H265 => StreamType::H265,
     };
+    // stream types and registration descriptors as in ATSC A/52 and Apple's
+    // HLS authoring spec
+    let (audio_stream_type, audio_descriptors) = match audio_codec {
+        AudioCodec::Aac => (StreamType::AdtsAac, vec![]),
+        AudioCodec::Ac3 => (
+            StreamType::DolbyDigitalUpToSixChannelAudio,
+            vec![Descriptor {
+                tag: REGISTRATION_DESCRIPTOR_TAG,
+                data: b"AC-3".to_vec(),
+            }],
+        ),
+        AudioCodec::Eac3 => (
+            StreamType::DolbyDigitalPlusUpTo16ChannelAudioForAtsc,
+            vec![Descriptor {
+                tag: REGISTRATION_DESCRIPTOR_TAG,
+                data: b"EAC3".to_vec(),
+            }],
+        ),
+    };
 
     TsPacket {
         header: default_ts_header(PMT_PID).unwrap(),
@@ -358,11 +412,58 @@ fn default_pmt_packet(codec: &SuportCodec) -> TsPacket {
                     descriptors: vec![],
                 },
                 EsInfo {
-                    stream_type: StreamType::AdtsAac,
+                    stream_type: audio_stream_type,
                     elementary_pid: Pid::new(AUDIO_ES_PID).unwrap(),
-                    descriptors: vec![],
+                    descriptors: audio_descriptors,
                 },
             ],
         })),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mpeg2ts::ts::payload::Pes;
+
+    // the last millisecond before the 90 kHz clock wraps at 33 bits
+    const LAST_MS: u64 = Timestamp::MAX / 90;
+
+    fn pes(packet: &TsPacket) -> &Pes {
+        match &packet.payload {
+            Some(TsPayload::Pes(pes)) => pes,
+            _ => panic!("not a PES packet"),
+        }
+    }
+
+    #[test]
+    fn wraps_the_90khz_clock_at_33_bits() {
+        assert_eq!(to_90khz(LAST_MS, 0), LAST_MS * 90);
+        assert_eq!(to_90khz(LAST_MS + 1, 0), (LAST_MS + 1) * 90 - (1 << 33));
+        assert_eq!(to_90khz(0, Timestamp::MAX), Timestamp::MAX);
+        assert_eq!(to_90khz(1, Timestamp::MAX), 89);
+    }
+
+    #[test]
+    fn writes_wrapped_pts_and_pcr() {
+        let mut ts = TransportStream::new();
+        ts.push_video(LAST_MS, 1, true, vec![0; 16]).unwrap();
+        ts.push_video(LAST_MS + 1, 0, true, vec![0; 16]).unwrap();
+        ts.push_audio(LAST_MS + 1, vec![0; 16]).unwrap();
+
+        let before = &ts.packets[0];
+        let pcr = before.adaptation_field.as_ref().unwrap().pcr.unwrap();
+        assert_eq!(pcr.as_u64(), LAST_MS * 90 * 300);
+        assert_eq!(pes(before).header.dts.unwrap().as_u64(), LAST_MS * 90);
+        // the composition time alone carries the PTS over the wrap
+        assert_eq!(pes(before).header.pts.unwrap().as_u64(), 28);
+
+        let after = &ts.packets[1];
+        let pcr = after.adaptation_field.as_ref().unwrap().pcr.unwrap();
+        assert_eq!(pcr.as_u64(), 28 * 300);
+        assert!(pcr.as_u64() <= ClockReference::MAX);
+        assert_eq!(pes(after).header.dts.unwrap().as_u64(), 28);
+        assert_eq!(pes(&ts.packets[2]).header.pts.unwrap().as_u64(), 28);
+        assert!(ts.write_to().is_ok());
+    }
+}
@@ -1,22 +1,27 @@
+use crate::clock;
 use crate::codec::aac::{self, AacCoder};
-use crate::codec::avc::{self, AvcCoder};
-use crate::codec::flv::{AudioData, Codec, VideoData};
-use crate::codec::hevc::{self, HevcCoder};
+use crate::codec::flv::{audio::AudioFormat, AudioData, Codec, VideoData};
 use crate::codec::FormatReader;
 use crate::codec::FormatWriter;
+use crate::config;
 use crate::error::Error;
+use crate::metrics::{self, DropReason};
 use crate::packet::{Packet, PacketType};
+use crate::sampling;
+use crate::shutdown::{Shutdown, SinkGuard};
 use crate::transport::{
-    trigger_channel, ChannelMessage, ManagerHandle, TsMessageQueue, TsMessageQueueHandle, Watcher,
+    trigger_channel, ChannelMessage, ManagerHandle, Segment, Sink, TsMessageQueue,
+    TsMessageQueueHandle, Watcher,
 };
-use anyhow::{bail, Result};
-use chrono::prelude::*;
+use anyhow::Result;
+use bytes::Bytes;
+use log::Level;
+use std::collections::VecDeque;
 use std::convert::TryFrom;
-use std::fs;
-use std::path::{Path, PathBuf};
+use std::path::PathBuf;
 
 //static  self.ts_duration: u64 = 5;
-use crate::transport_stream::{SuportCodec, TransportStream};
+use crate::transport_stream::{AudioCodec, SuportCodec, TransportStream};
 pub struct Writer {
     app_name: String,
     watcher: Watcher,
@@ -24,26 +29,42 @@ pub struct Writer {
     next_write: u64,
     last_keyframe: u64,
     keyframe_counter: usize,
+    // audio received before the first keyframe, at most a segment's worth,
+    // muxed ahead of it instead of being lost
+    early_audio: VecDeque<(u64, Vec<u8>)>,
     buffer: TransportStream,
-    avc_coder: AvcCoder,
-    hevc_coder: HevcCoder,
     aac_coder: AacCoder,
     stream_path: PathBuf,
     mq_message_handle: TsMessageQueueHandle,
+    manager_handle: ManagerHandle,
+    // reported to the manager, which shows the stream as degraded
+    failing: bool,
+    video_config: Option<Bytes>,
+    audio_config: Option<Bytes>,
+    // flags the next segment, set when it doesn't continue the previous one
+    discontinuity: bool,
+    // wall clock time of the first packet in the buffer, unix milliseconds
+    started_at: Option<i64>,
+    // dropped after the last segment is flushed
+    _sink: SinkGuard,
 }
 
 impl Writer {
     pub fn create(
         app_name: String,
         watcher: Watcher,
-        stream_path: String,
+        config: &config::Hls,
         mq_message_handle: TsMessageQueueHandle,
-        ts_duration: u64,
+        manager_handle: ManagerHandle,
+        sink: SinkGuard,
     ) -> Result<Self> {
-        let mut next_write: u64 = Utc::now().timestamp() as u64 + ts_duration; // milliseconds
+        let ts_duration = config.ts_duration;
+        let mut next_write: u64 = clock::now().timestamp() as u64 + ts_duration; // milliseconds
         next_write = next_write - next_write % ts_duration;
-        let stream_path = PathBuf::from(stream_path).join(app_name.clone());
+        let stream_path = PathBuf::from(&config.data_path).join(app_name.clone());
         super::prepare_stream_directory(&stream_path)?;
+        let mut buffer = TransportStream::new();
+        buffer.set_timestamp_offset(config.pts_offset);
 
         Ok(Self {
             app_name,
@@ -52,12 +73,19 @@ impl Writer {
             next_write,
             last_keyframe: 0,
             keyframe_counter: 0,
-            buffer: TransportStream::new(),
-            avc_coder: AvcCoder::new(),
+            early_audio: VecDeque::new(),
+            buffer,
             aac_coder: AacCoder::new(),
-            hevc_coder: HevcCoder::new(),
             stream_path,
             mq_message_handle,
+            manager_handle,
+            failing: false,
+            video_config: None,
+            audio_config: None,
+            // a new writer means the publisher (re)started
+            discontinuity: true,
+            started_at: None,
+            _sink: sink,
         })
     }
 
@@ -67,13 +95,17 @@ impl Writer {
             let packet = match self.watcher.recv().await {
                 Ok(packet) => packet,
                 Err(RecvError::Closed) => break,
-                Err(_) => continue,
+                Err(RecvError::Lagged(skipped)) => {
+                    metrics::dropped(&self.app_name, DropReason::Lag, skipped);
+                    continue;
+                }
             };
 
-            match self.handle_packet(packet) {
+            match self.handle_packet(&packet) {
                 Ok(_) => {}
                 Err(err) => {
                     log::error!("handle_packet err {}", err);
+                    self.set_failing(true);
                     break;
                 }
             }
@@ -81,7 +113,9 @@ impl Writer {
         Ok(())
     }
 
-    fn handle_video<T>(&mut self, timestamp: T, bytes: &[u8]) -> Result<()>
+    /// Muxes a video packet, taking the frame from the AnnexB conversion
+    /// its channel already did.
+    fn handle_video<T>(&mut self, timestamp: T, bytes: &[u8], annexb: Option<Bytes>) -> Result<()>
     where
         T: Into<u64>,
     {
@@ -91,14 +125,13 @@ impl Writer {
         let payload = &flv_packet.body;
 
         if flv_packet.is_sequence_header() {
+            if self.video_config.as_ref().map_or(false, |c| c != payload) {
+                self.restart_segment(timestamp)?;
+            }
+            self.video_config = Some(payload.clone());
             match flv_packet.codec {
-                Codec::H264 => {
-                    self.avc_coder.set_dcr(payload.as_ref())?;
-                }
-                Codec::H265 => {
-                    self.hevc_coder.set_dcr(payload.as_ref())?;
-                    self.buffer.set_codec(SuportCodec::H265);
-                }
+                Codec::H264 => self.buffer.set_codec(SuportCodec::H264),
+                Codec::H265 => self.buffer.set_codec(SuportCodec::H265),
             }
 
             return Ok(());
@@ -109,56 +142,35 @@ impl Writer {
         //  println!("{} keyframe {}",timestamp,flv_packet.is_keyframe());
         let keyframe_duration = timestamp - self.last_keyframe;
         if keyframe {
-            if Utc::now().timestamp() >= self.next_write as i64 {
+            if clock::now().timestamp() >= self.next_write as i64 {
                 let len = (keyframe_duration as f64 / 1000.0) as i64;
-                let filename = format!("{}.ts", self.next_write - self.ts_duration);
-                let path = self.stream_path.join(&filename);
-                self.buffer.write_to_file(&path)?;
-                self.mq_message_handle
-                    .send(TsMessageQueue::Ts(
-                        self.app_name.clone(),
-                        (self.next_write - self.ts_duration) as i64,
-                        len as u8,
-                    ))
-                    .map_err(|_| Error::SendTsToMqErr)?;
-                self.next_write += self.ts_duration as u64; // 这边能调节ts大小
+                self.flush_segment(len as u8)?;
+                self.next_write += self.ts_duration; // 这边能调节ts大小
                 self.last_keyframe = timestamp;
             }
             self.keyframe_counter += 1;
-        }
-
-        match flv_packet.codec {
-            Codec::H264 => {
-                let video = match self.avc_coder.read_format(avc::Avcc, &payload)? {
-                    Some(avc) => self.avc_coder.write_format(avc::AnnexB, avc)?,
-                    None => return Ok(()),
-                };
-
-                let comp_time = flv_packet.composition_time as u64;
-
-                if let Err(why) = self
-                    .buffer
-                    .push_video(timestamp, comp_time, keyframe, video)
-                {
-                    log::warn!("Failed to put data into buffer: {:?}", why);
-                }
+            if self.keyframe_counter == 1 {
+                self.flush_early_audio(timestamp);
             }
+        }
 
-            Codec::H265 => {
-                let video = match self.hevc_coder.read_format(hevc::Hvcc, &payload)? {
-                    Some(hevc) => self.hevc_coder.write_format(hevc::AnnexB, hevc)?,
-                    None => return Ok(()),
-                };
+        let video = match annexb {
+            Some(video) => video.to_vec(),
+            None => return Ok(()),
+        };
 
-                let comp_time = flv_packet.composition_time as u64;
+        let comp_time = flv_packet.composition_time as u64;
 
-                if let Err(why) = self
-                    .buffer
-                    .push_video(timestamp, comp_time, keyframe, video)
-                {
-                    log::warn!("Failed to put data into buffer: {:?}", why);
-                }
-            }
+        if let Err(why) = self
+            .buffer
+            .push_video(timestamp, comp_time, keyframe, video)
+        {
+            let message = format!(
+                "Failed to put data into buffer of {}: {:?}",
+                self.app_name, why
+            );
+            sampling::log(Level::Warn, message);
+            metrics::dropped(&self.app_name, DropReason::Parse, 1);
         }
 
         Ok(())
@@ -170,37 +182,175 @@ impl Writer {
     {
         let timestamp: u64 = timestamp.into();
 
-        let flv = AudioData::try_from(bytes).unwrap();
+        let flv = AudioData::try_from(bytes)?;
+        let codec = match flv.format {
+            AudioFormat::Aac => AudioCodec::Aac,
+            AudioFormat::Ac3 => AudioCodec::Ac3,
+            AudioFormat::Eac3 => AudioCodec::Eac3,
+        };
 
         if flv.is_sequence_header() {
-            self.aac_coder.set_asc(flv.body.as_ref())?;
+            // compared with the tag header, which tells the codecs apart
+            if self.audio_config.as_ref().map_or(false, |c| c != bytes) {
+                self.restart_segment(timestamp)?;
+            }
+            self.audio_config = Some(Bytes::copy_from_slice(bytes));
+            self.buffer.set_audio_codec(codec);
+            if codec == AudioCodec::Aac {
+                self.aac_coder = AacCoder::new();
+                self.aac_coder.set_asc(flv.body.as_ref())?;
+            }
             return Ok(());
         }
 
+        let audio = match codec {
+            AudioCodec::Aac => match self.aac_coder.read_format(aac::Raw, &flv.body)? {
+                Some(raw_aac) => self
+                    .aac_coder
+                    .write_format(aac::AudioDataTransportStream, raw_aac)?,
+                None => return Ok(()),
+            },
+            // Dolby sync frames are self-contained, they go into the PES as is
+            AudioCodec::Ac3 | AudioCodec::Eac3 => {
+                self.buffer.set_audio_codec(codec);
+                flv.body.to_vec()
+            }
+        };
+
+        // segments start with a keyframe, hold the audio until there is one
         if self.keyframe_counter == 0 {
+            let window = self.ts_duration * 1000;
+            while let Some(&(oldest, _)) = self.early_audio.front() {
+                if oldest + window >= timestamp {
+                    break;
+                }
+                self.early_audio.pop_front();
+            }
+            self.early_audio.push_back((timestamp, audio));
             return Ok(());
         }
 
-        let audio = match self.aac_coder.read_format(aac::Raw, &flv.body)? {
-            Some(raw_aac) => self
-                .aac_coder
-                .write_format(aac::AudioDataTransportStream, raw_aac)?,
-            None => return Ok(()),
-        };
-
         if let Err(why) = self.buffer.push_audio(timestamp, audio) {
-            log::warn!("Failed to put data into buffer: {:?}", why);
+            let message = format!(
+                "Failed to put data into buffer of {}: {:?}",
+                self.app_name, why
+            );
+            sampling::log(Level::Warn, message);
+            metrics::dropped(&self.app_name, DropReason::Parse, 1);
         }
 
         Ok(())
     }
 
-    fn handle_packet(&mut self, packet: Packet) -> Result<()> {
-        match packet.kind {
-            PacketType::Video => self.handle_video(packet.timestamp.unwrap(), packet.as_ref()),
+    /// Muxes the audio held back until the first keyframe at `keyframe`,
+    /// leaving out what is more than a segment older than it.
+    fn flush_early_audio(&mut self, keyframe: u64) {
+        let window = self.ts_duration * 1000;
+        let mut flushed = 0;
+        for (timestamp, audio) in self.early_audio.drain(..) {
+            if timestamp + window < keyframe {
+                continue;
+            }
+            if let Err(why) = self.buffer.push_audio(timestamp, audio) {
+                let message = format!(
+                    "Failed to put data into buffer of {}: {:?}",
+                    self.app_name, why
+                );
+                sampling::log(Level::Warn, message);
+                metrics::dropped(&self.app_name, DropReason::Parse, 1);
+            }
+            flushed += 1;
+        }
+        if flushed > 0 {
+            log::debug!(
+                "Muxed {} audio frames received before the first keyframe of {}",
+                flushed,
+                self.app_name
+            );
+        }
+    }
+
+    /// Writes the buffered packets as the current segment and queues it for
+    /// the playlist.
+    fn flush_segment(&mut self, duration: u8) -> Result<(), Error> {
+        let name = self.next_write - self.ts_duration;
+        let path = self.stream_path.join(format!("{}.ts", name));
+        let started_at = self.started_at.take();
+        match self.buffer.write_to_file(&path) {
+            Ok(_) => {
+                let segment = Segment {
+                    name: name as i64,
+                    // segments are named after the wall clock when they were due
+                    started_at: started_at.unwrap_or(name as i64 * 1000),
+                    duration,
+                    discontinuity: self.discontinuity,
+                };
+                self.discontinuity = false;
+                self.set_failing(false);
+                _ = self.manager_handle.send(ChannelMessage::SegmentWritten(
+                    self.app_name.clone(),
+                    segment,
+                ));
+                self.mq_message_handle
+                    .send(TsMessageQueue::Ts(self.app_name.clone(), segment))
+                    .map_err(|_| Error::SendTsToMqErr)
+            }
+            Err(e) => {
+                // never advertise a segment that is not on disk, players will
+                // see a gap before the next one instead
+                metrics::SEGMENT_WRITE_ERRORS.inc();
+                log::error!("Failed to write {}: {}", path.display(), e);
+                self.discontinuity = true;
+                self.set_failing(true);
+                Ok(())
+            }
+        }
+    }
+
+    fn set_failing(&mut self, failing: bool) {
+        if self.failing != failing {
+            self.failing = failing;
+            _ = self.manager_handle.send(ChannelMessage::SinkFailing(
+                self.app_name.clone(),
+                Sink::Hls,
+                failing,
+            ));
+        }
+    }
+
+    /// Ends the current segment early when a new sequence header changes the
+    /// stream parameters, so no segment mixes two decoder configurations.
+    fn restart_segment(&mut self, timestamp: u64) -> Result<()> {
+        log::info!(
+            "Stream parameters changed, restarting segment in {}",
+            self.stream_path.display()
+        );
+        if self.buffer.size() > 0 {
+            let name = self.next_write - self.ts_duration;
+            let len = timestamp.saturating_sub(self.last_keyframe) / 1000;
+            self.flush_segment(len as u8)?;
+            // the next segment starts now, named after the one just written
+            self.next_write = (clock::now().timestamp() as u64).max(name + 1) + self.ts_duration;
+            self.last_keyframe = timestamp;
+        }
+        self.discontinuity = true;
+        Ok(())
+    }
+
+    fn handle_packet(&mut self, packet: &Packet) -> Result<()> {
+        let result = match packet.kind {
+            PacketType::Video => self.handle_video(
+                packet.timestamp.unwrap(),
+                packet.as_ref(),
+                packet.annexb.clone(),
+            ),
             PacketType::Audio => self.handle_audio(packet.timestamp.unwrap(), packet.as_ref()),
             _ => Ok(()),
+        };
+        if self.started_at.is_none() && self.buffer.size() > 0 {
+            self.started_at = packet.wallclock;
         }
+        result
     }
 }
 
@@ -208,50 +358,44 @@ impl Drop for Writer {
     fn drop(&mut self) {
         //解决视频最后几秒丢失问题
         if self.buffer.size() > 0 {
-            let len = Utc::now().timestamp() as u64 - (self.next_write - self.ts_duration);
-            let filename = format!("{}.ts", self.next_write - self.ts_duration);
-            let path = self.stream_path.join(&filename);
-            _ = self.buffer.write_to_file(&path);
-            _ = self
-                .mq_message_handle
-                .send(TsMessageQueue::Ts(
-                    self.app_name.clone(),
-                    (self.next_write - self.ts_duration) as i64,
-                    len as u8,
-                ))
-                .map_err(|_| Error::SendTsToMqErr);
+            let len = clock::now().timestamp() as u64 - (self.next_write - self.ts_duration);
+            _ = self.flush_segment(len as u8);
         }
+        _ = self
+            .mq_message_handle
+            .send(TsMessageQueue::Finished(self.app_name.clone()));
         log::info!("Closing HLS writer for {}", self.stream_path.display());
     }
 }
 
 pub struct Service {
     manager_handle: ManagerHandle,
-    ts_data_path: String,
+    config: config::Hls,
     sender: TsMessageQueueHandle,
-    ts_duration: u64,
+    shutdown: Shutdown,
 }
 
 impl Service {
     pub fn new(
         manager_handle: ManagerHandle,
-        ts_data_path: String,
+        config: config::Hls,
         sender: TsMessageQueueHandle,
-        ts_duration: u64,
+        shutdown: Shutdown,
     ) -> Self {
         Self {
             manager_handle,
-            ts_data_path,
+            config,
             sender,
-            ts_duration,
+            shutdown,
         }
     }
 
     pub async fn run(self) {
         let (trigger, mut trigger_handle) = trigger_channel();
-        if let Err(_) = self
+        if self
             .manager_handle
-            .send(ChannelMessage::RegisterTrigger("create_session", trigger))
+            .send(ChannelMessage::RegisterTrigger(Sink::Hls.event(), trigger))
+            .is_err()
         {
             log::error!("Failed to register session trigger");
             return;
@@ -260,16 +404,24 @@ impl Service {
         while let Some((app_name, watcher)) = trigger_handle.recv().await {
             let sender = self.sender.clone();
             match Writer::create(
-                app_name,
+                app_name.clone(),
                 watcher,
-                self.ts_data_path.clone(),
+                &self.config,
                 sender,
-                self.ts_duration,
+                self.manager_handle.clone(),
+                self.shutdown.sink(),
             ) {
                 Ok(writer) => {
                     tokio::spawn(async move { writer.run().await.unwrap() });
                 }
-                Err(why) => log::error!("Failed to create writer: {:?}", why),
+                Err(why) => {
+                    log::error!("Failed to create writer: {:?}", why);
+                    _ = self.manager_handle.send(ChannelMessage::SinkFailing(
+                        app_name,
+                        Sink::Hls,
+                        true,
+                    ));
+                }
             }
         }
     }
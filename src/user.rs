@@ -33,7 +33,7 @@ impl UserCheck for Redis {
 
     async fn delete_key(&self, key: &str) -> Result<()> {
         if let Ok(mut conn) = self.client.get_connection() {
-            conn.del(key)?;
+            conn.del::<_, ()>(key)?;
         }
         Ok(())
     }
@@ -0,0 +1,29 @@
+use serde_json::Value;
+
+/// Posts `event` as JSON to the plain HTTP `url`, a failure is only logged.
+pub async fn post(url: String, event: Value) {
+    #[cfg(any(feature = "hls", feature = "http-flv"))]
+    {
+        let request = hyper::Request::post(&url)
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .body(hyper::Body::from(event.to_string()));
+        let result = match request {
+            Ok(request) => hyper::Client::new().request(request).await,
+            Err(e) => {
+                log::error!("Invalid webhook {}: {}", url, e);
+                return;
+            }
+        };
+        match result {
+            Ok(res) if res.status().is_success() => {}
+            Ok(res) => log::warn!("Webhook {} answered {}", url, res.status()),
+            Err(e) => log::warn!("Failed to post to webhook {}: {}", url, e),
+        }
+    }
+    #[cfg(not(any(feature = "hls", feature = "http-flv")))]
+    log::warn!(
+        "Not posting {} to {}, built without an HTTP client",
+        event,
+        url
+    );
+}
@@ -0,0 +1,302 @@
+use anyhow::{bail, Context, Result};
+use sha2::{Digest, Sha256};
+use std::env;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const USAGE: &str = "usage: cargo xtask [command]
+
+commands:
+    features                        list the feature sets releases are built with
+    dist [--target <triple>] [--set <name>]...
+                                    build release archives into dist/, all sets by default
+    sign                            sign dist/SHA256SUMS with cosign (COSIGN_KEY)
+    image [--tag <tag>] [--set <name>] [--sign]
+                                    build the container image, optionally signed with cosign";
+
+/// Feature sets a release is built with, named in the archive.
+const FEATURE_SETS: [(&str, &[&str]); 4] = [
+    ("default", &["http-flv", "hls", "flv"]),
+    ("auth", &["http-flv", "hls", "flv", "auth"]),
+    ("mosaic", &["http-flv", "hls", "flv", "auth", "mosaic"]),
+    // links the pic C library against the FFmpeg in /usr/local/ffmpeg
+    (
+        "full",
+        &["http-flv", "hls", "flv", "auth", "mosaic", "keyframe_image"],
+    ),
+];
+const DEFAULT_TARGET: &str = "x86_64-unknown-linux-musl";
+const DIST: &str = "dist";
+const FFMPEG_PREFIX: &str = "/usr/local/ffmpeg";
+/// Shipped next to the binary in every archive.
+const ARCHIVE_FILES: [&str; 3] = ["conf.yaml", "README.md", "LICENSE"];
+
+fn main() -> Result<()> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    // cargo runs the alias from anywhere in the workspace
+    env::set_current_dir(root())?;
+    match args.first().map(String::as_str) {
+        Some("features") => features(),
+        Some("dist") => dist(&args[1..]),
+        Some("sign") => sign(),
+        Some("image") => image(&args[1..]),
+        _ => {
+            eprintln!("{}", USAGE);
+            std::process::exit(2);
+        }
+    }
+}
+
+fn root() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .parent()
+        .unwrap()
+        .to_path_buf()
+}
+
+fn features() -> Result<()> {
+    for (name, features) in FEATURE_SETS.iter() {
+        println!("{:<10} {}", name, features.join(","));
+    }
+    Ok(())
+}
+
+/// Values of `--<name>` options, in order.
+fn options<'a>(args: &'a [String], name: &str) -> Result<Vec<&'a str>> {
+    let mut values = vec![];
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == name {
+            match args.next() {
+                Some(value) => values.push(value.as_str()),
+                None => bail!("{} needs a value", name),
+            }
+        }
+    }
+    Ok(values)
+}
+
+fn feature_set(name: &str) -> Result<&'static [&'static str]> {
+    match FEATURE_SETS.iter().find(|(set, _)| *set == name) {
+        Some((_, features)) => Ok(features),
+        None => bail!("unknown feature set {}, see cargo xtask features", name),
+    }
+}
+
+/// Builds every requested feature set for one target, packs each into a
+/// reproducible archive and writes their checksums to dist/SHA256SUMS.
+fn dist(args: &[String]) -> Result<()> {
+    let target = options(args, "--target")?
+        .pop()
+        .unwrap_or(DEFAULT_TARGET)
+        .to_owned();
+    let mut sets = options(args, "--set")?;
+    if sets.is_empty() {
+        sets = FEATURE_SETS.iter().map(|(name, _)| *name).collect();
+    }
+    if !Path::new("Cargo.lock").exists() {
+        bail!("Cargo.lock is missing, reproducible builds need the same lock file");
+    }
+    let epoch = source_date_epoch()?;
+    let version = version()?;
+    fs::create_dir_all(DIST)?;
+
+    let mut archives = vec![];
+    for set in sets {
+        let features = feature_set(set)?;
+        if features.contains(&"keyframe_image") && !Path::new(FFMPEG_PREFIX).exists() {
+            bail!(
+                "feature set {} needs FFmpeg installed to {}",
+                set,
+                FFMPEG_PREFIX
+            );
+        }
+        println!("building {} for {}", set, target);
+        build(&target, features, &epoch)?;
+        let name = format!("xlive-{}-{}-{}", version, target, set);
+        archives.push(archive(&name, &target, &epoch)?);
+    }
+    // the lock file pins what the archives were built from
+    fs::copy("Cargo.lock", Path::new(DIST).join("Cargo.lock"))?;
+    archives.push(Path::new(DIST).join("Cargo.lock"));
+
+    let mut sums = String::new();
+    for path in &archives {
+        let digest = Sha256::digest(fs::read(path)?);
+        let hex: String = digest.iter().map(|b| format!("{:02x}", b)).collect();
+        let file = path.file_name().unwrap().to_string_lossy();
+        _ = writeln!(sums, "{}  {}", hex, file);
+    }
+    fs::write(Path::new(DIST).join("SHA256SUMS"), &sums)?;
+    print!("{}", sums);
+    Ok(())
+}
+
+fn build(target: &str, features: &[&str], epoch: &str) -> Result<()> {
+    let root = root();
+    // no absolute build paths in the binary, so it doesn't depend on the checkout
+    let rustflags = format!(
+        "--remap-path-prefix={}=/xlive --remap-path-prefix={}=/cargo",
+        root.display(),
+        cargo_home().display()
+    );
+    let status = Command::new(env::var("CARGO").unwrap_or_else(|_| "cargo".to_owned()))
+        .args(["build", "--release", "--locked", "--package", "xlive"])
+        .args(["--no-default-features", "--features", &features.join(",")])
+        .args(["--target", target])
+        .env("SOURCE_DATE_EPOCH", epoch)
+        .env("CARGO_INCREMENTAL", "0")
+        .env("RUSTFLAGS", rustflags)
+        .status()
+        .context("failed to run cargo")?;
+    if !status.success() {
+        bail!("cargo build failed: {}", status);
+    }
+    Ok(())
+}
+
+/// Packs the binary and `ARCHIVE_FILES` with sorted entries, fixed owners
+/// and every mtime set to the last commit, so equal inputs give equal
+/// archives.
+fn archive(name: &str, target: &str, epoch: &str) -> Result<PathBuf> {
+    let staging = Path::new(DIST).join(name);
+    if staging.exists() {
+        fs::remove_dir_all(&staging)?;
+    }
+    fs::create_dir_all(&staging)?;
+    let binary = Path::new("target")
+        .join(target)
+        .join("release")
+        .join("xlive");
+    fs::copy(&binary, staging.join("xlive"))
+        .with_context(|| format!("missing {}", binary.display()))?;
+    for file in ARCHIVE_FILES.iter() {
+        fs::copy(file, staging.join(file))?;
+    }
+    let tarball = Path::new(DIST).join(format!("{}.tar", name));
+    let status = Command::new("tar")
+        .args(["--sort=name", "--owner=0", "--group=0", "--numeric-owner"])
+        .arg(format!("--mtime=@{}", epoch))
+        .arg("-cf")
+        .arg(&tarball)
+        .arg("-C")
+        .arg(DIST)
+        .arg(name)
+        .status()
+        .context("failed to run tar")?;
+    if !status.success() {
+        bail!("tar failed: {}", status);
+    }
+    // -n leaves the name and time out of the gzip header
+    let status = Command::new("gzip")
+        .args(["-n", "-f", "-9"])
+        .arg(&tarball)
+        .status()
+        .context("failed to run gzip")?;
+    if !status.success() {
+        bail!("gzip failed: {}", status);
+    }
+    fs::remove_dir_all(&staging)?;
+    Ok(Path::new(DIST).join(format!("{}.tar.gz", name)))
+}
+
+/// Signs the checksums, which cover every archive, with the cosign key
+/// named by `COSIGN_KEY`.
+fn sign() -> Result<()> {
+    let sums = Path::new(DIST).join("SHA256SUMS");
+    if !sums.exists() {
+        bail!("{} is missing, run cargo xtask dist first", sums.display());
+    }
+    let key = env::var("COSIGN_KEY").context("COSIGN_KEY must name the signing key")?;
+    let status = Command::new("cosign")
+        .args(["sign-blob", "--yes", "--key", &key])
+        .arg("--output-signature")
+        .arg(Path::new(DIST).join("SHA256SUMS.sig"))
+        .arg(&sums)
+        .status()
+        .context("failed to run cosign")?;
+    if !status.success() {
+        bail!("cosign sign-blob failed: {}", status);
+    }
+    Ok(())
+}
+
+/// Builds the container image from the Dockerfile with one feature set
+/// and signs it if asked, which needs the image pushed.
+fn image(args: &[String]) -> Result<()> {
+    let version = version()?;
+    let set = options(args, "--set")?.pop().unwrap_or("default");
+    let features = feature_set(set)?;
+    let tag = match options(args, "--tag")?.pop() {
+        Some(tag) => tag.to_owned(),
+        None => format!("livewin_live:{}-{}", version, set),
+    };
+    let status = Command::new("docker")
+        .args(["build", "--tag", &tag])
+        .arg("--build-arg")
+        .arg(format!("FEATURES={}", features.join(",")))
+        .arg("--build-arg")
+        .arg(format!("SOURCE_DATE_EPOCH={}", source_date_epoch()?))
+        .arg(".")
+        .status()
+        .context("failed to run docker")?;
+    if !status.success() {
+        bail!("docker build failed: {}", status);
+    }
+    if args.iter().any(|arg| arg == "--sign") {
+        let key = env::var("COSIGN_KEY").context("COSIGN_KEY must name the signing key")?;
+        let status = Command::new("docker")
+            .args(["push", &tag])
+            .status()
+            .context("failed to run docker")?;
+        if !status.success() {
+            bail!("docker push failed: {}", status);
+        }
+        let status = Command::new("cosign")
+            .args(["sign", "--yes", "--key", &key, &tag])
+            .status()
+            .context("failed to run cosign")?;
+        if !status.success() {
+            bail!("cosign sign failed: {}", status);
+        }
+    }
+    println!("built {}", tag);
+    Ok(())
+}
+
+/// Time of the last commit, used for every timestamp in the artifacts.
+fn source_date_epoch() -> Result<String> {
+    if let Ok(epoch) = env::var("SOURCE_DATE_EPOCH") {
+        return Ok(epoch);
+    }
+    let output = Command::new("git")
+        .args(["log", "-1", "--format=%ct"])
+        .output()
+        .context("failed to run git")?;
+    let epoch = String::from_utf8(output.stdout)?.trim().to_owned();
+    if !output.status.success() || epoch.is_empty() {
+        bail!("can't tell the time of the last commit, set SOURCE_DATE_EPOCH");
+    }
+    Ok(epoch)
+}
+
+/// Version of the xlive package.
+fn version() -> Result<String> {
+    let manifest = fs::read_to_string("Cargo.toml")?;
+    manifest
+        .lines()
+        .find_map(|line| {
+            let value = line.strip_prefix("version")?.trim().strip_prefix('=')?;
+            Some(value.trim().trim_matches('"').to_owned())
+        })
+        .context("no version in Cargo.toml")
+}
+
+fn cargo_home() -> PathBuf {
+    match env::var_os("CARGO_HOME") {
+        Some(home) => PathBuf::from(home),
+        None => Path::new(&env::var_os("HOME").unwrap_or_default()).join(".cargo"),
+    }
+}